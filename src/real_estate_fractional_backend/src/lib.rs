@@ -1,13 +1,18 @@
-use candid::{CandidType, Deserialize, Principal};
+use candid::{CandidType, Deserialize, Nat, Principal};
+#[cfg(not(test))]
 use ic_cdk::api::caller;
 use ic_cdk::query;
 use ic_cdk::update;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet, HashMap, HashSet};
 use std::cell::RefCell;
 
 // Types
 pub type PropertyId = u64;
 pub type UserId = String; // For now, use Principal as String
+type OwnershipMap = HashMap<(PropertyId, Principal, Subaccount), u64>; // shares per subaccount
+type IncomeMap = HashMap<(PropertyId, Principal, Subaccount), u64>; // unclaimed income per subaccount
+type LockMap = HashMap<(PropertyId, Principal, Principal), u64>; // (property, owner, locker) -> shares
+pub type Subaccount = Vec<u8>; // ICRC-1 style, 32 bytes; the all-zero subaccount is the default
 
 #[derive(CandidType, Deserialize, Clone, PartialEq, Eq, Hash)]
 pub enum Role {
@@ -20,6 +25,18 @@ pub enum Role {
 pub struct PropertyMetadata {
     pub location: String,
     pub description: String,
+    pub valuation: u64,
+    /// Number of decimal places in raw prices for this property.
+    pub decimals: u8,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct UpdatePropertyArgs {
+    pub name: Option<String>,
+    pub location: Option<String>,
+    pub description: Option<String>,
+    pub valuation: Option<u64>,
+    pub decimals: Option<u8>,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
@@ -30,14 +47,220 @@ pub struct Property {
     pub shares_available: u64,
     pub metadata: PropertyMetadata,
     pub status: PropertyStatus,
+    pub documents: Vec<DocumentRef>,
+}
+
+/// Reference to an off-chain legal document, anchored by its SHA-256 hash.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DocumentRef {
+    pub title: String,
+    pub sha256: Vec<u8>,
+    pub url: String,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PropertyDetail {
+    pub property: Property,
+    pub holder_count: u64,
+    pub shares_listed: u64,
+    pub latest_price: Option<u64>,
+}
+
+/// Lightweight view of a property for catalog listings.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PropertySummary {
+    pub id: PropertyId,
+    pub name: String,
+    pub shares_available: u64,
+    pub total_shares: u64,
+    pub valuation: u64,
+}
+
+/// Every setting that governs how a property trades, including platform-wide ones.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PropertyConfig {
+    pub status: PropertyStatus,
+    pub trading_window: Option<TradingWindow>,
+    pub transfer_restricted: bool,
+    pub min_deposit: Option<u64>,
+    pub platform_fee_bps: u64,
+    pub max_listings_per_seller: u64,
+    pub max_price_per_share: Option<u64>,
+    pub max_total_shares: Option<u64>,
+}
+
+/// Every platform-wide setting, as read by clients in one call.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct GlobalConfig {
+    pub settlement_ledger: Option<Principal>,
+    pub platform_fee_bps: u64,
+    pub fee_rebate_tiers: Vec<FeeRebateTier>,
+    pub registration_policy: RegistrationPolicy,
+    pub require_unique_names: bool,
+    pub max_listings_per_seller: u64,
+    pub max_price_per_share: Option<u64>,
+    pub max_total_shares: Option<u64>,
+    pub rate_limit: Option<RateLimit>,
+    pub log_level: LogLevel,
+    pub required_approvals: u64,
 }
 
 #[derive(CandidType, Deserialize, Clone)]
 pub struct Listing {
+    pub id: u64,
+    pub property_id: PropertyId,
+    pub seller: Principal,
+    pub amount: u64,
+    pub price_per_share: u64,
+    pub listed_at: u64,
+    /// Seller's annotation, at most `MAX_LISTING_NOTE_LEN` characters.
+    pub note: Option<String>,
+}
+
+/// A resting order to buy shares, crossed against listings by `run_matching`.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Bid {
+    pub id: u64,
+    pub property_id: PropertyId,
+    pub buyer: Principal,
+    pub amount: u64,
+    pub price_per_share: u64,
+    pub placed_at: u64,
+}
+
+/// Aggregated depth as `(price_per_share, total_amount)` levels. Asks are sorted ascending
+/// by price, bids descending, so the best price on each side comes first.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct OrderBook {
+    pub asks: Vec<(u64, u64)>,
+    pub bids: Vec<(u64, u64)>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct MatchFill {
+    pub bid_id: u64,
+    pub listing_id: u64,
+    pub buyer: Principal,
+    pub seller: Principal,
+    pub amount: u64,
+    pub price_per_share: u64,
+    pub settlement_block: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BuyReceipt {
+    pub shares: u64,
+    pub price_per_share: u64,
+    /// Exactly `shares * price_per_share`.
+    pub total_paid: u64,
+    /// Platform fee taken out of `total_paid`.
+    pub fee: u64,
+    pub listing_id: u64,
+    pub settlement_block: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BudgetBuyReceipt {
+    pub shares: u64,
+    /// Sum of the fills' `total_paid`, so each price level is charged exactly.
+    pub total_paid: u64,
+    pub fee: u64,
+    /// Budget left unspent.
+    pub leftover: u64,
+    /// One receipt per listing bought from, cheapest first.
+    pub fills: Vec<BuyReceipt>,
+}
+
+/// Whether a buyer could buy a number of shares right now, and what stands in the way.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct BuyEligibility {
+    pub eligible: bool,
+    pub property_active: bool,
+    pub trading_open: bool,
+    /// Whether the buyer may receive this property's shares, given any transfer restriction.
+    pub recipient_approved: bool,
+    /// Shares on offer from other sellers that their balances still back.
+    pub available_liquidity: u64,
+    /// Cost of buying `amount` from the cheapest listings, if enough are on offer.
+    pub estimated_cost: Option<u64>,
+}
+
+/// Shares set aside for a recipient who can't receive them yet. They stay in the sender's
+/// balance, locked by this canister, until the recipient claims them or the sender reclaims them.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingTransfer {
+    pub id: u64,
+    pub property_id: PropertyId,
+    pub from: Principal,
+    pub to: Principal,
+    pub amount: u64,
+    pub created_at: u64,
+}
+
+/// Shares taken out of `shares_available` for a primary-market buyer whose off-chain payment
+/// is pending. They are issued on confirmation and released on cancellation or expiry.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Reservation {
+    pub id: u64,
     pub property_id: PropertyId,
+    pub to: Principal,
+    pub amount: u64,
+    pub created_at: u64,
+    pub expires_at: u64,
+}
+
+/// High-value admin action that needs multi-sig approval once more than one is required.
+#[derive(CandidType, Deserialize, Clone)]
+pub enum AdminOperation {
+    DepositRentalIncome { property_id: PropertyId, amount: u64 },
+    WithdrawTreasury { to: Principal, amount: u64 },
+    DistributeToSnapshot { property_id: PropertyId, snapshot_id: u64, amount: u64 },
+    SetRequiredApprovals { approvals: u64 },
+    SetRole { user: Principal, role: Role },
+    AdjustUnclaimedIncome { property_id: PropertyId, user: Principal, delta: i64, reason: String },
+    ReverseSplit { property_id: PropertyId, factor: u64, cash_out_price: u64 },
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct PendingOperation {
+    pub id: u64,
+    pub operation: AdminOperation,
+    pub proposer: Principal,
+    pub approvals: Vec<Principal>,
+    pub created_at: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct TradeRecord {
+    pub timestamp: u64,
+    pub buyer: Principal,
     pub seller: Principal,
     pub amount: u64,
     pub price_per_share: u64,
+    pub settlement_block: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IncomeDeposit {
+    pub timestamp: u64,
+    pub amount: u64,
+    pub allocations: Vec<(Principal, u64)>, // what each holder was credited, by principal
+}
+
+/// Cap table of a property recorded at a point in time.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Snapshot {
+    pub id: u64,
+    pub property_id: PropertyId,
+    pub taken_at: u64,
+    pub total_shares: u64,
+    pub balances: Vec<(Principal, u64)>,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct WithdrawReceipt {
+    pub amount: u64,
+    pub block_index: u64,
 }
 
 // Ensure PropertyStatus is defined at the top level
@@ -46,6 +269,7 @@ pub enum PropertyStatus {
     Active,
     Maintenance,
     Sold,
+    Delisted,
 }
 
 #[derive(CandidType, Deserialize, Clone, PartialEq)]
@@ -68,298 +292,4326 @@ pub struct Proposal {
     pub votes: HashMap<Principal, bool>, // true = yes, false = no
 }
 
+#[derive(CandidType, Deserialize, Clone)]
+pub enum EventKind {
+    PropertyRegistered,
+    PropertyUpdated,
+    DocumentAdded { sha256: Vec<u8> },
+    DocumentRemoved { sha256: Vec<u8> },
+    PropertyStatusChanged { status: PropertyStatus },
+    PropertyDeleted,
+    PropertyMerged { into: PropertyId },
+    PropertySplit { factor: u64 },
+    PropertyConsolidated { factor: u64 },
+    SharesIssued { to: Principal, amount: u64 },
+    SharesTransferred { from: Principal, to: Principal, amount: u64 },
+    SharesListed { listing_id: u64, seller: Principal, amount: u64, price_per_share: u64 },
+    ListingCancelled { listing_id: u64, seller: Principal },
+    SharesBought { listing_id: u64, seller: Principal, buyer: Principal, amount: u64, price_per_share: u64 },
+    SnapshotTaken { snapshot_id: u64 },
+    SnapshotIncomeDistributed { snapshot_id: u64, amount: u64 },
+    SharesLocked { owner: Principal, locker: Principal, amount: u64 },
+    SharesUnlocked { owner: Principal, locker: Principal, amount: u64 },
+    PendingTransferCreated { transfer_id: u64, from: Principal, to: Principal, amount: u64 },
+    PendingTransferReclaimed { transfer_id: u64, from: Principal },
+    SharesReserved { reservation_id: u64, to: Principal, amount: u64 },
+    ReservationReleased { reservation_id: u64, to: Principal },
+    BidPlaced { bid_id: u64, buyer: Principal, amount: u64, price_per_share: u64 },
+    BidCancelled { bid_id: u64, buyer: Principal },
+    IncomeDeposited { amount: u64 },
+    IncomeClaimed { user: Principal, amount: u64 },
+    IncomeWithdrawn { user: Principal, amount: u64, block_index: u64 },
+    IncomeAdjusted { user: Principal, delta: i64, reason: String },
+    TreasuryWithdrawn { to: Principal, amount: u64, block_index: u64 },
+    ProposalSubmitted { proposal_id: u64 },
+    ProposalVoted { proposal_id: u64, vote: bool },
+    ProposalExecuted { proposal_id: u64, approved: bool },
+    OperationProposed { operation_id: u64 },
+    OperationApproved { operation_id: u64 },
+    RoleSet { user: Principal, role: Role },
+    UserMigrated { from: Principal, to: Principal },
+    SharesRecovered { from: Principal, to: Principal, amount: u64 },
+    KycSet { user: Principal, status: bool },
+    RecipientApproval { user: Principal, approved: bool },
+    ConfigChanged { setting: String },
+    StateImported,
+}
+
+impl EventKind {
+    /// Users directly affected by the event, beyond the caller.
+    fn parties(&self) -> Vec<Principal> {
+        match self {
+            EventKind::SharesIssued { to, .. }
+            | EventKind::TreasuryWithdrawn { to, .. }
+            | EventKind::SharesReserved { to, .. }
+            | EventKind::ReservationReleased { to, .. } => vec![*to],
+            EventKind::SharesTransferred { from, to, .. }
+            | EventKind::SharesRecovered { from, to, .. }
+            | EventKind::UserMigrated { from, to }
+            | EventKind::PendingTransferCreated { from, to, .. } => vec![*from, *to],
+            EventKind::PendingTransferReclaimed { from, .. } => vec![*from],
+            EventKind::SharesListed { seller, .. } | EventKind::ListingCancelled { seller, .. } => vec![*seller],
+            EventKind::SharesBought { seller, buyer, .. } => vec![*seller, *buyer],
+            EventKind::SharesLocked { owner, locker, .. } | EventKind::SharesUnlocked { owner, locker, .. } => {
+                vec![*owner, *locker]
+            }
+            EventKind::BidPlaced { buyer, .. } | EventKind::BidCancelled { buyer, .. } => vec![*buyer],
+            EventKind::IncomeClaimed { user, .. }
+            | EventKind::IncomeWithdrawn { user, .. }
+            | EventKind::IncomeAdjusted { user, .. } => vec![*user],
+            EventKind::RoleSet { user, .. } | EventKind::KycSet { user, .. } | EventKind::RecipientApproval { user, .. } => {
+                vec![*user]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Audit log entry. `caller` is the principal that made the call, which may differ
+/// from the user named in the event.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct Event {
+    pub id: u64,
+    pub timestamp: u64,
+    pub caller: Principal,
+    pub property_id: Option<PropertyId>,
+    pub kind: EventKind,
+}
+
+/// Errors shared across endpoints. Most endpoints report them as their `String` error.
+#[derive(CandidType, Deserialize, Clone, Debug, PartialEq)]
+pub enum RwaError {
+    /// The caller isn't allowed to make this call, e.g. because it is anonymous.
+    Unauthorized,
+    /// The caller has made too many calls in the current rate-limit window.
+    RateLimited,
+    /// No property exists with the given id.
+    PropertyNotFound,
+    /// The request needs more shares than are available.
+    InsufficientShares { available: u64 },
+    /// Share amounts must be positive.
+    InvalidAmount,
+    /// The property exists but isn't `Active`.
+    PropertyNotActive,
+    /// The property's trading window is closed.
+    TradingClosed,
+    /// Prices must be positive and within the configured maximum, if any.
+    InvalidPrice { max: Option<u64> },
+    /// The seller already has the maximum number of listings for the property.
+    ListingLimitReached { max: u64 },
+}
+
+impl std::fmt::Display for RwaError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RwaError::Unauthorized => write!(f, "Unauthorized"),
+            RwaError::RateLimited => write!(f, "Rate limited: too many calls, try again later"),
+            RwaError::PropertyNotFound => write!(f, "Property not found"),
+            RwaError::InsufficientShares { available } => write!(f, "Not enough shares: {} available", available),
+            RwaError::InvalidAmount => write!(f, "Amount must be positive"),
+            RwaError::PropertyNotActive => write!(f, "Property is not active"),
+            RwaError::TradingClosed => write!(f, "Trading is closed for this property"),
+            RwaError::InvalidPrice { max: Some(max) } => write!(f, "Price per share must be between 1 and {}", max),
+            RwaError::InvalidPrice { max: None } => write!(f, "Price per share must be positive"),
+            RwaError::ListingLimitReached { max } => write!(f, "Listing limit of {} reached for this property", max),
+        }
+    }
+}
+
+/// When a property's shares may be listed and bought. Times are nanoseconds, like `time()`.
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum TradingWindow {
+    /// Open from `opens_at` (inclusive) until `closes_at` (exclusive).
+    Fixed { opens_at: u64, closes_at: u64 },
+    /// Open every day between these offsets from UTC midnight. If `open` is after `close`
+    /// the window runs past midnight.
+    Daily { open: u64, close: u64 },
+}
+
+impl TradingWindow {
+    fn is_open(&self, at: u64) -> bool {
+        match *self {
+            TradingWindow::Fixed { opens_at, closes_at } => opens_at <= at && at < closes_at,
+            TradingWindow::Daily { open, close } => {
+                let offset = at % NANOS_PER_DAY;
+                if open <= close {
+                    open <= offset && offset < close
+                } else {
+                    offset >= open || offset < close
+                }
+            }
+        }
+    }
+}
+
+/// At most `max_calls` trading and holding calls per principal in each `window_nanos`.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub struct RateLimit {
+    pub max_calls: u64,
+    pub window_nanos: u64,
+}
+
+/// Verbosity of the canister debug log. Each level includes the ones before it.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Info,
+    Debug,
+}
+
+impl LogLevel {
+    fn label(self) -> &'static str {
+        match self {
+            LogLevel::Off => "OFF",
+            LogLevel::Error => "ERROR",
+            LogLevel::Info => "INFO",
+            LogLevel::Debug => "DEBUG",
+        }
+    }
+}
+
+/// Orderings offered by `get_properties_sorted`.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub enum SortKey {
+    /// Annualized yield over recent deposits, as from `get_annualized_yield_bps`.
+    Yield,
+    Valuation,
+    AvailableShares,
+    Name,
+}
+
+/// Sellers who have held a property for at least `min_holding_nanos` get `rebate_bps` of the
+/// platform fee on their sales waived. The highest tier a seller qualifies for applies.
+#[derive(CandidType, Deserialize, Clone, Copy, PartialEq)]
+pub struct FeeRebateTier {
+    pub min_holding_nanos: u64,
+    pub rebate_bps: u64,
+}
+
+/// Who may register properties, and at what cost. Admins are never restricted.
+#[derive(CandidType, Deserialize, Clone, PartialEq)]
+pub enum RegistrationPolicy {
+    Open,
+    AdminOnly,
+    /// Each non-admin caller may register at most this many properties.
+    Quota(u64),
+    /// Each non-admin registration must attach at least this many cycles.
+    Paid(u64),
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct IncomeSummary {
+    pub total_deposited: u64,
+    pub total_unclaimed: u64,
+    pub total_claimed: u64,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct DistributionEstimate {
+    pub holder_count: u64,
+    /// Rough instruction count for `deposit_rental_income`, which scans every ownership
+    /// entry and credits each subaccount holding shares.
+    pub estimated_instructions: u64,
+    /// Whether the estimate is under the per-message instruction limit.
+    pub within_limit: bool,
+}
+
+#[derive(CandidType, Deserialize, Clone)]
+pub struct VersionInfo {
+    pub semver: String,
+    /// Build time in seconds since the epoch, or 0 if not provided at build time.
+    pub build_time: u64,
+    pub commit: String,
+}
+
+/// Snapshot of the canister's core collections, used for backup and migration.
+#[derive(CandidType, Deserialize, Clone)]
+pub struct StateDump {
+    pub properties: Vec<Property>,
+    pub ownership: Vec<(PropertyId, Principal, Subaccount, u64)>,
+    pub rental_income: Vec<(PropertyId, u64)>,
+    pub unclaimed_income: Vec<(PropertyId, Principal, Subaccount, u64)>,
+    pub claimed_income: Vec<(PropertyId, u64)>,
+    pub marketplace: Vec<Listing>,
+    pub proposals: Vec<Proposal>,
+    pub reservations: Vec<Reservation>,
+    /// Collateral locks as (property, owner, locker, shares). Pending transfers' locks aren't
+    /// included, since the transfers themselves aren't.
+    pub locks: Vec<(PropertyId, Principal, Principal, u64)>,
+    pub transfer_restricted: Vec<PropertyId>,
+    pub approved_recipients: Vec<(PropertyId, Principal)>,
+    pub next_property_id: PropertyId,
+    pub next_listing_id: u64,
+    pub next_proposal_id: u64,
+    pub next_reservation_id: u64,
+}
+
+const NANOS_PER_DAY: u64 = 24 * 60 * 60 * 1_000_000_000;
+
+/// Income deposited within this window is extrapolated to a year for yield figures.
+const YIELD_LOOKBACK_NANOS: u64 = 90 * NANOS_PER_DAY;
+
+/// Encoded-size budget for list queries, leaving headroom under the 2MB response limit.
+const MAX_RESPONSE_BYTES: usize = 1_800_000;
+
+/// Default cap on simultaneous active listings a seller can have per property.
+const DEFAULT_MAX_LISTINGS_PER_SELLER: u64 = 10;
+
+/// Longest note a seller can attach to a listing, in characters.
+const MAX_LISTING_NOTE_LEN: usize = 280;
+
+/// How long a pending transfer waits for its recipient before the sender may reclaim it.
+const PENDING_TRANSFER_TIMEOUT_NANOS: u64 = 30 * NANOS_PER_DAY;
+
+/// Rough instruction costs used to estimate an income distribution, measured per update
+/// call, per ownership entry scanned and per holder credited.
+const DISTRIBUTION_BASE_INSTRUCTIONS: u64 = 200_000;
+const DISTRIBUTION_SCAN_INSTRUCTIONS: u64 = 1_000;
+const DISTRIBUTION_CREDIT_INSTRUCTIONS: u64 = 5_000;
+
+/// Instruction limit for a single update message.
+const MAX_UPDATE_INSTRUCTIONS: u64 = 20_000_000_000;
+
+/// Build metadata, supplied through `BUILD_TIME` and `GIT_COMMIT` when the canister is built.
+const BUILD_TIME: Option<&str> = option_env!("BUILD_TIME");
+const GIT_COMMIT: Option<&str> = option_env!("GIT_COMMIT");
+
 thread_local! {
     static PROPERTIES: RefCell<HashMap<PropertyId, Property>> = RefCell::new(HashMap::new());
-    static OWNERSHIP: RefCell<HashMap<(PropertyId, Principal), u64>> = RefCell::new(HashMap::new());
-    static NEXT_PROPERTY_ID: RefCell<PropertyId> = RefCell::new(1);
+    static OWNERSHIP: RefCell<OwnershipMap> = RefCell::new(HashMap::new());
+    static LOCKED: RefCell<LockMap> = RefCell::new(HashMap::new()); // shares held as collateral, still part of the default subaccount
+    static NEXT_PROPERTY_ID: RefCell<PropertyId> = const { RefCell::new(1) };
+    static NAME_INDEX: RefCell<HashMap<String, PropertyId>> = RefCell::new(HashMap::new()); // name -> lowest property id with that name
     static RENTAL_INCOME: RefCell<HashMap<PropertyId, u64>> = RefCell::new(HashMap::new()); // total deposited
-    static UNCLAIMED_INCOME: RefCell<HashMap<(PropertyId, Principal), u64>> = RefCell::new(HashMap::new()); // per user
-    static MARKETPLACE: RefCell<Vec<Listing>> = RefCell::new(Vec::new());
+    static UNCLAIMED_INCOME: RefCell<IncomeMap> = RefCell::new(HashMap::new()); // per user and subaccount
+    static CLAIMED_INCOME: RefCell<HashMap<PropertyId, u64>> = RefCell::new(HashMap::new()); // total claimed
+    static DEPOSITS: RefCell<HashMap<PropertyId, Vec<IncomeDeposit>>> = RefCell::new(HashMap::new()); // deposit history, oldest first
+    static VALUATIONS: RefCell<HashMap<PropertyId, Vec<(u64, u64)>>> = RefCell::new(HashMap::new()); // (timestamp, valuation), oldest first
+    static INCOME_DUST: RefCell<HashMap<PropertyId, u64>> = RefCell::new(HashMap::new()); // rounding left from deposits, carried forward
+    static MIN_DEPOSITS: RefCell<HashMap<PropertyId, u64>> = RefCell::new(HashMap::new());
+    static MARKETPLACE: RefCell<Vec<Listing>> = const { RefCell::new(Vec::new()) };
     static ADMINS: RefCell<Vec<Principal>> = RefCell::new(vec![Principal::anonymous()]);
     static ROLES: RefCell<HashMap<Principal, Role>> = RefCell::new(HashMap::new());
     static KYC: RefCell<HashMap<Principal, bool>> = RefCell::new(HashMap::new());
-    static BOOTSTRAPPED: RefCell<bool> = RefCell::new(false);
+    static BOOTSTRAPPED: RefCell<bool> = const { RefCell::new(false) };
     static PROPOSALS: RefCell<HashMap<u64, Proposal>> = RefCell::new(HashMap::new());
-    static NEXT_PROPOSAL_ID: RefCell<u64> = RefCell::new(1);
+    static NEXT_PROPOSAL_ID: RefCell<u64> = const { RefCell::new(1) };
+    static TRADES: RefCell<HashMap<PropertyId, Vec<TradeRecord>>> = RefCell::new(HashMap::new()); // executed trades, oldest first
+    static EVENTS: RefCell<Vec<Event>> = const { RefCell::new(Vec::new()) }; // audit log, id = index + 1
+    static USER_EVENTS: RefCell<HashMap<Principal, Vec<u64>>> = RefCell::new(HashMap::new()); // event ids per affected user
+    static BIDS: RefCell<Vec<Bid>> = const { RefCell::new(Vec::new()) };
+    static NEXT_BID_ID: RefCell<u64> = const { RefCell::new(1) };
+    static SETTLEMENT_LEDGER: RefCell<Option<Principal>> = const { RefCell::new(None) };
+    static SNAPSHOTS: RefCell<HashMap<u64, Snapshot>> = RefCell::new(HashMap::new());
+    static NEXT_SNAPSHOT_ID: RefCell<u64> = const { RefCell::new(1) };
+    static SETTLING: RefCell<HashMap<PropertyId, u64>> = RefCell::new(HashMap::new()); // trades awaiting the ledger, per property
+    static PENDING_TRANSFERS: RefCell<BTreeMap<u64, PendingTransfer>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_PENDING_TRANSFER_ID: RefCell<u64> = const { RefCell::new(1) };
+    static RESERVATIONS: RefCell<BTreeMap<u64, Reservation>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_RESERVATION_ID: RefCell<u64> = const { RefCell::new(1) };
+    static PENDING_OPERATIONS: RefCell<BTreeMap<u64, PendingOperation>> = const { RefCell::new(BTreeMap::new()) };
+    static NEXT_OPERATION_ID: RefCell<u64> = const { RefCell::new(1) };
+    static REQUIRED_APPROVALS: RefCell<u64> = const { RefCell::new(1) }; // admin approvals per high-value action
+    static PLATFORM_FEE_BPS: RefCell<u64> = const { RefCell::new(0) };
+    static FEE_REMAINDERS: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new()); // per seller fractional fee carried forward, in 1/10_000 units
+    static FEE_REBATE_TIERS: RefCell<Vec<FeeRebateTier>> = const { RefCell::new(Vec::new()) }; // by min_holding_nanos ascending
+    static HOLDING_SINCE: RefCell<HashMap<(PropertyId, Principal), u64>> = RefCell::new(HashMap::new()); // when each position was opened
+    static AUTO_CLAIM: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new()); // users whose income is claimed when they move shares
+    static TREASURY: RefCell<u64> = const { RefCell::new(0) }; // platform fees collected
+    static SELLER_PROCEEDS: RefCell<HashMap<(PropertyId, Principal), u128>> = RefCell::new(HashMap::new()); // lifetime, net of fees
+    static NEXT_LISTING_ID: RefCell<u64> = const { RefCell::new(1) };
+    static REGISTRATION_POLICY: RefCell<RegistrationPolicy> = const { RefCell::new(RegistrationPolicy::Open) };
+    static REQUIRE_UNIQUE_NAMES: RefCell<bool> = const { RefCell::new(false) };
+    static REGISTRATIONS: RefCell<HashMap<Principal, u64>> = RefCell::new(HashMap::new()); // properties registered per caller
+    static MAX_LISTINGS_PER_SELLER: RefCell<u64> = const { RefCell::new(DEFAULT_MAX_LISTINGS_PER_SELLER) };
+    static MAX_TOTAL_SHARES: RefCell<Option<u64>> = const { RefCell::new(None) };
+    static MAX_PRICE_PER_SHARE: RefCell<Option<u64>> = const { RefCell::new(None) };
+    static TRADING_WINDOWS: RefCell<HashMap<PropertyId, TradingWindow>> = RefCell::new(HashMap::new());
+    static LOG_LEVEL: RefCell<LogLevel> = const { RefCell::new(LogLevel::Off) };
+    static RATE_LIMIT: RefCell<Option<RateLimit>> = const { RefCell::new(None) };
+    static CALL_WINDOWS: RefCell<HashMap<Principal, (u64, u64)>> = RefCell::new(HashMap::new()); // (window start, calls)
+    static TRANSFER_RESTRICTED: RefCell<HashSet<PropertyId>> = RefCell::new(HashSet::new());
+    static APPROVED_RECIPIENTS: RefCell<HashSet<(PropertyId, Principal)>> = RefCell::new(HashSet::new());
+    static RECOVERABLE_PRINCIPALS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::new()); // provably inaccessible holders
+    static FORBIDDEN_RECIPIENTS: RefCell<HashSet<Principal>> = RefCell::new(HashSet::from([Principal::management_canister()])); // shares sent here are burned
 }
 
-fn get_role(principal: &Principal) -> Role {
-    ROLES.with(|roles| roles.borrow().get(principal).cloned().unwrap_or(Role::User))
+// Clock: the canister reads IC time, unit tests read a settable mock clock.
+#[cfg(test)]
+thread_local! {
+    static MOCK_TIME: RefCell<u64> = const { RefCell::new(0) };
 }
 
-fn is_kyc_verified(principal: &Principal) -> bool {
-    KYC.with(|kyc| kyc.borrow().get(principal).cloned().unwrap_or(false))
+#[cfg(not(test))]
+fn now() -> u64 {
+    ic_cdk::api::time()
 }
 
-#[update]
-pub fn set_kyc_status(user: Principal, status: bool) -> Result<String, String> {
-    let caller_principal = caller();
-    if get_role(&caller_principal) != Role::Admin {
-        return Err("Only admin can set KYC status".to_string());
+#[cfg(test)]
+fn now() -> u64 {
+    MOCK_TIME.with(|t| *t.borrow())
+}
+
+#[cfg(test)]
+fn set_mock_time(nanos: u64) {
+    MOCK_TIME.with(|t| *t.borrow_mut() = nanos);
+}
+
+// Message context: the canister reads the caller, its own id and attached cycles from the
+// IC, unit tests set them directly.
+#[cfg(test)]
+thread_local! {
+    static MOCK_CALLER: RefCell<Principal> = const { RefCell::new(Principal::anonymous()) };
+    static MOCK_CYCLES: RefCell<u128> = const { RefCell::new(0) };
+}
+
+#[cfg(test)]
+fn caller() -> Principal {
+    MOCK_CALLER.with(|c| *c.borrow())
+}
+
+#[cfg(test)]
+fn set_mock_caller(principal: Principal) {
+    MOCK_CALLER.with(|c| *c.borrow_mut() = principal);
+}
+
+#[cfg(not(test))]
+fn canister_id() -> Principal {
+    ic_cdk::id()
+}
+
+#[cfg(test)]
+fn canister_id() -> Principal {
+    Principal::from_slice(&[0xCA; 10])
+}
+
+#[cfg(not(test))]
+fn cycles_available() -> u128 {
+    ic_cdk::api::call::msg_cycles_available128()
+}
+
+#[cfg(test)]
+fn cycles_available() -> u128 {
+    MOCK_CYCLES.with(|c| *c.borrow())
+}
+
+#[cfg(not(test))]
+fn accept_cycles(amount: u128) {
+    ic_cdk::api::call::msg_cycles_accept128(amount);
+}
+
+#[cfg(test)]
+fn accept_cycles(amount: u128) {
+    MOCK_CYCLES.with(|c| *c.borrow_mut() -= amount);
+}
+
+#[cfg(test)]
+fn set_mock_cycles(amount: u128) {
+    MOCK_CYCLES.with(|c| *c.borrow_mut() = amount);
+}
+
+#[cfg(not(test))]
+fn trap(message: &str) -> ! {
+    ic_cdk::trap(message)
+}
+
+#[cfg(test)]
+fn trap(message: &str) -> ! {
+    panic!("{}", message)
+}
+
+/// Whether messages at `level` are currently written to the debug log.
+fn log_enabled(level: LogLevel) -> bool {
+    level != LogLevel::Off && level <= LOG_LEVEL.with(|l| *l.borrow())
+}
+
+/// Write `<LEVEL> <op> <details>` to the debug log if `level` is enabled. Call from updates
+/// only; queries don't log. `details` is only formatted when the message is written.
+fn log(level: LogLevel, op: &str, details: std::fmt::Arguments) {
+    if log_enabled(level) {
+        ic_cdk::println!("{} {} {}", level.label(), op, details);
     }
-    KYC.with(|kyc| {
-        kyc.borrow_mut().insert(user, status);
+}
+
+/// Append an event to the audit log, attributed to the current caller.
+fn record_event(property_id: Option<PropertyId>, kind: EventKind) {
+    record_event_by(caller(), property_id, kind);
+}
+
+/// Append an event attributed to `actor`. Used after an await, once the caller has been captured.
+fn record_event_by(actor: Principal, property_id: Option<PropertyId>, kind: EventKind) {
+    let mut parties = kind.parties();
+    if let (EventKind::IncomeDeposited { .. }, Some(pid)) = (&kind, property_id) {
+        // Every current holder receives a share of a deposit
+        OWNERSHIP.with(|own| parties.extend(holdings(&own.borrow(), pid).into_keys()));
+    }
+    if let EventKind::SnapshotIncomeDistributed { snapshot_id, .. } = &kind {
+        // Holders as of the snapshot receive the distribution
+        SNAPSHOTS.with(|snaps| {
+            if let Some(snap) = snaps.borrow().get(snapshot_id) {
+                parties.extend(snap.balances.iter().map(|(user, _)| *user));
+            }
+        });
+    }
+    parties.sort();
+    parties.dedup();
+    let id = EVENTS.with(|events| {
+        let mut events = events.borrow_mut();
+        let id = events.len() as u64 + 1;
+        events.push(Event {
+            id,
+            timestamp: now(),
+            caller: actor,
+            property_id,
+            kind,
+        });
+        id
+    });
+    USER_EVENTS.with(|index| {
+        let mut index = index.borrow_mut();
+        for user in parties {
+            index.entry(user).or_default().push(id);
+        }
     });
-    Ok("KYC status updated".to_string())
 }
 
-#[update]
-pub fn set_role(user: Principal, role: Role) -> Result<String, String> {
-    let caller_principal = caller();
-    if get_role(&caller_principal) != Role::Admin {
-        return Err("Only admin can set roles".to_string());
+// Settlement ledger (ICRC-1/ICRC-2)
+
+#[derive(CandidType, Deserialize, Clone)]
+struct Account {
+    owner: Principal,
+    subaccount: Option<Vec<u8>>,
+}
+
+#[derive(CandidType)]
+struct TransferFromArgs {
+    spender_subaccount: Option<Vec<u8>>,
+    from: Account,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType)]
+struct TransferArg {
+    from_subaccount: Option<Vec<u8>>,
+    to: Account,
+    amount: Nat,
+    fee: Option<Nat>,
+    memo: Option<Vec<u8>>,
+    created_at_time: Option<u64>,
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+#[derive(CandidType, Deserialize, Debug)]
+enum TransferFromError {
+    BadFee { expected_fee: Nat },
+    BadBurn { min_burn_amount: Nat },
+    InsufficientFunds { balance: Nat },
+    InsufficientAllowance { allowance: Nat },
+    TooOld,
+    CreatedInFuture { ledger_time: u64 },
+    Duplicate { duplicate_of: Nat },
+    TemporarilyUnavailable,
+    GenericError { error_code: Nat, message: String },
+}
+
+fn block_index_to_u64(block: Nat) -> Result<u64, String> {
+    u64::try_from(block.0).map_err(|_| "Ledger returned an out-of-range block index".to_string())
+}
+
+/// Pull `amount` from `from` to `to` on the settlement ledger using the allowance `from`
+/// granted this canister. Returns the ledger block index, or `None` when no settlement
+/// ledger is configured and payment is handled off-chain.
+async fn settle_payment(from: Principal, to: Principal, amount: u64) -> Result<Option<u64>, String> {
+    let ledger = match SETTLEMENT_LEDGER.with(|l| *l.borrow()) {
+        Some(ledger) => ledger,
+        None => return Ok(None),
+    };
+    let args = TransferFromArgs {
+        spender_subaccount: None,
+        from: Account { owner: from, subaccount: None },
+        to: Account { owner: to, subaccount: None },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let block = icrc2_transfer_from(ledger, args).await?.map_err(|e| format!("Ledger transfer failed: {:?}", e))?;
+    block_index_to_u64(block).map(Some)
+}
+
+/// Send `amount` from this canister's account to `to` (in `subaccount`, or the default one) on
+/// the settlement ledger. Returns `None` when no settlement ledger is configured.
+async fn payout(to: Principal, subaccount: Option<Subaccount>, amount: u64) -> Result<Option<u64>, String> {
+    let ledger = match SETTLEMENT_LEDGER.with(|l| *l.borrow()) {
+        Some(ledger) => ledger,
+        None => return Ok(None),
+    };
+    let args = TransferArg {
+        from_subaccount: None,
+        to: Account { owner: to, subaccount },
+        amount: Nat::from(amount),
+        fee: None,
+        memo: None,
+        created_at_time: None,
+    };
+    let block = icrc1_transfer(ledger, args).await?.map_err(|e| format!("Ledger transfer failed: {:?}", e))?;
+    block_index_to_u64(block).map(Some)
+}
+
+#[cfg(not(test))]
+async fn icrc2_transfer_from(ledger: Principal, args: TransferFromArgs) -> Result<Result<Nat, TransferFromError>, String> {
+    let (result,): (Result<Nat, TransferFromError>,) = ic_cdk::call(ledger, "icrc2_transfer_from", (args,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    Ok(result)
+}
+
+#[cfg(not(test))]
+async fn icrc1_transfer(ledger: Principal, args: TransferArg) -> Result<Result<Nat, TransferError>, String> {
+    let (result,): (Result<Nat, TransferError>,) = ic_cdk::call(ledger, "icrc1_transfer", (args,))
+        .await
+        .map_err(|(code, msg)| format!("Ledger call failed: {:?} {}", code, msg))?;
+    Ok(result)
+}
+
+// Unit tests settle against an in-memory ledger that records every transfer and can be
+// switched to reject them, or to suspend each call once like a real inter-canister call.
+#[cfg(test)]
+#[derive(Clone, Debug, PartialEq)]
+struct MockTransfer {
+    from: Option<Principal>,
+    to: Principal,
+    to_subaccount: Option<Subaccount>,
+    amount: u64,
+}
+
+#[cfg(test)]
+thread_local! {
+    static MOCK_TRANSFERS: RefCell<Vec<MockTransfer>> = const { RefCell::new(Vec::new()) };
+    static MOCK_LEDGER_FAILING: RefCell<bool> = const { RefCell::new(false) };
+    static MOCK_LEDGER_SUSPENDING: RefCell<bool> = const { RefCell::new(false) };
+}
+
+#[cfg(test)]
+fn set_mock_ledger_suspending(suspending: bool) {
+    MOCK_LEDGER_SUSPENDING.with(|s| *s.borrow_mut() = suspending);
+}
+
+/// Resolves on its second poll, standing in for the wait on a ledger reply.
+#[cfg(test)]
+async fn mock_ledger_wait() {
+    if !MOCK_LEDGER_SUSPENDING.with(|s| *s.borrow()) {
+        return;
     }
-    ROLES.with(|roles| {
-        roles.borrow_mut().insert(user, role);
+    let mut polled = false;
+    std::future::poll_fn(|_| {
+        if std::mem::replace(&mut polled, true) {
+            std::task::Poll::Ready(())
+        } else {
+            std::task::Poll::Pending
+        }
+    })
+    .await
+}
+
+#[cfg(test)]
+fn set_mock_ledger_failing(failing: bool) {
+    MOCK_LEDGER_FAILING.with(|f| *f.borrow_mut() = failing);
+}
+
+#[cfg(test)]
+fn mock_transfers() -> Vec<MockTransfer> {
+    MOCK_TRANSFERS.with(|t| t.borrow().clone())
+}
+
+/// Record a transfer on the mock ledger, returning its block index.
+#[cfg(test)]
+fn mock_ledger_transfer(from: Option<Principal>, to: &Account, amount: &Nat) -> Option<Nat> {
+    if MOCK_LEDGER_FAILING.with(|f| *f.borrow()) {
+        return None;
+    }
+    let amount = u64::try_from(amount.0.clone()).unwrap();
+    MOCK_TRANSFERS.with(|t| {
+        let mut t = t.borrow_mut();
+        t.push(MockTransfer { from, to: to.owner, to_subaccount: to.subaccount.clone(), amount });
+        Some(Nat::from(t.len() as u64))
+    })
+}
+
+#[cfg(test)]
+async fn icrc2_transfer_from(_ledger: Principal, args: TransferFromArgs) -> Result<Result<Nat, TransferFromError>, String> {
+    mock_ledger_wait().await;
+    let block = mock_ledger_transfer(Some(args.from.owner), &args.to, &args.amount);
+    Ok(block.ok_or(TransferFromError::InsufficientAllowance { allowance: Nat::from(0u64) }))
+}
+
+#[cfg(test)]
+async fn icrc1_transfer(_ledger: Principal, args: TransferArg) -> Result<Result<Nat, TransferError>, String> {
+    mock_ledger_wait().await;
+    let block = mock_ledger_transfer(None, &args.to, &args.amount);
+    Ok(block.ok_or(TransferError::InsufficientFunds { balance: Nat::from(0u64) }))
+}
+
+/// Settle a trade: the buyer pays the platform fee to this canister and the rest to the seller.
+/// If the seller leg fails the fee is refunded. Returns the block index of the seller leg.
+async fn settle_trade(buyer: Principal, seller: Principal, total: u64, fee: u64) -> Result<Option<u64>, String> {
+    if fee > 0 {
+        settle_payment(buyer, canister_id(), fee).await?;
+    }
+    match settle_payment(buyer, seller, total - fee).await {
+        Ok(block) => Ok(block),
+        Err(e) => {
+            if fee > 0 && payout(buyer, None, fee).await.is_err() {
+                // The refund didn't go through, so the canister still holds the fee
+                TREASURY.with(|t| add_saturating(&mut t.borrow_mut(), fee));
+            }
+            Err(e)
+        }
+    }
+}
+
+/// Add a settled trade's fee to the treasury. Trades settled off-chain (no block) never paid
+/// the fee to this canister, so there is nothing to hold for them.
+fn collect_fee(settlement_block: Option<u64>, fee: u64) {
+    if settlement_block.is_some() {
+        TREASURY.with(|t| add_saturating(&mut t.borrow_mut(), fee));
+    }
+}
+
+/// `settle_trade` for a trade of a property's shares. The traded shares are off the books
+/// until the ledger answers, so the property counts as settling meanwhile.
+async fn settle_property_trade(property_id: PropertyId, buyer: Principal, seller: Principal, total: u64, fee: u64) -> Result<Option<u64>, String> {
+    SETTLING.with(|s| *s.borrow_mut().entry(property_id).or_insert(0) += 1);
+    let result = settle_trade(buyer, seller, total, fee).await;
+    SETTLING.with(|s| {
+        let mut s = s.borrow_mut();
+        if let Some(count) = s.get_mut(&property_id) {
+            *count -= 1;
+            if *count == 0 {
+                s.remove(&property_id);
+            }
+        }
+    });
+    result
+}
+
+/// Whether a property has trades waiting on the ledger, whose amounts a split, consolidation
+/// or merge would make stale.
+fn has_settling_trades(property_id: PropertyId) -> bool {
+    SETTLING.with(|s| s.borrow().contains_key(&property_id))
+}
+
+/// Platform fee rate for a sale by `seller`, after any long-term holder rebate. The rebated
+/// rate is rounded down to a whole basis point.
+fn seller_fee_bps(property_id: PropertyId, seller: Principal) -> u64 {
+    let bps = PLATFORM_FEE_BPS.with(|f| *f.borrow());
+    let held_for = HOLDING_SINCE
+        .with(|h| h.borrow().get(&(property_id, seller)).cloned())
+        .map(|since| now().saturating_sub(since))
+        .unwrap_or(0);
+    let rebate_bps = FEE_REBATE_TIERS.with(|t| {
+        t.borrow().iter().filter(|tier| held_for >= tier.min_holding_nanos).map(|tier| tier.rebate_bps).max().unwrap_or(0)
+    });
+    bps - bps * rebate_bps / 10_000
+}
+
+/// Platform fee for a sale by `seller` of `total` at a rate of `bps`.
+///
+/// Fee policy: integer division would round the fee on small trades down to zero, so the
+/// fractional part of each fee is carried forward and added to the seller's next trade. Many
+/// small trades therefore pay the same total fee as one trade of the same combined size, and
+/// no seller pays towards another's fractions.
+fn take_fee(seller: Principal, total: u64, bps: u64) -> u64 {
+    FEE_REMAINDERS.with(|r| {
+        let mut r = r.borrow_mut();
+        let numer = total as u128 * bps as u128 + r.get(&seller).cloned().unwrap_or(0) as u128;
+        set_fee_remainder(&mut r, seller, (numer % 10_000) as u64);
+        ((numer / 10_000) as u64).min(total)
+    })
+}
+
+/// Undo `take_fee` for a trade that failed to settle.
+fn return_fee(seller: Principal, total: u64, fee: u64, bps: u64) {
+    FEE_REMAINDERS.with(|r| {
+        let mut r = r.borrow_mut();
+        let current = r.get(&seller).cloned().unwrap_or(0);
+        let restored = current as i128 + fee as i128 * 10_000 - total as i128 * bps as i128;
+        set_fee_remainder(&mut r, seller, restored.clamp(0, u64::MAX as i128) as u64);
+    });
+}
+
+fn set_fee_remainder(remainders: &mut HashMap<Principal, u64>, seller: Principal, remainder: u64) {
+    if remainder == 0 {
+        remainders.remove(&seller);
+    } else {
+        remainders.insert(seller, remainder);
+    }
+}
+
+/// The all-zero subaccount, which listings, locks and plain transfers use.
+fn default_subaccount() -> Subaccount {
+    vec![0; 32]
+}
+
+/// Validate a subaccount, mapping `None` to the default one.
+fn normalize_subaccount(subaccount: Option<Subaccount>) -> Result<Subaccount, String> {
+    match subaccount {
+        None => Ok(default_subaccount()),
+        Some(sub) if sub.len() != 32 => Err("Subaccount must be 32 bytes".to_string()),
+        Some(sub) => Ok(sub),
+    }
+}
+
+/// Shares a user holds across all their subaccounts of a property.
+fn holding(own: &OwnershipMap, property_id: PropertyId, user: Principal) -> u64 {
+    own.iter().filter(|((pid, owner, _), _)| *pid == property_id && *owner == user).map(|(_, s)| *s).sum()
+}
+
+/// Every holder of a property and their shares across all subaccounts, by principal.
+fn holdings(own: &OwnershipMap, property_id: PropertyId) -> BTreeMap<Principal, u64> {
+    let mut holders = BTreeMap::new();
+    for ((pid, user, _), shares) in own.iter() {
+        if *pid == property_id {
+            *holders.entry(*user).or_insert(0) += *shares;
+        }
+    }
+    holders
+}
+
+/// Add shares to one of a user's subaccounts.
+fn credit_subaccount(own: &mut OwnershipMap, property_id: PropertyId, user: Principal, subaccount: Subaccount, amount: u64) {
+    if amount > 0 {
+        if holding(own, property_id, user) == 0 {
+            // A new position: start its holding period
+            HOLDING_SINCE.with(|h| h.borrow_mut().insert((property_id, user), now()));
+        }
+        *own.entry((property_id, user, subaccount)).or_insert(0) += amount;
+    }
+}
+
+/// Add shares to a user's default subaccount.
+fn credit_shares(own: &mut OwnershipMap, property_id: PropertyId, user: Principal, amount: u64) {
+    credit_subaccount(own, property_id, user, default_subaccount(), amount);
+}
+
+/// Give back shares reserved for a trade that didn't settle, keeping the holder's original
+/// holding period.
+fn restore_shares(own: &mut OwnershipMap, property_id: PropertyId, user: Principal, amount: u64) {
+    if amount > 0 {
+        *own.entry((property_id, user, default_subaccount())).or_insert(0) += amount;
+    }
+}
+
+/// Shares of a user's balance locked as collateral, across all lockers.
+fn locked_shares(property_id: PropertyId, user: Principal) -> u64 {
+    LOCKED.with(|locked| {
+        locked.borrow().iter().filter(|((pid, owner, _), _)| *pid == property_id && *owner == user).map(|(_, s)| *s).sum()
+    })
+}
+
+/// Shares a user can move out of one of their subaccounts. Locks draw on the default one.
+fn available_balance(own: &OwnershipMap, property_id: PropertyId, user: Principal, subaccount: &Subaccount) -> u64 {
+    let balance = own.get(&(property_id, user, subaccount.clone())).cloned().unwrap_or(0);
+    if *subaccount == default_subaccount() {
+        balance.saturating_sub(locked_shares(property_id, user))
+    } else {
+        balance
+    }
+}
+
+/// Shares a user can move from their default subaccount: its balance minus anything locked.
+fn unlocked_balance(own: &OwnershipMap, property_id: PropertyId, user: Principal) -> u64 {
+    available_balance(own, property_id, user, &default_subaccount())
+}
+
+/// Remove shares from one of a user's subaccounts, dropping the entry once it reaches zero.
+/// Returns false without changing anything if too few of them are available.
+fn debit_subaccount(own: &mut OwnershipMap, property_id: PropertyId, user: Principal, subaccount: &Subaccount, amount: u64) -> bool {
+    if available_balance(own, property_id, user, subaccount) < amount {
+        return false;
+    }
+    let key = (property_id, user, subaccount.clone());
+    let balance = own.get(&key).cloned().unwrap_or(0);
+    if balance == amount {
+        own.remove(&key);
+    } else {
+        own.insert(key, balance - amount);
+    }
+    true
+}
+
+/// Remove shares from a user's default subaccount. Returns false without changing anything
+/// if the unlocked balance is too small.
+fn debit_shares(own: &mut OwnershipMap, property_id: PropertyId, user: Principal, amount: u64) -> bool {
+    debit_subaccount(own, property_id, user, &default_subaccount(), amount)
+}
+
+/// Rebuild the name index for `name` from the properties currently carrying it.
+fn reindex_name(name: &str) {
+    let id = PROPERTIES.with(|props| props.borrow().values().filter(|p| p.name == name).map(|p| p.id).min());
+    NAME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        match id {
+            Some(id) => index.insert(name.to_string(), id),
+            None => index.remove(name),
+        };
+    });
+}
+
+/// Whether `name` is already taken by a property other than `except`, when unique names are required.
+fn name_conflicts(name: &str, except: Option<PropertyId>) -> bool {
+    REQUIRE_UNIQUE_NAMES.with(|r| *r.borrow())
+        && PROPERTIES.with(|props| props.borrow().values().any(|p| p.name == name && Some(p.id) != except))
+}
+
+/// Collect items until their encoded size would exceed the response budget.
+/// Returns the items and whether the result was truncated.
+fn cap_response<T: CandidType>(items: impl Iterator<Item = T>) -> (Vec<T>, bool) {
+    let mut out = Vec::new();
+    let mut size = 0;
+    for item in items {
+        size += candid::encode_one(&item).map(|bytes| bytes.len()).unwrap_or(0);
+        if size > MAX_RESPONSE_BYTES {
+            return (out, true);
+        }
+        out.push(item);
+    }
+    (out, false)
+}
+
+/// Defense in depth after any change to a property's supply: unissued shares can never
+/// exceed the total. Traps, rolling back the call, if the invariant is broken.
+fn assert_supply_invariant(property_id: PropertyId) {
+    let broken = PROPERTIES.with(|props| {
+        props.borrow().get(&property_id).is_some_and(|p| p.shares_available > p.total_shares)
+    });
+    if broken {
+        trap(&format!("Supply invariant violated for property {}", property_id));
+    }
+}
+
+/// Whether a property supply exceeds the admin-configured ceiling, if any.
+fn exceeds_share_cap(total_shares: u64) -> bool {
+    MAX_TOTAL_SHARES.with(|m| m.borrow().is_some_and(|max| total_shares > max))
+}
+
+/// Add to a lifetime running total, pinning at `u64::MAX` instead of overflowing.
+fn add_saturating(total: &mut u64, amount: u64) {
+    *total = total.saturating_add(amount);
+}
+
+fn credit_proceeds(property_id: PropertyId, seller: Principal, amount: u64) {
+    SELLER_PROCEEDS.with(|p| {
+        *p.borrow_mut().entry((property_id, seller)).or_insert(0) += amount as u128;
+    });
+}
+
+/// Add to the unclaimed income of one of a user's subaccounts, without creating entries for nothing.
+fn credit_income(ui: &mut IncomeMap, property_id: PropertyId, user: Principal, subaccount: Subaccount, amount: u64) {
+    if amount > 0 {
+        *ui.entry((property_id, user, subaccount)).or_insert(0) += amount;
+    }
+}
+
+/// A user's unclaimed income for a property across all their subaccounts.
+fn total_income(ui: &IncomeMap, property_id: PropertyId, user: Principal) -> u64 {
+    ui.iter()
+        .filter(|((pid, owner, _), _)| *pid == property_id && *owner == user)
+        .fold(0u64, |total, (_, amount)| total.saturating_add(*amount))
+}
+
+/// Take `amount` out of a user's unclaimed income for a property, default subaccount first and
+/// the others in order after it. The caller has checked their total covers it.
+fn debit_income(ui: &mut IncomeMap, property_id: PropertyId, user: Principal, mut amount: u64) {
+    // The all-zero default sorts before every other subaccount
+    let mut subaccounts: Vec<Subaccount> =
+        ui.keys().filter(|(pid, owner, _)| *pid == property_id && *owner == user).map(|(_, _, sub)| sub.clone()).collect();
+    subaccounts.sort();
+    for sub in subaccounts {
+        if amount == 0 {
+            break;
+        }
+        let key = (property_id, user, sub);
+        let balance = ui.get(&key).cloned().unwrap_or(0);
+        let cut = balance.min(amount);
+        amount -= cut;
+        if cut == balance {
+            ui.remove(&key);
+        } else {
+            ui.insert(key, balance - cut);
+        }
+    }
+}
+
+/// Total each holder was allocated across their subaccounts, ordered by principal.
+fn allocations_by_holder(allocations: &[(Principal, Subaccount, u64)]) -> Vec<(Principal, u64)> {
+    let mut totals: BTreeMap<Principal, u64> = BTreeMap::new();
+    for (user, _, amount) in allocations {
+        *totals.entry(*user).or_insert(0) += *amount;
+    }
+    totals.into_iter().collect()
+}
+
+/// A holder's pro-rata cut of an income amount, rounded down.
+fn income_share(amount: u64, shares: u64, total_shares: u64) -> u64 {
+    (amount as u128 * shares as u128 / total_shares as u128) as u64
+}
+
+/// Income available to current holders from a deposit: their pro-rata cut of `amount`, given
+/// `issued` of `total_shares` are held, plus rounding dust carried from earlier deposits.
+fn holder_pool(property_id: PropertyId, amount: u64, issued: u64, total_shares: u64) -> u64 {
+    let dust = INCOME_DUST.with(|d| d.borrow().get(&property_id).cloned().unwrap_or(0));
+    income_share(amount, issued, total_shares).saturating_add(dust)
+}
+
+/// Split a deposit between a property's current holders, including carried dust. Returns the
+/// cut of each subaccount holding shares, ordered by principal and subaccount, and the dust left
+/// for the next deposit.
+fn compute_distribution(property_id: PropertyId, amount: u64, issued: u64, total_shares: u64) -> (Vec<(Principal, Subaccount, u64)>, u64) {
+    let pool = holder_pool(property_id, amount, issued, total_shares);
+    let mut allocations: Vec<(Principal, Subaccount, u64)> = OWNERSHIP.with(|own| {
+        own.borrow()
+            .iter()
+            .filter(|((pid, _, _), shares)| *pid == property_id && **shares > 0)
+            .map(|((_, user, sub), shares)| (*user, sub.clone(), income_share(pool, *shares, issued)))
+            .collect()
+    });
+    allocations.sort();
+    let allocated: u64 = allocations.iter().map(|(_, _, amount)| *amount).sum();
+    (allocations, pool - allocated)
+}
+
+/// Whether `to` may receive shares of a property through transfers and trades. The anonymous
+/// principal and forbidden destinations never may.
+fn recipient_allowed(property_id: PropertyId, to: Principal) -> bool {
+    if to == Principal::anonymous() || FORBIDDEN_RECIPIENTS.with(|f| f.borrow().contains(&to)) {
+        return false;
+    }
+    !TRANSFER_RESTRICTED.with(|r| r.borrow().contains(&property_id))
+        || APPROVED_RECIPIENTS.with(|a| a.borrow().contains(&(property_id, to)))
+}
+
+/// Whether a property exists and is `Active`. Listings left over after a property is sold,
+/// delisted or put under maintenance can't be bought from.
+fn property_active(property_id: PropertyId) -> bool {
+    PROPERTIES.with(|props| props.borrow().get(&property_id).is_some_and(|p| p.status == PropertyStatus::Active))
+}
+
+/// Whether a property has pending transfers, which would go stale if its share count changed.
+fn has_pending_transfers(property_id: PropertyId) -> bool {
+    PENDING_TRANSFERS.with(|p| p.borrow().values().any(|t| t.property_id == property_id))
+}
+
+/// Shares of a property held in open reservations. They are neither available nor issued.
+fn reserved_shares(property_id: PropertyId) -> u64 {
+    RESERVATIONS.with(|r| r.borrow().values().filter(|r| r.property_id == property_id).map(|r| r.amount).sum())
+}
+
+/// Shares of a property held by investors.
+fn issued_shares(prop: &Property) -> u64 {
+    prop.total_shares - prop.shares_available - reserved_shares(prop.id)
+}
+
+/// Return a reservation's shares to `shares_available`.
+fn release_reservation(reservation: &Reservation) {
+    PROPERTIES.with(|props| {
+        if let Some(prop) = props.borrow_mut().get_mut(&reservation.property_id) {
+            prop.shares_available += reservation.amount;
+        }
+    });
+    assert_supply_invariant(reservation.property_id);
+    record_event(
+        Some(reservation.property_id),
+        EventKind::ReservationReleased { reservation_id: reservation.id, to: reservation.to },
+    );
+}
+
+/// Release every reservation of a property that has passed its expiry.
+fn release_expired_reservations(property_id: PropertyId) {
+    let at = now();
+    let expired: Vec<Reservation> = RESERVATIONS.with(|r| {
+        let mut r = r.borrow_mut();
+        let ids: Vec<u64> =
+            r.values().filter(|res| res.property_id == property_id && res.expires_at <= at).map(|res| res.id).collect();
+        ids.iter().filter_map(|id| r.remove(id)).collect()
+    });
+    for reservation in &expired {
+        release_reservation(reservation);
+    }
+}
+
+/// Release `amount` of the lock this canister holds on a sender's shares for pending transfers.
+fn release_pending_lock(transfer: &PendingTransfer) {
+    LOCKED.with(|locked| {
+        let mut locked = locked.borrow_mut();
+        let key = (transfer.property_id, transfer.from, canister_id());
+        let remaining = locked.get(&key).cloned().unwrap_or(0).saturating_sub(transfer.amount);
+        if remaining == 0 {
+            locked.remove(&key);
+        } else {
+            locked.insert(key, remaining);
+        }
+    });
+}
+
+/// Whether trading in a property is currently allowed by its trading window, if it has one.
+fn trading_open(property_id: PropertyId) -> bool {
+    TRADING_WINDOWS.with(|w| w.borrow().get(&property_id).is_none_or(|window| window.is_open(now())))
+}
+
+/// Parse a legacy textual user id into a principal.
+fn user_id_to_principal(id: &UserId) -> Result<Principal, String> {
+    Principal::from_text(id.trim()).map_err(|e| format!("Invalid principal '{}': {}", id, e))
+}
+
+/// The caller, unless it is the anonymous principal, which can't hold shares or trade, or it
+/// has used up its calls under the rate limit. Admins aren't rate limited.
+fn authenticated_caller() -> Result<Principal, String> {
+    let principal = caller();
+    if principal == Principal::anonymous() {
+        return Err(RwaError::Unauthorized.to_string());
+    }
+    if get_role(&principal) != Role::Admin {
+        check_rate_limit(principal)?;
+    }
+    Ok(principal)
+}
+
+/// Count a call against `principal`'s rate limit, failing once the window's calls are used up.
+fn check_rate_limit(principal: Principal) -> Result<(), String> {
+    let Some(limit) = RATE_LIMIT.with(|l| *l.borrow()) else {
+        return Ok(());
+    };
+    let at = now();
+    CALL_WINDOWS.with(|w| {
+        let mut w = w.borrow_mut();
+        let (start, calls) = w.entry(principal).or_insert((at, 0));
+        if at.saturating_sub(*start) >= limit.window_nanos {
+            *start = at;
+            *calls = 0;
+        }
+        if *calls >= limit.max_calls {
+            return Err(RwaError::RateLimited.to_string());
+        }
+        *calls += 1;
+        Ok(())
+    })
+}
+
+fn get_role(principal: &Principal) -> Role {
+    ROLES.with(|roles| roles.borrow().get(principal).cloned().unwrap_or(Role::User))
+}
+
+fn is_kyc_verified(principal: &Principal) -> bool {
+    KYC.with(|kyc| kyc.borrow().get(principal).cloned().unwrap_or(false))
+}
+
+#[update]
+pub fn set_kyc_status(user: Principal, status: bool) -> Result<String, String> {
+    let caller_principal = caller();
+    if get_role(&caller_principal) != Role::Admin {
+        return Err("Only admin can set KYC status".to_string());
+    }
+    KYC.with(|kyc| {
+        kyc.borrow_mut().insert(user, status);
+    });
+    record_event(None, EventKind::KycSet { user, status });
+    Ok("KYC status updated".to_string())
+}
+
+/// Admin sets a user's role. Once multi-sig is on, roles change through `propose_operation`,
+/// so a single admin can't appoint their own co-approvers.
+#[update]
+pub fn set_role(user: Principal, role: Role) -> Result<String, String> {
+    let caller_principal = caller();
+    if get_role(&caller_principal) != Role::Admin {
+        return Err("Only admin can set roles".to_string());
+    }
+    if multi_sig_required() {
+        return Err("Role changes require multi-sig approval; use propose_operation".to_string());
+    }
+    apply_role(user, role)
+}
+
+fn apply_role(user: Principal, role: Role) -> Result<String, String> {
+    if get_role(&user) == Role::Admin && role != Role::Admin && admin_count() <= get_required_approvals() {
+        return Err("Demoting this admin would leave too few admins to approve operations".to_string());
+    }
+    ROLES.with(|roles| {
+        roles.borrow_mut().insert(user, role.clone());
+    });
+    record_event(None, EventKind::RoleSet { user, role });
+    Ok("Role updated".to_string())
+}
+
+fn admin_count() -> u64 {
+    ROLES.with(|roles| roles.borrow().values().filter(|r| **r == Role::Admin).count() as u64)
+}
+
+#[update]
+pub fn bootstrap_admin(admin: Principal) -> Result<String, String> {
+    let already_bootstrapped = BOOTSTRAPPED.with(|b| *b.borrow());
+    if already_bootstrapped {
+        return Err("Admin already bootstrapped".to_string());
+    }
+    ROLES.with(|roles| {
+        roles.borrow_mut().insert(admin, Role::Admin);
+    });
+    BOOTSTRAPPED.with(|b| *b.borrow_mut() = true);
+    record_event(None, EventKind::RoleSet { user: admin, role: Role::Admin });
+    Ok("Admin bootstrapped".to_string())
+}
+
+#[query]
+pub fn get_my_role() -> Role {
+    get_role(&caller())
+}
+
+#[query]
+pub fn is_my_kyc_verified() -> bool {
+    is_kyc_verified(&caller())
+}
+
+/// Admin updates a property's name and metadata. Only the provided fields change;
+/// share counts can't be modified through this path.
+#[update]
+pub fn update_property_metadata(property_id: PropertyId, args: UpdatePropertyArgs) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can update property metadata".to_string());
+    }
+    if args.name.as_deref().is_some_and(|name| name_conflicts(name, Some(property_id))) {
+        return Err("A property with this name already exists".to_string());
+    }
+    let mut renamed = None;
+    let result = PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        if let Some(prop) = props.get_mut(&property_id) {
+            if let Some(name) = args.name {
+                renamed = Some((std::mem::replace(&mut prop.name, name.clone()), name));
+            }
+            if let Some(location) = args.location {
+                prop.metadata.location = location;
+            }
+            if let Some(description) = args.description {
+                prop.metadata.description = description;
+            }
+            if let Some(valuation) = args.valuation {
+                if valuation != prop.metadata.valuation {
+                    VALUATIONS.with(|v| v.borrow_mut().entry(property_id).or_default().push((now(), valuation)));
+                }
+                prop.metadata.valuation = valuation;
+            }
+            if let Some(decimals) = args.decimals {
+                prop.metadata.decimals = decimals;
+            }
+            Ok("Property metadata updated".to_string())
+        } else {
+            Err("Property not found".to_string())
+        }
+    });
+    if let Some((old_name, new_name)) = renamed {
+        reindex_name(&old_name);
+        reindex_name(&new_name);
+    }
+    if result.is_ok() {
+        record_event(Some(property_id), EventKind::PropertyUpdated);
+    }
+    result
+}
+
+/// Query the valuation changes made through `update_property_metadata`, oldest first,
+/// as `(timestamp, valuation)` pairs.
+#[query]
+pub fn get_valuation_history(property_id: PropertyId) -> Vec<(u64, u64)> {
+    VALUATIONS.with(|v| v.borrow().get(&property_id).cloned().unwrap_or_default())
+}
+
+/// Admin attaches a legal document reference to a property.
+#[update]
+pub fn add_document(property_id: PropertyId, doc: DocumentRef) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can add documents".to_string());
+    }
+    if doc.sha256.len() != 32 {
+        return Err("Document hash must be 32 bytes".to_string());
+    }
+    let sha256 = doc.sha256.clone();
+    PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        let prop = props.get_mut(&property_id).ok_or_else(|| "Property not found".to_string())?;
+        if prop.documents.iter().any(|d| d.sha256 == doc.sha256) {
+            return Err("Document already attached".to_string());
+        }
+        prop.documents.push(doc);
+        Ok(())
+    })?;
+    record_event(Some(property_id), EventKind::DocumentAdded { sha256 });
+    Ok("Document added".to_string())
+}
+
+/// Admin removes the document with the given hash from a property.
+#[update]
+pub fn remove_document(property_id: PropertyId, sha256: Vec<u8>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can remove documents".to_string());
+    }
+    PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        let prop = props.get_mut(&property_id).ok_or_else(|| "Property not found".to_string())?;
+        let pos = prop.documents.iter().position(|d| d.sha256 == sha256).ok_or_else(|| "Document not found".to_string())?;
+        prop.documents.remove(pos);
+        Ok::<_, String>(())
+    })?;
+    record_event(Some(property_id), EventKind::DocumentRemoved { sha256 });
+    Ok("Document removed".to_string())
+}
+
+#[update]
+pub fn update_property_status(property_id: PropertyId, status: PropertyStatus) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can update property status".to_string());
+    }
+    let result = PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        if let Some(prop) = props.get_mut(&property_id) {
+            prop.status = status.clone();
+            Ok("Property status updated".to_string())
+        } else {
+            Err("Property not found".to_string())
+        }
+    });
+    if result.is_ok() {
+        record_event(Some(property_id), EventKind::PropertyStatusChanged { status });
+    }
+    result
+}
+
+/// Admin deletes a property that no investor holds or is owed income from, purging every
+/// record keyed by it. Ids are never reused, since `NEXT_PROPERTY_ID` only increments; the
+/// audit log keeps the property's history.
+#[update]
+pub fn delete_property(property_id: PropertyId) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can delete properties".to_string());
+    }
+    let prop = PROPERTIES.with(|props| props.borrow().get(&property_id).cloned()).ok_or_else(|| "Property not found".to_string())?;
+    if prop.shares_available != prop.total_shares {
+        return Err("Cannot delete a property with issued shares".to_string());
+    }
+    if UNCLAIMED_INCOME.with(|ui| ui.borrow().iter().any(|((pid, _, _), a)| *pid == property_id && *a > 0)) {
+        return Err("Cannot delete a property with unclaimed income".to_string());
+    }
+    PROPERTIES.with(|props| props.borrow_mut().remove(&property_id));
+    reindex_name(&prop.name);
+    // Nobody holds shares, but purge anything keyed by the id regardless
+    OWNERSHIP.with(|own| own.borrow_mut().retain(|(pid, _, _), _| *pid != property_id));
+    LOCKED.with(|locked| locked.borrow_mut().retain(|(pid, _, _), _| *pid != property_id));
+    HOLDING_SINCE.with(|hs| hs.borrow_mut().retain(|(pid, _), _| *pid != property_id));
+    UNCLAIMED_INCOME.with(|ui| ui.borrow_mut().retain(|(pid, _, _), _| *pid != property_id));
+    SELLER_PROCEEDS.with(|sp| sp.borrow_mut().retain(|(pid, _), _| *pid != property_id));
+    APPROVED_RECIPIENTS.with(|ar| ar.borrow_mut().retain(|(pid, _)| *pid != property_id));
+    TRANSFER_RESTRICTED.with(|tr| tr.borrow_mut().remove(&property_id));
+    RENTAL_INCOME.with(|ri| ri.borrow_mut().remove(&property_id));
+    CLAIMED_INCOME.with(|ci| ci.borrow_mut().remove(&property_id));
+    DEPOSITS.with(|d| d.borrow_mut().remove(&property_id));
+    VALUATIONS.with(|v| v.borrow_mut().remove(&property_id));
+    INCOME_DUST.with(|d| d.borrow_mut().remove(&property_id));
+    MIN_DEPOSITS.with(|m| m.borrow_mut().remove(&property_id));
+    TRADING_WINDOWS.with(|w| w.borrow_mut().remove(&property_id));
+    TRADES.with(|t| t.borrow_mut().remove(&property_id));
+    MARKETPLACE.with(|mp| mp.borrow_mut().retain(|l| l.property_id != property_id));
+    BIDS.with(|bids| bids.borrow_mut().retain(|b| b.property_id != property_id));
+    PENDING_TRANSFERS.with(|pt| pt.borrow_mut().retain(|_, t| t.property_id != property_id));
+    SNAPSHOTS.with(|s| s.borrow_mut().retain(|_, s| s.property_id != property_id));
+    PROPOSALS.with(|p| p.borrow_mut().retain(|_, p| p.property_id != property_id));
+    record_event(Some(property_id), EventKind::PropertyDeleted);
+    Ok("Property deleted".to_string())
+}
+
+/// Admin merges two properties into a new one with their combined shares and valuation.
+/// Each subaccount receives new shares worth what its holdings in `a` and `b` were worth at
+/// the current valuations, rounded down; what rounding leaves stays unissued. Both originals
+/// are delisted with their listings and bids cancelled and their open proposals rejected,
+/// while their unclaimed income stays claimable there and their undistributed dust carries
+/// over to the new property. Transfer-restricted properties can't be merged, since the new
+/// property would trade without their restriction.
+#[update]
+pub fn merge_properties(a: PropertyId, b: PropertyId) -> Result<PropertyId, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can merge properties".to_string());
+    }
+    if a == b {
+        return Err("Can't merge a property with itself".to_string());
+    }
+    let get = |id| PROPERTIES.with(|props| props.borrow().get(&id).cloned()).ok_or_else(|| "Property not found".to_string());
+    let (prop_a, prop_b) = (get(a)?, get(b)?);
+    if prop_a.status != PropertyStatus::Active || prop_b.status != PropertyStatus::Active {
+        return Err("Property is not active".to_string());
+    }
+    if prop_a.metadata.decimals != prop_b.metadata.decimals {
+        return Err("Properties must use the same price decimals".to_string());
+    }
+    if prop_a.total_shares == 0 || prop_b.total_shares == 0 || prop_a.metadata.valuation == 0 || prop_b.metadata.valuation == 0 {
+        return Err("Both properties need shares and a valuation".to_string());
+    }
+    for id in [a, b] {
+        if has_pending_transfers(id) {
+            return Err("Property has pending transfers".to_string());
+        }
+        if has_settling_trades(id) {
+            return Err("Property has trades settling".to_string());
+        }
+        if reserved_shares(id) > 0 {
+            return Err("Property has open reservations".to_string());
+        }
+        if LOCKED.with(|locked| locked.borrow().iter().any(|((pid, _, _), s)| *pid == id && *s > 0)) {
+            return Err("Property has locked shares".to_string());
+        }
+        if is_transfer_restricted(id) {
+            return Err("Property is transfer-restricted".to_string());
+        }
+    }
+    let overflow = || "Merge would overflow share counts".to_string();
+    let total_shares = prop_a.total_shares.checked_add(prop_b.total_shares).ok_or_else(overflow)?;
+    let valuation = prop_a.metadata.valuation.checked_add(prop_b.metadata.valuation).ok_or_else(overflow)?;
+    if exceeds_share_cap(total_shares) {
+        return Err("Total shares exceed the maximum allowed per property".to_string());
+    }
+    let name = format!("{} + {}", prop_a.name, prop_b.name);
+    if name_conflicts(&name, None) {
+        return Err("A property with this name already exists".to_string());
+    }
+    // Value each subaccount's combined position, then convert it to new shares
+    let mut values: BTreeMap<(Principal, Subaccount), u128> = BTreeMap::new();
+    OWNERSHIP.with(|own| {
+        for ((pid, user, sub), shares) in own.borrow().iter() {
+            let prop = match *pid {
+                pid if pid == a => &prop_a,
+                pid if pid == b => &prop_b,
+                _ => continue,
+            };
+            *values.entry((*user, sub.clone())).or_insert(0) +=
+                *shares as u128 * prop.metadata.valuation as u128 / prop.total_shares as u128;
+        }
+    });
+    // No value exceeds the combined valuation, so no allocation exceeds total_shares
+    let allocations: Vec<(Principal, Subaccount, u64)> = values
+        .into_iter()
+        .map(|((user, sub), value)| {
+            let shares = value.checked_mul(total_shares as u128).ok_or_else(overflow)? / valuation as u128;
+            Ok((user, sub, shares as u64))
+        })
+        .filter(|r| !matches!(r, Ok((_, _, 0))))
+        .collect::<Result<Vec<_>, String>>()?;
+    let issued: u64 = allocations.iter().map(|(_, _, shares)| *shares).sum();
+    // Apply
+    let id = NEXT_PROPERTY_ID.with(|id| {
+        let mut id = id.borrow_mut();
+        let curr = *id;
+        *id += 1;
+        curr
+    });
+    let metadata = PropertyMetadata {
+        location: format!("{}; {}", prop_a.metadata.location, prop_b.metadata.location),
+        description: format!("Merger of properties {} and {}", a, b),
+        valuation,
+        decimals: prop_a.metadata.decimals,
+    };
+    let documents = prop_a.documents.iter().chain(prop_b.documents.iter()).cloned().collect();
+    let property = Property {
+        id,
+        name,
+        total_shares,
+        shares_available: total_shares - issued,
+        metadata,
+        status: PropertyStatus::Active,
+        documents,
+    };
+    PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        props.insert(id, property.clone());
+        // The originals' holdings have been exchanged, so their shares are all unissued again
+        for old in [a, b] {
+            if let Some(prop) = props.get_mut(&old) {
+                prop.shares_available = prop.total_shares;
+                prop.status = PropertyStatus::Delisted;
+            }
+        }
+    });
+    NAME_INDEX.with(|index| {
+        index.borrow_mut().entry(property.name.clone()).or_insert(id);
+    });
+    OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        own.retain(|(pid, _, _), _| *pid != a && *pid != b);
+        for (user, sub, shares) in &allocations {
+            credit_subaccount(&mut own, id, *user, sub.clone(), *shares);
+        }
+    });
+    HOLDING_SINCE.with(|hs| hs.borrow_mut().retain(|(pid, _), _| *pid != a && *pid != b));
+    let dust = INCOME_DUST.with(|d| {
+        let mut d = d.borrow_mut();
+        d.remove(&a).unwrap_or(0).saturating_add(d.remove(&b).unwrap_or(0))
+    });
+    if dust > 0 {
+        INCOME_DUST.with(|d| d.borrow_mut().insert(id, dust));
+    }
+    let cancelled: Vec<Listing> = MARKETPLACE.with(|mp| {
+        let mut mp = mp.borrow_mut();
+        let (cancelled, kept) = mp.drain(..).partition(|l| l.property_id == a || l.property_id == b);
+        *mp = kept;
+        cancelled
+    });
+    let cancelled_bids: Vec<Bid> = BIDS.with(|bids| {
+        let mut bids = bids.borrow_mut();
+        let (cancelled, kept) = bids.drain(..).partition(|bid| bid.property_id == a || bid.property_id == b);
+        *bids = kept;
+        cancelled
+    });
+    let mut rejected: Vec<(PropertyId, u64)> = PROPOSALS.with(|props| {
+        props
+            .borrow_mut()
+            .values_mut()
+            .filter(|p| (p.property_id == a || p.property_id == b) && p.status == ProposalStatus::Open)
+            .map(|p| {
+                p.status = ProposalStatus::Rejected;
+                (p.property_id, p.id)
+            })
+            .collect()
+    });
+    rejected.sort();
+    for pid in [id, a, b] {
+        assert_supply_invariant(pid);
+    }
+    record_event(Some(id), EventKind::PropertyRegistered);
+    for (user, shares) in allocations_by_holder(&allocations) {
+        record_event(Some(id), EventKind::SharesIssued { to: user, amount: shares });
+    }
+    for old in [a, b] {
+        record_event(Some(old), EventKind::PropertyStatusChanged { status: PropertyStatus::Delisted });
+        record_event(Some(old), EventKind::PropertyMerged { into: id });
+    }
+    for listing in cancelled {
+        record_event(Some(listing.property_id), EventKind::ListingCancelled { listing_id: listing.id, seller: listing.seller });
+    }
+    for bid in cancelled_bids {
+        record_event(Some(bid.property_id), EventKind::BidCancelled { bid_id: bid.id, buyer: bid.buyer });
+    }
+    for (property_id, proposal_id) in rejected {
+        record_event(Some(property_id), EventKind::ProposalExecuted { proposal_id, approved: false });
+    }
+    Ok(id)
+}
+
+/// Admin sets the policy that governs who may register properties.
+#[update]
+pub fn set_registration_policy(policy: RegistrationPolicy) -> Result<String, String> {
+    let caller_principal = caller();
+    if get_role(&caller_principal) != Role::Admin {
+        return Err("Only admin can set registration policy".to_string());
+    }
+    REGISTRATION_POLICY.with(|p| *p.borrow_mut() = policy);
+    record_event(None, EventKind::ConfigChanged { setting: "registration_policy".to_string() });
+    Ok("Registration policy updated".to_string())
+}
+
+#[query]
+pub fn get_registration_policy() -> RegistrationPolicy {
+    REGISTRATION_POLICY.with(|p| p.borrow().clone())
+}
+
+/// Admin toggles whether property names must be unique.
+#[update]
+pub fn set_require_unique_names(required: bool) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set name uniqueness".to_string());
+    }
+    REQUIRE_UNIQUE_NAMES.with(|r| *r.borrow_mut() = required);
+    record_event(None, EventKind::ConfigChanged { setting: "require_unique_names".to_string() });
+    Ok("Name uniqueness updated".to_string())
+}
+
+#[query]
+pub fn get_require_unique_names() -> bool {
+    REQUIRE_UNIQUE_NAMES.with(|r| *r.borrow())
+}
+
+// Update register_property to include metadata and status
+#[update]
+pub fn register_property(name: String, total_shares: u64, metadata: PropertyMetadata) -> Result<Property, String> {
+    let registrant = authenticated_caller()?;
+    if name_conflicts(&name, None) {
+        return Err("A property with this name already exists".to_string());
+    }
+    if exceeds_share_cap(total_shares) {
+        return Err("Total shares exceed the maximum allowed per property".to_string());
+    }
+    if get_role(&registrant) != Role::Admin {
+        match REGISTRATION_POLICY.with(|p| p.borrow().clone()) {
+            RegistrationPolicy::Open => {}
+            RegistrationPolicy::AdminOnly => {
+                return Err("Only admin can register properties".to_string());
+            }
+            RegistrationPolicy::Quota(max) => {
+                let registered = REGISTRATIONS.with(|r| r.borrow().get(&registrant).cloned().unwrap_or(0));
+                if registered >= max {
+                    return Err("Registration quota exceeded".to_string());
+                }
+            }
+            RegistrationPolicy::Paid(fee) => {
+                if cycles_available() < fee as u128 {
+                    return Err(format!("Registration requires {} cycles", fee));
+                }
+                accept_cycles(fee as u128);
+            }
+        }
+    }
+    REGISTRATIONS.with(|r| {
+        *r.borrow_mut().entry(registrant).or_insert(0) += 1;
+    });
+    let property = PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        let id = NEXT_PROPERTY_ID.with(|id| {
+            let mut id = id.borrow_mut();
+            let curr = *id;
+            *id += 1;
+            curr
+        });
+        let property = Property {
+            id,
+            name,
+            total_shares,
+            shares_available: total_shares,
+            metadata,
+            status: PropertyStatus::Active,
+            documents: Vec::new(),
+        };
+        props.insert(id, property.clone());
+        property
+    });
+    NAME_INDEX.with(|index| {
+        index.borrow_mut().entry(property.name.clone()).or_insert(property.id);
+    });
+    record_event(Some(property.id), EventKind::PropertyRegistered);
+    log(LogLevel::Info, "register_property", format_args!("property_id={} total_shares={}", property.id, property.total_shares));
+    Ok(property)
+}
+
+/// Admin issues unissued shares of a property to `to`, optionally into one of its subaccounts.
+#[update]
+pub fn issue_shares(property_id: PropertyId, to: Principal, amount: u64, to_subaccount: Option<Subaccount>) -> Result<String, String> {
+    if get_role(&authenticated_caller()?) != Role::Admin {
+        return Err("Only admin can issue shares".to_string());
+    }
+    if to == Principal::anonymous() {
+        return Err("Anonymous principal can't hold shares".to_string());
+    }
+    let to_subaccount = normalize_subaccount(to_subaccount)?;
+    // Only active properties can issue
+    let active = PROPERTIES.with(|props| props.borrow().get(&property_id).map(|p| p.status == PropertyStatus::Active));
+    if active == Some(false) {
+        return Err("Property is not active".to_string());
+    }
+    release_expired_reservations(property_id);
+    // Check property exists and has enough shares
+    PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        let prop = props.get_mut(&property_id).ok_or(RwaError::PropertyNotFound)?;
+        if prop.shares_available < amount {
+            return Err(RwaError::InsufficientShares { available: prop.shares_available });
+        }
+        prop.shares_available -= amount;
+        OWNERSHIP.with(|own| credit_subaccount(&mut own.borrow_mut(), property_id, to, to_subaccount, amount));
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+    assert_supply_invariant(property_id);
+    record_event(Some(property_id), EventKind::SharesIssued { to, amount });
+    log(LogLevel::Info, "issue_shares", format_args!("property_id={} to={} amount={}", property_id, to, amount));
+    Ok("Shares issued".to_string())
+}
+
+/// Admin reserves shares for a primary-market buyer until `expires_at`, taking them out of
+/// `shares_available` while off-chain payment is pending.
+#[update]
+pub fn reserve_shares(property_id: PropertyId, to: Principal, amount: u64, expires_at: u64) -> Result<u64, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can reserve shares".to_string());
+    }
+    if to == Principal::anonymous() {
+        return Err("Anonymous principal can't hold shares".to_string());
+    }
+    if amount == 0 {
+        return Err("Reservation amount must be positive".to_string());
+    }
+    if expires_at <= now() {
+        return Err("Expiry must be in the future".to_string());
+    }
+    if !property_active(property_id) {
+        return Err("Property is not active".to_string());
+    }
+    release_expired_reservations(property_id);
+    PROPERTIES.with(|props| {
+        let mut props = props.borrow_mut();
+        let prop = props.get_mut(&property_id).ok_or(RwaError::PropertyNotFound)?;
+        if prop.shares_available < amount {
+            return Err(RwaError::InsufficientShares { available: prop.shares_available });
+        }
+        prop.shares_available -= amount;
+        Ok(())
+    })
+    .map_err(|e| e.to_string())?;
+    let id = NEXT_RESERVATION_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let curr = *next;
+        *next += 1;
+        curr
+    });
+    RESERVATIONS.with(|r| {
+        r.borrow_mut().insert(id, Reservation { id, property_id, to, amount, created_at: now(), expires_at });
+    });
+    assert_supply_invariant(property_id);
+    record_event(Some(property_id), EventKind::SharesReserved { reservation_id: id, to, amount });
+    Ok(id)
+}
+
+/// Admin confirms payment for a reservation, issuing its shares to the buyer.
+/// An expired reservation is released instead. While the property isn't active or the buyer
+/// may not receive its shares, the reservation stays open and can't be confirmed.
+#[update]
+pub fn confirm_reservation(reservation_id: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can confirm reservations".to_string());
+    }
+    let reservation = RESERVATIONS.with(|r| r.borrow().get(&reservation_id).cloned()).ok_or_else(|| "Reservation not found".to_string())?;
+    if reservation.expires_at <= now() {
+        RESERVATIONS.with(|r| r.borrow_mut().remove(&reservation_id));
+        release_reservation(&reservation);
+        return Err("Reservation expired".to_string());
+    }
+    if !property_active(reservation.property_id) {
+        return Err("Property is not active".to_string());
+    }
+    if !recipient_allowed(reservation.property_id, reservation.to) {
+        return Err("Recipient is not approved for this property".to_string());
+    }
+    RESERVATIONS.with(|r| r.borrow_mut().remove(&reservation_id));
+    let Reservation { property_id, to, amount, .. } = reservation;
+    OWNERSHIP.with(|own| credit_shares(&mut own.borrow_mut(), property_id, to, amount));
+    assert_supply_invariant(property_id);
+    record_event(Some(property_id), EventKind::SharesIssued { to, amount });
+    Ok("Reservation confirmed".to_string())
+}
+
+/// Admin cancels a reservation, returning its shares to `shares_available`.
+#[update]
+pub fn cancel_reservation(reservation_id: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can cancel reservations".to_string());
+    }
+    let reservation = RESERVATIONS.with(|r| r.borrow_mut().remove(&reservation_id)).ok_or_else(|| "Reservation not found".to_string())?;
+    release_reservation(&reservation);
+    Ok("Reservation cancelled".to_string())
+}
+
+/// Query a property's open reservations, oldest first. Expired ones are listed until the
+/// next call that releases them.
+#[query]
+pub fn get_reservations(property_id: PropertyId) -> Vec<Reservation> {
+    RESERVATIONS.with(|r| r.borrow().values().filter(|res| res.property_id == property_id).cloned().collect())
+}
+
+/// Admin splits a property's shares 1:`factor`, multiplying every holder's balance,
+/// `total_shares`, `shares_available`, listing and bid amounts and open-proposal vote weights.
+/// Listing and bid prices are divided by `factor`, so each must be priced in multiples of it.
+/// Rental income is tracked in currency units and is unaffected.
+#[update]
+pub fn split_property(property_id: PropertyId, factor: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can split properties".to_string());
+    }
+    if factor < 2 {
+        return Err("Split factor must be at least 2".to_string());
+    }
+    if has_pending_transfers(property_id) {
+        return Err("Property has pending transfers".to_string());
+    }
+    if has_settling_trades(property_id) {
+        return Err("Property has trades settling".to_string());
+    }
+    if reserved_shares(property_id) > 0 {
+        return Err("Property has open reservations".to_string());
+    }
+    let overflow = || "Split would overflow share counts".to_string();
+    // Compute every new figure first so nothing is mutated if any of them overflows
+    let (total_shares, shares_available) = PROPERTIES.with(|props| {
+        let props = props.borrow();
+        let prop = props.get(&property_id).ok_or_else(|| "Property not found".to_string())?;
+        Ok::<_, String>((
+            prop.total_shares.checked_mul(factor).ok_or_else(overflow)?,
+            prop.shares_available.checked_mul(factor).ok_or_else(overflow)?,
+        ))
+    })?;
+    if exceeds_share_cap(total_shares) {
+        return Err("Split would exceed the maximum allowed shares per property".to_string());
+    }
+    let balances = OWNERSHIP.with(|own| {
+        own.borrow()
+            .iter()
+            .filter(|((pid, _, _), _)| *pid == property_id)
+            .map(|(key, shares)| shares.checked_mul(factor).map(|s| (key.clone(), s)).ok_or_else(overflow))
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    let listings = MARKETPLACE.with(|mp| {
+        mp.borrow()
+            .iter()
+            .filter(|l| l.property_id == property_id)
+            .map(|l| {
+                if l.price_per_share % factor != 0 {
+                    return Err(format!("Listing {} price is not divisible by the split factor", l.id));
+                }
+                let amount = l.amount.checked_mul(factor).ok_or_else(overflow)?;
+                Ok((l.id, amount, l.price_per_share / factor))
+            })
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    let bids = BIDS.with(|bids| {
+        bids.borrow()
+            .iter()
+            .filter(|b| b.property_id == property_id)
+            .map(|b| {
+                if b.price_per_share % factor != 0 {
+                    return Err(format!("Bid {} price is not divisible by the split factor", b.id));
+                }
+                let amount = b.amount.checked_mul(factor).ok_or_else(overflow)?;
+                Ok((b.id, amount, b.price_per_share / factor))
+            })
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    let votes = PROPOSALS.with(|props| {
+        props
+            .borrow()
+            .values()
+            .filter(|p| p.property_id == property_id && p.status == ProposalStatus::Open)
+            .map(|p| {
+                let yes = p.yes_votes.checked_mul(factor).ok_or_else(overflow)?;
+                let no = p.no_votes.checked_mul(factor).ok_or_else(overflow)?;
+                Ok((p.id, yes, no))
+            })
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    // Apply
+    PROPERTIES.with(|props| {
+        if let Some(prop) = props.borrow_mut().get_mut(&property_id) {
+            prop.total_shares = total_shares;
+            prop.shares_available = shares_available;
+        }
+    });
+    OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        for (key, shares) in balances {
+            own.insert(key, shares);
+        }
+    });
+    // Locks never exceed balances, so scaling them can't overflow
+    LOCKED.with(|locked| {
+        for ((pid, _, _), shares) in locked.borrow_mut().iter_mut() {
+            if *pid == property_id {
+                *shares *= factor;
+            }
+        }
+    });
+    MARKETPLACE.with(|mp| {
+        let mut mp = mp.borrow_mut();
+        for (id, amount, price_per_share) in listings {
+            if let Some(listing) = mp.iter_mut().find(|l| l.id == id) {
+                listing.amount = amount;
+                listing.price_per_share = price_per_share;
+            }
+        }
+    });
+    BIDS.with(|b| {
+        let mut b = b.borrow_mut();
+        for (id, amount, price_per_share) in bids {
+            if let Some(bid) = b.iter_mut().find(|b| b.id == id) {
+                bid.amount = amount;
+                bid.price_per_share = price_per_share;
+            }
+        }
+    });
+    PROPOSALS.with(|props| {
+        let mut props = props.borrow_mut();
+        for (id, yes, no) in votes {
+            if let Some(proposal) = props.get_mut(&id) {
+                proposal.yes_votes = yes;
+                proposal.no_votes = no;
+            }
+        }
+    });
+    assert_supply_invariant(property_id);
+    record_event(Some(property_id), EventKind::PropertySplit { factor });
+    Ok("Property split".to_string())
+}
+
+/// Admin consolidates a property's shares `factor`:1, dividing every holder's balance,
+/// `total_shares`, listing and bid amounts and open-proposal vote weights by `factor`.
+///
+/// Remainder policy: each subaccount's leftover shares (`balance % factor`) return to the
+/// unissued pool and are cashed out into that subaccount's unclaimed income at `cash_out_price` per
+/// pre-consolidation share (pass 0 to return them without compensation). The cash-out is
+/// recorded as deposited rental income. Listing and bid amounts round down and their prices
+/// are multiplied by `factor`; listings rounding to zero are removed and such bids cancelled.
+/// `total_shares` must be divisible by `factor`. Once multi-sig is on, consolidations with a
+/// cash-out go through `propose_operation` instead.
+#[update]
+pub fn reverse_split_property(property_id: PropertyId, factor: u64, cash_out_price: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can consolidate properties".to_string());
+    }
+    if cash_out_price > 0 && multi_sig_required() {
+        return Err("Consolidations with a cash-out require multi-sig approval; use propose_operation".to_string());
+    }
+    apply_reverse_split(property_id, factor, cash_out_price)
+}
+
+fn apply_reverse_split(property_id: PropertyId, factor: u64, cash_out_price: u64) -> Result<String, String> {
+    if factor < 2 {
+        return Err("Consolidation factor must be at least 2".to_string());
+    }
+    if has_pending_transfers(property_id) {
+        return Err("Property has pending transfers".to_string());
+    }
+    if has_settling_trades(property_id) {
+        return Err("Property has trades settling".to_string());
+    }
+    if reserved_shares(property_id) > 0 {
+        return Err("Property has open reservations".to_string());
+    }
+    let total_shares = PROPERTIES.with(|props| {
+        props.borrow().get(&property_id).map(|p| p.total_shares).ok_or_else(|| "Property not found".to_string())
+    })?;
+    if total_shares % factor != 0 {
+        return Err("Total shares are not divisible by the consolidation factor".to_string());
+    }
+    let overflow = || "Consolidation would overflow".to_string();
+    // Compute new balances and cash-outs first so nothing is mutated on error
+    let mut balances = Vec::new();
+    let mut cash_outs = Vec::new();
+    let mut total_cash_out: u64 = 0;
+    let mut new_issued: u64 = 0;
+    OWNERSHIP.with(|own| {
+        for (key, shares) in own.borrow().iter() {
+            if key.0 != property_id {
+                continue;
+            }
+            let consolidated = shares / factor;
+            let remainder = shares % factor;
+            balances.push((key.clone(), consolidated));
+            new_issued += consolidated;
+            if remainder > 0 && cash_out_price > 0 {
+                let payout = remainder.checked_mul(cash_out_price).ok_or_else(overflow)?;
+                total_cash_out = total_cash_out.checked_add(payout).ok_or_else(overflow)?;
+                cash_outs.push((key.clone(), payout));
+            }
+        }
+        Ok::<_, String>(())
+    })?;
+    let listings = MARKETPLACE.with(|mp| {
+        mp.borrow()
+            .iter()
+            .filter(|l| l.property_id == property_id)
+            .map(|l| {
+                let price = l.price_per_share.checked_mul(factor).ok_or_else(overflow)?;
+                Ok((l.id, l.amount / factor, price))
+            })
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    let bids = BIDS.with(|bids| {
+        bids.borrow()
+            .iter()
+            .filter(|b| b.property_id == property_id)
+            .map(|b| {
+                let price = b.price_per_share.checked_mul(factor).ok_or_else(overflow)?;
+                Ok((b.id, b.amount / factor, price))
+            })
+            .collect::<Result<Vec<_>, String>>()
+    })?;
+    // Apply
+    let new_total = total_shares / factor;
+    PROPERTIES.with(|props| {
+        if let Some(prop) = props.borrow_mut().get_mut(&property_id) {
+            prop.total_shares = new_total;
+            prop.shares_available = new_total - new_issued;
+        }
+    });
+    OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        for (key, shares) in balances {
+            if shares == 0 {
+                own.remove(&key);
+            } else {
+                own.insert(key, shares);
+            }
+        }
+    });
+    LOCKED.with(|locked| {
+        let mut locked = locked.borrow_mut();
+        for ((pid, _, _), shares) in locked.iter_mut() {
+            if *pid == property_id {
+                *shares /= factor;
+            }
+        }
+        locked.retain(|_, shares| *shares > 0);
+    });
+    if total_cash_out > 0 {
+        RENTAL_INCOME.with(|ri| {
+            add_saturating(ri.borrow_mut().entry(property_id).or_insert(0), total_cash_out);
+        });
+        UNCLAIMED_INCOME.with(|ui| {
+            let mut ui = ui.borrow_mut();
+            for ((_, user, sub), payout) in cash_outs {
+                credit_income(&mut ui, property_id, user, sub, payout);
+            }
+        });
+    }
+    MARKETPLACE.with(|mp| {
+        let mut mp = mp.borrow_mut();
+        for (id, amount, price_per_share) in listings {
+            if let Some(listing) = mp.iter_mut().find(|l| l.id == id) {
+                listing.amount = amount;
+                listing.price_per_share = price_per_share;
+            }
+        }
+        mp.retain(|l| l.property_id != property_id || l.amount > 0);
+    });
+    let cancelled_bids: Vec<Bid> = BIDS.with(|b| {
+        let mut b = b.borrow_mut();
+        for (id, amount, price_per_share) in bids {
+            if let Some(bid) = b.iter_mut().find(|b| b.id == id) {
+                bid.amount = amount;
+                bid.price_per_share = price_per_share;
+            }
+        }
+        let (cancelled, kept) = b.drain(..).partition(|bid| bid.property_id == property_id && bid.amount == 0);
+        *b = kept;
+        cancelled
+    });
+    PROPOSALS.with(|props| {
+        for proposal in props.borrow_mut().values_mut() {
+            if proposal.property_id == property_id && proposal.status == ProposalStatus::Open {
+                proposal.yes_votes /= factor;
+                proposal.no_votes /= factor;
+            }
+        }
+    });
+    assert_supply_invariant(property_id);
+    record_event(Some(property_id), EventKind::PropertyConsolidated { factor });
+    for bid in cancelled_bids {
+        record_event(Some(property_id), EventKind::BidCancelled { bid_id: bid.id, buyer: bid.buyer });
+    }
+    Ok("Property consolidated".to_string())
+}
+
+#[query]
+pub fn get_property(property_id: PropertyId) -> Option<Property> {
+    PROPERTIES.with(|props| props.borrow().get(&property_id).cloned())
+}
+
+/// Query all configurable parameters that apply to a property in one call.
+#[query]
+pub fn get_property_config(property_id: PropertyId) -> Option<PropertyConfig> {
+    let status = PROPERTIES.with(|props| props.borrow().get(&property_id).map(|p| p.status.clone()))?;
+    Some(PropertyConfig {
+        status,
+        trading_window: get_trading_window(property_id),
+        transfer_restricted: is_transfer_restricted(property_id),
+        min_deposit: get_min_deposit(property_id),
+        platform_fee_bps: get_platform_fee_bps(),
+        max_listings_per_seller: get_max_listings_per_seller(),
+        max_price_per_share: get_max_price_per_share(),
+        max_total_shares: get_max_total_shares(),
+    })
+}
+
+/// Query all platform-wide configurable parameters in one call.
+#[query]
+pub fn get_global_config() -> GlobalConfig {
+    GlobalConfig {
+        settlement_ledger: get_settlement_ledger(),
+        platform_fee_bps: get_platform_fee_bps(),
+        fee_rebate_tiers: get_fee_rebate_tiers(),
+        registration_policy: get_registration_policy(),
+        require_unique_names: get_require_unique_names(),
+        max_listings_per_seller: get_max_listings_per_seller(),
+        max_price_per_share: get_max_price_per_share(),
+        max_total_shares: get_max_total_shares(),
+        rate_limit: get_rate_limit(),
+        log_level: get_log_level(),
+        required_approvals: get_required_approvals(),
+    }
+}
+
+/// Query a page of property summaries ordered by id.
+#[query]
+pub fn get_property_summaries(offset: u64, limit: u64) -> Vec<PropertySummary> {
+    PROPERTIES.with(|props| {
+        let props = props.borrow();
+        let mut ids: Vec<&PropertyId> = props.keys().collect();
+        ids.sort();
+        ids.into_iter()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .map(|id| {
+                let p = &props[id];
+                PropertySummary {
+                    id: p.id,
+                    name: p.name.clone(),
+                    shares_available: p.shares_available,
+                    total_shares: p.total_shares,
+                    valuation: p.metadata.valuation,
+                }
+            })
+            .collect()
+    })
+}
+
+/// Query several properties in one call. Results line up with `ids`, with `None` for unknown ids.
+#[query]
+pub fn get_properties(ids: Vec<PropertyId>) -> Vec<Option<Property>> {
+    PROPERTIES.with(|props| {
+        let props = props.borrow();
+        ids.iter().map(|id| props.get(id).cloned()).collect()
+    })
+}
+
+/// Query all properties ordered by id. The flag is set when the result was cut short to fit the response limit.
+#[query]
+pub fn get_all_properties() -> (Vec<Property>, bool) {
+    PROPERTIES.with(|props| {
+        let props = props.borrow();
+        let mut all: Vec<&Property> = props.values().collect();
+        all.sort_by_key(|p| p.id);
+        cap_response(all.into_iter().cloned())
+    })
+}
+
+/// Query a property together with its holder count, shares listed and latest trade price.
+#[query]
+pub fn get_property_detail(property_id: PropertyId) -> Option<PropertyDetail> {
+    let property = get_property(property_id)?;
+    let shares_listed = MARKETPLACE.with(|mp| {
+        mp.borrow().iter().filter(|l| l.property_id == property_id).map(|l| l.amount).sum()
+    });
+    let latest_price = TRADES.with(|trades| {
+        trades.borrow().get(&property_id).and_then(|t| t.last()).map(|t| t.price_per_share)
+    });
+    Some(PropertyDetail {
+        property,
+        holder_count: get_holder_count(property_id),
+        shares_listed,
+        latest_price,
+    })
+}
+
+/// Look up a property by exact name. When several properties share a name, the one
+/// registered first is returned.
+#[query]
+pub fn get_property_by_name(name: String) -> Option<Property> {
+    let id = NAME_INDEX.with(|index| index.borrow().get(&name).cloned())?;
+    get_property(id)
+}
+
+/// Query active properties that still have shares available for primary issuance, ordered by id.
+#[query]
+pub fn get_primary_market(offset: u64, limit: u64) -> Vec<Property> {
+    PROPERTIES.with(|props| {
+        let props = props.borrow();
+        let mut available: Vec<&Property> =
+            props.values().filter(|p| p.status == PropertyStatus::Active && p.shares_available > 0).collect();
+        available.sort_by_key(|p| p.id);
+        available.into_iter().skip(offset as usize).take(limit as usize).cloned().collect()
+    })
+}
+
+/// Query how much of a property has been issued, as `(issued, total, bps_sold)`.
+#[query]
+pub fn get_issuance_progress(property_id: PropertyId) -> Result<(u64, u64, u64), String> {
+    let prop = PROPERTIES.with(|props| props.borrow().get(&property_id).cloned()).ok_or_else(|| "Property not found".to_string())?;
+    let issued = issued_shares(&prop);
+    let bps_sold = if prop.total_shares == 0 {
+        0
+    } else {
+        (issued as u128 * 10_000 / prop.total_shares as u128) as u64
+    };
+    Ok((issued, prop.total_shares, bps_sold))
+}
+
+/// Render a raw price using the property's decimals, e.g. 12345 with 2 decimals is "123.45".
+/// The fractional part is always zero-padded to the full number of decimals.
+#[query]
+pub fn format_price(property_id: PropertyId, raw_price: u64) -> Result<String, String> {
+    let decimals = PROPERTIES.with(|props| props.borrow().get(&property_id).map(|p| p.metadata.decimals as usize));
+    let decimals = decimals.ok_or_else(|| "Property not found".to_string())?;
+    if decimals == 0 {
+        return Ok(raw_price.to_string());
+    }
+    let digits = format!("{:0>width$}", raw_price, width = decimals + 1);
+    let (whole, frac) = digits.split_at(digits.len() - decimals);
+    Ok(format!("{}.{}", whole, frac))
+}
+
+/// Query the id the next registered property will receive, without consuming it.
+#[query]
+pub fn peek_next_property_id() -> PropertyId {
+    NEXT_PROPERTY_ID.with(|id| *id.borrow())
+}
+
+/// Query the shares a user holds of a property, across all their subaccounts.
+#[query]
+pub fn get_ownership(property_id: PropertyId, user: Principal) -> u64 {
+    OWNERSHIP.with(|own| holding(&own.borrow(), property_id, user))
+}
+
+/// Query the number of users holding shares of a property.
+#[query]
+pub fn get_holder_count(property_id: PropertyId) -> u64 {
+    OWNERSHIP.with(|own| holdings(&own.borrow(), property_id).len() as u64)
+}
+
+/// Query every property a user currently holds, has listed or has unclaimed income in, by id.
+#[query]
+pub fn get_account_properties(user: Principal) -> Vec<PropertyId> {
+    let mut ids: BTreeSet<PropertyId> = OWNERSHIP.with(|own| {
+        own.borrow().keys().filter(|(_, holder, _)| *holder == user).map(|(pid, _, _)| *pid).collect()
+    });
+    MARKETPLACE.with(|mp| ids.extend(mp.borrow().iter().filter(|l| l.seller == user).map(|l| l.property_id)));
+    UNCLAIMED_INCOME.with(|ui| {
+        ids.extend(ui.borrow().iter().filter(|((_, holder, _), amount)| *holder == user && **amount > 0).map(|((pid, _, _), _)| *pid))
+    });
+    ids.into_iter().collect()
+}
+
+/// Query the average holding of a property: issued shares divided by holder count, rounded
+/// down. 0 when nobody holds any shares or the property doesn't exist.
+#[query]
+pub fn get_average_holding(property_id: PropertyId) -> u64 {
+    let holders = get_holder_count(property_id);
+    if holders == 0 {
+        return 0;
+    }
+    let issued = PROPERTIES.with(|props| props.borrow().get(&property_id).map(issued_shares).unwrap_or(0));
+    issued / holders
+}
+
+/// Query an estimate of what `deposit_rental_income` would cost for a property, so admins
+/// can tell ahead of time whether a distribution fits in one message.
+#[query]
+pub fn estimate_distribution_cost(property_id: PropertyId) -> DistributionEstimate {
+    let holder_count = get_holder_count(property_id);
+    let (entries, credits) = OWNERSHIP.with(|own| {
+        let own = own.borrow();
+        (own.len() as u64, own.keys().filter(|(pid, _, _)| *pid == property_id).count() as u64)
+    });
+    let estimated_instructions = DISTRIBUTION_BASE_INSTRUCTIONS
+        .saturating_add(entries.saturating_mul(DISTRIBUTION_SCAN_INSTRUCTIONS))
+        .saturating_add(credits.saturating_mul(DISTRIBUTION_CREDIT_INSTRUCTIONS));
+    DistributionEstimate {
+        holder_count,
+        estimated_instructions,
+        within_limit: estimated_instructions < MAX_UPDATE_INSTRUCTIONS,
+    }
+}
+
+/// Query every holder of a property and their balance. The flag is set when the result was
+/// cut short to fit the response limit.
+#[query]
+pub fn get_property_owners(property_id: PropertyId) -> (Vec<(Principal, u64)>, bool) {
+    OWNERSHIP.with(|own| cap_response(holdings(&own.borrow(), property_id).into_iter()))
+}
+
+/// Query the `n` largest holders of a property, largest first.
+#[query]
+pub fn get_top_holders(property_id: PropertyId, n: u64) -> Vec<(Principal, u64)> {
+    let mut holders: Vec<(Principal, u64)> = OWNERSHIP.with(|own| holdings(&own.borrow(), property_id).into_iter().collect());
+    let n = (n as usize).min(holders.len());
+    if n == 0 {
+        return Vec::new();
+    }
+    let by_balance = |a: &(Principal, u64), b: &(Principal, u64)| b.1.cmp(&a.1).then(a.0.cmp(&b.0));
+    // Partition so the top n come first, then only sort those
+    holders.select_nth_unstable_by(n - 1, by_balance);
+    holders.truncate(n);
+    holders.sort_unstable_by(by_balance);
+    holders
+}
+
+/// Admin records the current cap table of a property. Returns the snapshot id.
+#[update]
+pub fn take_snapshot(property_id: PropertyId) -> Result<u64, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can take snapshots".to_string());
+    }
+    let total_shares = PROPERTIES.with(|props| props.borrow().get(&property_id).map(|p| p.total_shares));
+    let total_shares = total_shares.ok_or_else(|| "Property not found".to_string())?;
+    let balances: Vec<(Principal, u64)> = OWNERSHIP.with(|own| holdings(&own.borrow(), property_id).into_iter().collect());
+    let id = NEXT_SNAPSHOT_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let curr = *next;
+        *next += 1;
+        curr
+    });
+    SNAPSHOTS.with(|snaps| {
+        snaps.borrow_mut().insert(
+            id,
+            Snapshot {
+                id,
+                property_id,
+                taken_at: now(),
+                total_shares,
+                balances,
+            },
+        );
+    });
+    record_event(Some(property_id), EventKind::SnapshotTaken { snapshot_id: id });
+    Ok(id)
+}
+
+#[query]
+pub fn get_snapshot(snapshot_id: u64) -> Option<Snapshot> {
+    SNAPSHOTS.with(|snaps| snaps.borrow().get(&snapshot_id).cloned())
+}
+
+/// Query a user's ownership of a property as of a snapshot, in basis points of total shares.
+#[query]
+pub fn get_snapshot_percentage(property_id: PropertyId, snapshot_id: u64, user: Principal) -> Result<u64, String> {
+    SNAPSHOTS.with(|snaps| {
+        let snaps = snaps.borrow();
+        let snap = snaps
+            .get(&snapshot_id)
+            .filter(|s| s.property_id == property_id)
+            .ok_or_else(|| "Snapshot not found for property".to_string())?;
+        if snap.total_shares == 0 {
+            return Ok(0);
+        }
+        let shares = snap.balances.iter().find(|(owner, _)| *owner == user).map(|(_, s)| *s).unwrap_or(0);
+        Ok((shares as u128 * 10_000 / snap.total_shares as u128) as u64)
+    })
+}
+
+/// Admin deposits rental income for a property. Distributes to all current owners proportionally.
+/// Only active properties accept deposits. Once multi-sig is on, deposits go through
+/// `propose_operation` instead.
+#[update]
+pub fn deposit_rental_income(property_id: PropertyId, amount: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can deposit rental income".to_string());
+    }
+    if multi_sig_required() {
+        return Err("Deposits require multi-sig approval; use propose_operation".to_string());
+    }
+    apply_rental_deposit(property_id, amount)
+}
+
+fn apply_rental_deposit(property_id: PropertyId, amount: u64) -> Result<String, String> {
+    // Distribute to owners
+    let mut total_shares = 0;
+    let mut issued = 0;
+    let mut status = None;
+    PROPERTIES.with(|props| {
+        if let Some(prop) = props.borrow().get(&property_id) {
+            total_shares = prop.total_shares;
+            issued = issued_shares(prop);
+            status = Some(prop.status.clone());
+        }
+    });
+    if status.is_some_and(|s| s != PropertyStatus::Active) {
+        return Err("Property is not active".to_string());
+    }
+    if total_shares == 0 {
+        return Err("Property not found or has no shares".to_string());
+    }
+    if let Some(min) = get_min_deposit(property_id) {
+        if amount < min {
+            return Err(format!("Deposit must be at least {}", min));
+        }
+    }
+    // Track total income
+    RENTAL_INCOME.with(|ri| {
+        let mut ri = ri.borrow_mut();
+        add_saturating(ri.entry(property_id).or_insert(0), amount);
+    });
+    // Split the holders' pool between all owners, carrying what rounding leaves to the next deposit
+    let (allocations, dust) = compute_distribution(property_id, amount, issued, total_shares);
+    UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        for (user, sub, user_income) in &allocations {
+            credit_income(&mut ui, property_id, *user, sub.clone(), *user_income);
+        }
+    });
+    let allocations = allocations_by_holder(&allocations);
+    DEPOSITS.with(|d| {
+        d.borrow_mut().entry(property_id).or_default().push(IncomeDeposit { timestamp: now(), amount, allocations });
+    });
+    INCOME_DUST.with(|d| d.borrow_mut().insert(property_id, dust));
+    record_event(Some(property_id), EventKind::IncomeDeposited { amount });
+    log(LogLevel::Info, "deposit_rental_income", format_args!("property_id={} amount={}", property_id, amount));
+    Ok("Rental income distributed".to_string())
+}
+
+/// Query what a user would receive if `deposit_amount` were deposited for a property now.
+/// Nothing is changed; a missing property or holder yields 0.
+#[query]
+pub fn simulate_income_share(property_id: PropertyId, user: Principal, deposit_amount: u64) -> u64 {
+    let (total_shares, issued) = PROPERTIES.with(|props| {
+        props.borrow().get(&property_id).map(|p| (p.total_shares, issued_shares(p))).unwrap_or((0, 0))
+    });
+    if issued == 0 {
+        return 0;
+    }
+    // Each subaccount's cut is rounded separately, as a deposit would
+    let pool = holder_pool(property_id, deposit_amount, issued, total_shares);
+    OWNERSHIP.with(|own| {
+        own.borrow()
+            .iter()
+            .filter(|((pid, owner, _), _)| *pid == property_id && *owner == user)
+            .map(|(_, shares)| income_share(pool, *shares, issued))
+            .sum()
+    })
+}
+
+/// Query exactly what each holder would be credited if `amount` were deposited now, ordered
+/// by principal. Nothing is changed.
+#[query]
+pub fn preview_distribution(property_id: PropertyId, amount: u64) -> Result<Vec<(Principal, u64)>, String> {
+    let (total_shares, issued) = PROPERTIES.with(|props| {
+        props.borrow().get(&property_id).map(|p| (p.total_shares, issued_shares(p)))
+    })
+    .ok_or_else(|| "Property not found".to_string())?;
+    if total_shares == 0 {
+        return Err("Property has no shares".to_string());
+    }
+    Ok(allocations_by_holder(&compute_distribution(property_id, amount, issued, total_shares).0))
+}
+
+/// Admin sets the smallest rental deposit accepted for a property, or `None` for no floor.
+/// A floor of `total_shares` guarantees every share at least one unit per deposit.
+#[update]
+pub fn set_min_deposit(property_id: PropertyId, min: Option<u64>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set the minimum deposit".to_string());
+    }
+    if PROPERTIES.with(|props| !props.borrow().contains_key(&property_id)) {
+        return Err("Property not found".to_string());
+    }
+    MIN_DEPOSITS.with(|m| match min {
+        Some(min) => m.borrow_mut().insert(property_id, min),
+        None => m.borrow_mut().remove(&property_id),
+    });
+    record_event(Some(property_id), EventKind::ConfigChanged { setting: "min_deposit".to_string() });
+    Ok("Minimum deposit updated".to_string())
+}
+
+/// Query the smallest rental deposit accepted for a property, if it has a floor.
+#[query]
+pub fn get_min_deposit(property_id: PropertyId) -> Option<u64> {
+    MIN_DEPOSITS.with(|m| m.borrow().get(&property_id).cloned())
+}
+
+/// Admin distributes rental income to holders as of a snapshot rather than current owners,
+/// so income for a period goes to whoever held the shares during it. Snapshots record holders
+/// rather than their subaccounts, so the income goes to each holder's default subaccount. Once
+/// multi-sig is on, distributions go through `propose_operation` instead.
+#[update]
+pub fn distribute_to_snapshot(property_id: PropertyId, snapshot_id: u64, amount: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can distribute income".to_string());
+    }
+    if multi_sig_required() {
+        return Err("Deposits require multi-sig approval; use propose_operation".to_string());
+    }
+    apply_snapshot_distribution(property_id, snapshot_id, amount)
+}
+
+fn apply_snapshot_distribution(property_id: PropertyId, snapshot_id: u64, amount: u64) -> Result<String, String> {
+    let status = PROPERTIES.with(|props| props.borrow().get(&property_id).map(|p| p.status.clone()));
+    match status {
+        None => return Err("Property not found".to_string()),
+        Some(PropertyStatus::Active) => {}
+        Some(_) => return Err("Property is not active".to_string()),
+    }
+    let snapshot = SNAPSHOTS.with(|snaps| snaps.borrow().get(&snapshot_id).cloned());
+    let snapshot = match snapshot {
+        Some(snap) if snap.property_id == property_id => snap,
+        _ => return Err("Snapshot not found for property".to_string()),
+    };
+    if snapshot.total_shares == 0 {
+        return Err("Snapshot has no shares".to_string());
+    }
+    // Track total income
+    RENTAL_INCOME.with(|ri| {
+        add_saturating(ri.borrow_mut().entry(property_id).or_insert(0), amount);
+    });
+    let mut allocations: Vec<(Principal, u64)> = snapshot
+        .balances
+        .iter()
+        .map(|(user, shares)| (*user, income_share(amount, *shares, snapshot.total_shares)))
+        .filter(|(_, user_income)| *user_income > 0)
+        .collect();
+    allocations.sort_by_key(|(user, _)| *user);
+    UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        for (user, user_income) in &allocations {
+            credit_income(&mut ui, property_id, *user, default_subaccount(), *user_income);
+        }
+    });
+    DEPOSITS.with(|d| {
+        d.borrow_mut().entry(property_id).or_default().push(IncomeDeposit { timestamp: now(), amount, allocations });
+    });
+    record_event(Some(property_id), EventKind::SnapshotIncomeDistributed { snapshot_id, amount });
+    Ok("Rental income distributed to snapshot".to_string())
+}
+
+/// Admin deposits rental income for several properties in one call. Each deposit is applied
+/// independently, so an invalid property doesn't fail the rest of the batch; non-admins get
+/// every deposit refused.
+#[update]
+pub fn deposit_rental_income_batch(deposits: Vec<(PropertyId, u64)>) -> Vec<(PropertyId, Result<String, String>)> {
+    deposits
+        .into_iter()
+        .map(|(property_id, amount)| (property_id, deposit_rental_income(property_id, amount)))
+        .collect()
+}
+
+/// Query the annualized rental yield of a property in basis points of its valuation.
+/// Deposits from the last 90 days are extrapolated to a 365-day year.
+#[query]
+pub fn get_annualized_yield_bps(property_id: PropertyId) -> u64 {
+    let valuation = PROPERTIES.with(|props| props.borrow().get(&property_id).map(|p| p.metadata.valuation).unwrap_or(0));
+    if valuation == 0 {
+        return 0;
+    }
+    let since = now().saturating_sub(YIELD_LOOKBACK_NANOS);
+    let recent: u128 = DEPOSITS.with(|d| {
+        d.borrow()
+            .get(&property_id)
+            .map(|deps| deps.iter().filter(|dep| dep.timestamp >= since).map(|dep| dep.amount as u128).sum())
+            .unwrap_or(0)
+    });
+    let annual = recent * (365 * NANOS_PER_DAY) as u128 / YIELD_LOOKBACK_NANOS as u128;
+    (annual * 10_000 / valuation as u128).min(u64::MAX as u128) as u64
+}
+
+/// Query a page of properties ordered by `sort_by`. Ties are broken by ascending id.
+#[query]
+pub fn get_properties_sorted(sort_by: SortKey, descending: bool, offset: u64, limit: u64) -> Vec<Property> {
+    let mut props: Vec<Property> = PROPERTIES.with(|props| props.borrow().values().cloned().collect());
+    let yields: HashMap<PropertyId, u64> = if sort_by == SortKey::Yield {
+        props.iter().map(|p| (p.id, get_annualized_yield_bps(p.id))).collect()
+    } else {
+        HashMap::new()
+    };
+    props.sort_by(|a, b| {
+        let order = match sort_by {
+            SortKey::Yield => yields[&a.id].cmp(&yields[&b.id]),
+            SortKey::Valuation => a.metadata.valuation.cmp(&b.metadata.valuation),
+            SortKey::AvailableShares => a.shares_available.cmp(&b.shares_available),
+            SortKey::Name => a.name.cmp(&b.name),
+        };
+        let order = if descending { order.reverse() } else { order };
+        order.then(a.id.cmp(&b.id))
+    });
+    props.into_iter().skip(offset as usize).take(limit as usize).collect()
+}
+
+/// User claims their unclaimed rental income for a property. Only `user` themselves can claim.
+#[update]
+pub fn claim_income(property_id: PropertyId, user: Principal) -> Result<u64, String> {
+    if authenticated_caller()? != user {
+        return Err("Only the user can claim their income".to_string());
+    }
+    Ok(claim_all_income(user, property_id, user))
+}
+
+fn claim_all_income(actor: Principal, property_id: PropertyId, user: Principal) -> u64 {
+    let mut claimed = 0;
+    UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        claimed = total_income(&ui, property_id, user);
+        ui.retain(|(pid, owner, _), _| *pid != property_id || *owner != user);
+    });
+    CLAIMED_INCOME.with(|ci| {
+        add_saturating(ci.borrow_mut().entry(property_id).or_insert(0), claimed);
+    });
+    if claimed > 0 {
+        record_event_by(actor, Some(property_id), EventKind::IncomeClaimed { user, amount: claimed });
+    }
+    claimed
+}
+
+/// Claim a user's income for a property into their seller proceeds if they opted into
+/// auto-claim, so moving their shares leaves nothing stranded.
+fn auto_claim(actor: Principal, property_id: PropertyId, user: Principal) {
+    if get_auto_claim(user) {
+        let claimed = claim_all_income(actor, property_id, user);
+        credit_proceeds(property_id, user, claimed);
+    }
+}
+
+/// Caller opts in or out of having their income from a property claimed into their seller
+/// proceeds whenever they transfer or sell shares of it.
+#[update]
+pub fn set_auto_claim(enabled: bool) -> Result<String, String> {
+    let user = authenticated_caller()?;
+    AUTO_CLAIM.with(|a| {
+        let mut a = a.borrow_mut();
+        if enabled {
+            a.insert(user);
+        } else {
+            a.remove(&user);
+        }
+    });
+    Ok("Auto-claim updated".to_string())
+}
+
+#[query]
+pub fn get_auto_claim(user: Principal) -> bool {
+    AUTO_CLAIM.with(|a| a.borrow().contains(&user))
+}
+
+/// Caller claims part of their unclaimed income for a property, taking it from the default
+/// subaccount before the others. Returns what is left unclaimed.
+#[update]
+pub fn claim_income_partial(property_id: PropertyId, amount: u64) -> Result<u64, String> {
+    let user = authenticated_caller()?;
+    if amount == 0 {
+        return Err("Claim amount must be positive".to_string());
+    }
+    let remaining = UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        let balance = total_income(&ui, property_id, user);
+        if amount > balance {
+            return Err(format!("Only {} income available to claim", balance));
+        }
+        debit_income(&mut ui, property_id, user, amount);
+        Ok(balance - amount)
+    })?;
+    CLAIMED_INCOME.with(|ci| {
+        add_saturating(ci.borrow_mut().entry(property_id).or_insert(0), amount);
+    });
+    record_event(Some(property_id), EventKind::IncomeClaimed { user, amount });
+    Ok(remaining)
+}
+
+/// Claim the income one of the caller's subaccounts earned from a property (`None` is the
+/// default one) and pay it out to that subaccount on the settlement ledger. If the transfer
+/// fails the claim is rolled back.
+#[update]
+pub async fn claim_and_withdraw(property_id: PropertyId, subaccount: Option<Subaccount>) -> Result<WithdrawReceipt, String> {
+    let user = authenticated_caller()?;
+    let subaccount = normalize_subaccount(subaccount)?;
+    if SETTLEMENT_LEDGER.with(|l| l.borrow().is_none()) {
+        return Err("No settlement ledger configured".to_string());
+    }
+    let key = (property_id, user, subaccount);
+    let amount = UNCLAIMED_INCOME.with(|ui| ui.borrow_mut().remove(&key).unwrap_or(0));
+    if amount == 0 {
+        return Err("No income to withdraw".to_string());
+    }
+    log(LogLevel::Debug, "claim_and_withdraw", format_args!("property_id={} user={} amount={}", property_id, user, amount));
+    let to_subaccount = Some(key.2.clone()).filter(|sub| *sub != default_subaccount());
+    match payout(user, to_subaccount, amount).await {
+        Ok(Some(block_index)) => {
+            CLAIMED_INCOME.with(|ci| {
+                add_saturating(ci.borrow_mut().entry(property_id).or_insert(0), amount);
+            });
+            record_event_by(user, Some(property_id), EventKind::IncomeWithdrawn { user, amount, block_index });
+            Ok(WithdrawReceipt { amount, block_index })
+        }
+        result => {
+            UNCLAIMED_INCOME.with(|ui| *ui.borrow_mut().entry(key).or_insert(0) += amount);
+            let err = result.err().unwrap_or_else(|| "No settlement ledger configured".to_string());
+            log(LogLevel::Error, "claim_and_withdraw", format_args!("property_id={} user={} error={}", property_id, user, err));
+            Err(err)
+        }
+    }
+}
+
+/// Admin corrects a user's unclaimed income by a signed amount, e.g. after a distribution
+/// error. Credits go to the default subaccount and debits come out of it first; the total can't
+/// go negative, and the reason is kept in the audit log. Once multi-sig is on, adjustments go
+/// through `propose_operation` instead.
+#[update]
+pub fn adjust_unclaimed_income(property_id: PropertyId, user: Principal, delta: i64, reason: String) -> Result<u64, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can adjust unclaimed income".to_string());
+    }
+    if multi_sig_required() {
+        return Err("Income adjustments require multi-sig approval; use propose_operation".to_string());
+    }
+    apply_income_adjustment(property_id, user, delta, reason)
+}
+
+fn apply_income_adjustment(property_id: PropertyId, user: Principal, delta: i64, reason: String) -> Result<u64, String> {
+    if delta == 0 {
+        return Err("Adjustment must be non-zero".to_string());
+    }
+    if reason.trim().is_empty() {
+        return Err("Adjustment needs a reason".to_string());
+    }
+    if PROPERTIES.with(|props| !props.borrow().contains_key(&property_id)) {
+        return Err("Property not found".to_string());
+    }
+    let balance = UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        let current = total_income(&ui, property_id, user);
+        if delta > 0 {
+            let balance = current.checked_add(delta as u64).ok_or_else(|| "Adjustment overflows unclaimed income".to_string())?;
+            credit_income(&mut ui, property_id, user, default_subaccount(), delta as u64);
+            Ok::<_, String>(balance)
+        } else {
+            let balance = current
+                .checked_sub(delta.unsigned_abs())
+                .ok_or_else(|| format!("Adjustment exceeds unclaimed income of {}", current))?;
+            debit_income(&mut ui, property_id, user, delta.unsigned_abs());
+            Ok(balance)
+        }
+    })?;
+    record_event(Some(property_id), EventKind::IncomeAdjusted { user, delta, reason });
+    Ok(balance)
+}
+
+/// Admin removes zero-valued unclaimed income entries. Returns how many were removed.
+#[update]
+pub fn compact_unclaimed_income() -> Result<u64, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can compact unclaimed income".to_string());
+    }
+    Ok(UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        let before = ui.len();
+        ui.retain(|_, amount| *amount > 0);
+        (before - ui.len()) as u64
+    }))
+}
+
+/// Query unclaimed rental income for a user and property, across all their subaccounts.
+#[query]
+pub fn get_unclaimed_income(property_id: PropertyId, user: Principal) -> u64 {
+    UNCLAIMED_INCOME.with(|ui| total_income(&ui.borrow(), property_id, user))
+}
+
+/// Query unclaimed income for many `(property, user)` pairs at once, in the order given.
+#[query]
+pub fn get_unclaimed_income_many(queries: Vec<(PropertyId, Principal)>) -> Vec<u64> {
+    UNCLAIMED_INCOME.with(|ui| {
+        let ui = ui.borrow();
+        queries.iter().map(|(pid, user)| total_income(&ui, *pid, *user)).collect()
+    })
+}
+
+/// Query the properties where the caller has income waiting to be claimed, by property id.
+#[query]
+pub fn get_my_claimable_properties() -> Vec<(PropertyId, u64)> {
+    let user = caller();
+    let mut result: BTreeMap<PropertyId, u64> = BTreeMap::new();
+    UNCLAIMED_INCOME.with(|ui| {
+        for ((pid, holder, _), amount) in ui.borrow().iter() {
+            if *holder == user && *amount > 0 {
+                *result.entry(*pid).or_insert(0) += *amount;
+            }
+        }
+    });
+    result.into_iter().collect()
+}
+
+/// Query income from deposits that rounding has left unallocated so far. It is added to the
+/// next deposit's distribution.
+#[query]
+pub fn get_undistributed_dust(property_id: PropertyId) -> u64 {
+    INCOME_DUST.with(|d| d.borrow().get(&property_id).cloned().unwrap_or(0))
+}
+
+/// Query what a past deposit credited to a user, for dispute resolution. Deposits are
+/// indexed from 0 in the order they were made; an unknown deposit or user yields 0.
+#[query]
+pub fn get_deposit_allocation(property_id: PropertyId, deposit_index: u64, user: Principal) -> u64 {
+    DEPOSITS.with(|d| {
+        d.borrow()
+            .get(&property_id)
+            .and_then(|deps| deps.get(deposit_index as usize))
+            .and_then(|dep| dep.allocations.binary_search_by_key(&user, |(holder, _)| *holder).ok().map(|i| dep.allocations[i].1))
+            .unwrap_or(0)
+    })
+}
+
+/// Query aggregate deposited, unclaimed and claimed rental income for a property.
+/// Deposited income can exceed unclaimed + claimed by the share of unissued supply and by
+/// rounding dust not yet distributed.
+#[query]
+pub fn get_income_summary(property_id: PropertyId) -> IncomeSummary {
+    let total_deposited = RENTAL_INCOME.with(|ri| ri.borrow().get(&property_id).cloned().unwrap_or(0));
+    let total_unclaimed = UNCLAIMED_INCOME.with(|ui| {
+        ui.borrow()
+            .iter()
+            .filter(|((pid, _, _), _)| *pid == property_id)
+            .fold(0u64, |total, (_, amount)| total.saturating_add(*amount))
+    });
+    let total_claimed = CLAIMED_INCOME.with(|ci| ci.borrow().get(&property_id).cloned().unwrap_or(0));
+    IncomeSummary {
+        total_deposited,
+        total_unclaimed,
+        total_claimed,
+    }
+}
+
+/// Query whether `list_shares_for_sale` would accept a listing, running the same checks
+/// without creating it. Listed shares stay in the seller's balance, so the balance check is
+/// against unlocked shares only.
+#[query]
+pub fn check_listing_valid(property_id: PropertyId, seller: Principal, amount: u64, price_per_share: u64) -> Result<(), RwaError> {
+    // Check the property exists and is trading
+    match PROPERTIES.with(|props| props.borrow().get(&property_id).map(|p| p.status.clone())) {
+        None => return Err(RwaError::PropertyNotFound),
+        Some(PropertyStatus::Active) => {}
+        Some(_) => return Err(RwaError::PropertyNotActive),
+    }
+    if !trading_open(property_id) {
+        return Err(RwaError::TradingClosed);
+    }
+    // Check the price is within bounds
+    let max_price = MAX_PRICE_PER_SHARE.with(|m| *m.borrow());
+    if price_per_share == 0 || max_price.is_some_and(|max| price_per_share > max) {
+        return Err(RwaError::InvalidPrice { max: max_price });
+    }
+    if amount == 0 {
+        return Err(RwaError::InvalidAmount);
+    }
+    // Check seller owns enough unlocked shares
+    let owned = OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, seller));
+    if owned < amount {
+        return Err(RwaError::InsufficientShares { available: owned });
+    }
+    // Check seller hasn't hit the listing limit for this property
+    let max_listings = MAX_LISTINGS_PER_SELLER.with(|m| *m.borrow());
+    let active_listings = MARKETPLACE.with(|mp| {
+        mp.borrow().iter().filter(|l| l.property_id == property_id && l.seller == seller).count() as u64
+    });
+    if active_listings >= max_listings {
+        return Err(RwaError::ListingLimitReached { max: max_listings });
+    }
+    Ok(())
+}
+
+/// List shares for sale on the marketplace. `seller` must be the caller.
+#[update]
+pub fn list_shares_for_sale(
+    property_id: PropertyId,
+    seller: Principal,
+    amount: u64,
+    price_per_share: u64,
+    note: Option<String>,
+) -> Result<String, String> {
+    if authenticated_caller()? != seller {
+        return Err("Only the holder can list their shares".to_string());
+    }
+    if note.as_ref().is_some_and(|n| n.chars().count() > MAX_LISTING_NOTE_LEN) {
+        return Err(format!("Listing note can't exceed {} characters", MAX_LISTING_NOTE_LEN));
+    }
+    check_listing_valid(property_id, seller, amount, price_per_share).map_err(|e| e.to_string())?;
+    // Add listing
+    let id = NEXT_LISTING_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let curr = *next;
+        *next += 1;
+        curr
+    });
+    MARKETPLACE.with(|mp| {
+        mp.borrow_mut().push(Listing {
+            id,
+            property_id,
+            seller,
+            amount,
+            price_per_share,
+            listed_at: now(),
+            note,
+        });
+    });
+    record_event(Some(property_id), EventKind::SharesListed { listing_id: id, seller, amount, price_per_share });
+    Ok("Shares listed for sale".to_string())
+}
+
+/// Keep `keep` shares and list the rest of the seller's balance for sale in one call. `seller`
+/// must be the caller.
+#[update]
+pub fn split_and_list(property_id: PropertyId, seller: Principal, keep: u64, price_per_share: u64) -> Result<String, String> {
+    if caller() != seller {
+        return Err("Only the holder can list their shares".to_string());
+    }
+    let owned = OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, seller));
+    if keep >= owned {
+        return Err("Nothing left to list after keeping the requested shares".to_string());
+    }
+    list_shares_for_sale(property_id, seller, owned - keep, price_per_share, None)
+}
+
+/// Caller withdraws all of their listings, for one property or every property. Listed shares
+/// never leave the seller's balance, so nothing needs returning. Returns how many were removed.
+#[update]
+pub fn cancel_all_listings(property_id: Option<PropertyId>) -> Result<u64, String> {
+    let seller = authenticated_caller()?;
+    let cancelled: Vec<Listing> = MARKETPLACE.with(|mp| {
+        let mut mp = mp.borrow_mut();
+        let (cancelled, kept) = mp
+            .drain(..)
+            .partition(|l| l.seller == seller && property_id.is_none_or(|pid| pid == l.property_id));
+        *mp = kept;
+        cancelled
+    });
+    for listing in &cancelled {
+        record_event(Some(listing.property_id), EventKind::ListingCancelled { listing_id: listing.id, seller });
+    }
+    Ok(cancelled.len() as u64)
+}
+
+/// Admin removes a listing without the seller, e.g. one orphaned by a frozen account. The
+/// seller's shares were never escrowed, so they keep them.
+#[update]
+pub fn force_cancel_listing(listing_id: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can force-cancel listings".to_string());
+    }
+    let removed = MARKETPLACE.with(|mp| {
+        let mut mp = mp.borrow_mut();
+        let pos = mp.iter().position(|l| l.id == listing_id)?;
+        Some(mp.remove(pos))
+    });
+    let listing = removed.ok_or_else(|| "Listing not found".to_string())?;
+    record_event(Some(listing.property_id), EventKind::ListingCancelled { listing_id, seller: listing.seller });
+    Ok("Listing cancelled".to_string())
+}
+
+/// Admin sets the maximum number of active listings a seller can have per property.
+#[update]
+pub fn set_max_listings_per_seller(limit: u64) -> Result<String, String> {
+    let caller_principal = caller();
+    if get_role(&caller_principal) != Role::Admin {
+        return Err("Only admin can set listing limit".to_string());
+    }
+    MAX_LISTINGS_PER_SELLER.with(|m| *m.borrow_mut() = limit);
+    record_event(None, EventKind::ConfigChanged { setting: "max_listings_per_seller".to_string() });
+    Ok("Listing limit updated".to_string())
+}
+
+/// Query the maximum number of active listings a seller can have per property.
+#[query]
+pub fn get_max_listings_per_seller() -> u64 {
+    MAX_LISTINGS_PER_SELLER.with(|m| *m.borrow())
+}
+
+/// Set the maximum total shares a property may have (admin only). `None` removes the cap.
+/// Existing properties are not affected, but they cannot be split past the cap.
+#[update]
+pub fn set_max_total_shares(max: Option<u64>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set the share cap".to_string());
+    }
+    MAX_TOTAL_SHARES.with(|m| *m.borrow_mut() = max);
+    record_event(None, EventKind::ConfigChanged { setting: "max_total_shares".to_string() });
+    Ok("Share cap updated".to_string())
+}
+
+/// Query the maximum total shares a property may have, if capped.
+#[query]
+pub fn get_max_total_shares() -> Option<u64> {
+    MAX_TOTAL_SHARES.with(|m| *m.borrow())
+}
+
+/// Admin sets the maximum price per share accepted on new listings. `None` removes the bound.
+#[update]
+pub fn set_max_price_per_share(max: Option<u64>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set the maximum price".to_string());
+    }
+    MAX_PRICE_PER_SHARE.with(|m| *m.borrow_mut() = max);
+    record_event(None, EventKind::ConfigChanged { setting: "max_price_per_share".to_string() });
+    Ok("Maximum price updated".to_string())
+}
+
+/// Query the maximum price per share accepted on new listings, if bounded.
+#[query]
+pub fn get_max_price_per_share() -> Option<u64> {
+    MAX_PRICE_PER_SHARE.with(|m| *m.borrow())
+}
+
+/// Admin restricts when a property's shares may be listed and bought. `None` allows trading at any time.
+#[update]
+pub fn set_trading_window(property_id: PropertyId, window: Option<TradingWindow>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set trading windows".to_string());
+    }
+    if PROPERTIES.with(|props| !props.borrow().contains_key(&property_id)) {
+        return Err("Property not found".to_string());
+    }
+    match &window {
+        Some(TradingWindow::Fixed { opens_at, closes_at }) if opens_at >= closes_at => {
+            return Err("Trading window must open before it closes".to_string());
+        }
+        Some(TradingWindow::Daily { open, close }) if *open >= NANOS_PER_DAY || *close >= NANOS_PER_DAY || open == close => {
+            return Err("Daily trading window offsets must be distinct and within a day".to_string());
+        }
+        _ => {}
+    }
+    TRADING_WINDOWS.with(|w| match window {
+        Some(window) => w.borrow_mut().insert(property_id, window),
+        None => w.borrow_mut().remove(&property_id),
+    });
+    record_event(Some(property_id), EventKind::ConfigChanged { setting: "trading_window".to_string() });
+    Ok("Trading window updated".to_string())
+}
+
+/// Query a property's trading window, if it has one.
+#[query]
+pub fn get_trading_window(property_id: PropertyId) -> Option<TradingWindow> {
+    TRADING_WINDOWS.with(|w| w.borrow().get(&property_id).cloned())
+}
+
+/// Caller buys shares from the marketplace. Returns a receipt with the shares bought and total
+/// paid. When a settlement ledger is configured the caller pays the seller through it, and the
+/// ledger block index is recorded on the receipt and trade; a failed payment undoes the buy.
+#[update]
+pub async fn buy_shares(property_id: PropertyId, seller: Principal, amount: u64) -> Result<BuyReceipt, String> {
+    let buyer = authenticated_caller()?;
+    if amount == 0 {
+        return Err(RwaError::InvalidAmount.to_string());
+    }
+    if buyer == seller {
+        return Err("Can't buy from your own listing".to_string());
+    }
+    if !property_active(property_id) {
+        return Err("Property is not active".to_string());
+    }
+    if !trading_open(property_id) {
+        return Err("Trading is closed for this property".to_string());
+    }
+    if !recipient_allowed(property_id, buyer) {
+        return Err("Buyer is not approved for this property".to_string());
+    }
+    // Read and validate everything first, then take the shares off the listing and out of
+    // the seller's balance before paying
+    let listing = MARKETPLACE.with(|mp| {
+        mp.borrow().iter().find(|l| l.property_id == property_id && l.seller == seller && l.amount >= amount).cloned()
+    });
+    let listing = listing.ok_or_else(|| "Listing not found or insufficient shares".to_string())?;
+    if amount.checked_mul(listing.price_per_share).is_none() {
+        return Err("Trade total overflows".to_string());
+    }
+    if OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, seller)) < amount {
+        return Err("Seller no longer holds enough unlocked shares".to_string());
+    }
+    OWNERSHIP.with(|own| debit_shares(&mut own.borrow_mut(), property_id, seller, amount));
+    reduce_listing(listing.id, amount);
+    fill_listing(listing, buyer, amount).await
+}
+
+/// Pay for and deliver `amount` shares already taken off `listing` and out of the seller's
+/// balance to `buyer`, the caller. A failed payment puts the shares and listing back.
+async fn fill_listing(listing: Listing, buyer: Principal, amount: u64) -> Result<BuyReceipt, String> {
+    let property_id = listing.property_id;
+    let seller = listing.seller;
+    let listing_id = listing.id;
+    let price_per_share = listing.price_per_share;
+    let total_paid = amount * price_per_share;
+    let fee_bps = seller_fee_bps(property_id, seller);
+    let fee = take_fee(seller, total_paid, fee_bps);
+    log(LogLevel::Debug, "buy_shares", format_args!("listing_id={} buyer={} amount={} total={}", listing_id, buyer, amount, total_paid));
+    let settlement_block = match settle_property_trade(property_id, buyer, seller, total_paid, fee).await {
+        Ok(block) => block,
+        Err(e) => {
+            log(LogLevel::Error, "buy_shares", format_args!("listing_id={} buyer={} error={}", listing_id, buyer, e));
+            return_fee(seller, total_paid, fee, fee_bps);
+            // Payment failed: return the shares to the seller and restore the listing
+            OWNERSHIP.with(|own| restore_shares(&mut own.borrow_mut(), property_id, seller, amount));
+            MARKETPLACE.with(|mp| {
+                let mut mp = mp.borrow_mut();
+                match mp.iter_mut().find(|l| l.id == listing_id) {
+                    Some(l) => l.amount += amount,
+                    None => mp.push(Listing { amount, ..listing }),
+                }
+            });
+            return Err(e);
+        }
+    };
+    OWNERSHIP.with(|own| credit_shares(&mut own.borrow_mut(), property_id, buyer, amount));
+    collect_fee(settlement_block, fee);
+    credit_proceeds(property_id, seller, total_paid - fee);
+    TRADES.with(|trades| {
+        trades.borrow_mut().entry(property_id).or_default().push(TradeRecord {
+            timestamp: now(),
+            buyer,
+            seller,
+            amount,
+            price_per_share,
+            settlement_block,
+        });
+    });
+    record_event_by(
+        buyer,
+        Some(property_id),
+        EventKind::SharesBought { listing_id, seller, buyer, amount, price_per_share },
+    );
+    auto_claim(buyer, property_id, seller);
+    Ok(BuyReceipt {
+        shares: amount,
+        price_per_share,
+        total_paid,
+        fee,
+        listing_id,
+        settlement_block,
+    })
+}
+
+/// Query a seller's cumulative marketplace proceeds across all properties, net of platform fees,
+/// including income auto-claimed when they moved shares.
+#[query]
+pub fn get_seller_proceeds(user: Principal) -> u128 {
+    SELLER_PROCEEDS.with(|p| p.borrow().iter().filter(|((_, seller), _)| *seller == user).map(|(_, amount)| *amount).sum())
+}
+
+/// Query a seller's cumulative marketplace proceeds for one property, net of platform fees,
+/// including income auto-claimed when they moved shares.
+#[query]
+pub fn get_seller_proceeds_for_property(user: Principal, property_id: PropertyId) -> u128 {
+    SELLER_PROCEEDS.with(|p| p.borrow().get(&(property_id, user)).cloned().unwrap_or(0))
+}
+
+/// Admin sets the debug log verbosity. Logging is off by default.
+#[update]
+pub fn set_log_level(level: LogLevel) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set the log level".to_string());
+    }
+    LOG_LEVEL.with(|l| *l.borrow_mut() = level);
+    record_event(None, EventKind::ConfigChanged { setting: "log_level".to_string() });
+    Ok("Log level updated".to_string())
+}
+
+/// Query the debug log verbosity.
+#[query]
+pub fn get_log_level() -> LogLevel {
+    LOG_LEVEL.with(|l| *l.borrow())
+}
+
+/// Query whether `buyer` could buy `amount` shares of a property from the marketplace now.
+/// Nothing is reserved, so the answer can change before a buy is submitted.
+#[query]
+pub fn check_buy_eligibility(property_id: PropertyId, buyer: Principal, amount: u64) -> BuyEligibility {
+    let property_active = property_active(property_id);
+    let trading_open = trading_open(property_id);
+    let recipient_approved = recipient_allowed(property_id, buyer);
+    let mut listings: Vec<Listing> = MARKETPLACE.with(|mp| {
+        mp.borrow().iter().filter(|l| l.property_id == property_id && l.seller != buyer).cloned().collect()
+    });
+    listings.sort_by_key(|l| (l.price_per_share, l.listed_at, l.id));
+    // Count each seller's listings against their unlocked balance, cheapest first
+    let mut backing: HashMap<Principal, u64> = HashMap::new();
+    let mut available_liquidity: u64 = 0;
+    let mut remaining = amount;
+    let mut cost: u128 = 0;
+    OWNERSHIP.with(|own| {
+        let own = own.borrow();
+        for listing in &listings {
+            let left = backing.entry(listing.seller).or_insert_with(|| unlocked_balance(&own, property_id, listing.seller));
+            let backed = listing.amount.min(*left);
+            *left -= backed;
+            available_liquidity = available_liquidity.saturating_add(backed);
+            let take = backed.min(remaining);
+            remaining -= take;
+            cost += take as u128 * listing.price_per_share as u128;
+        }
+    });
+    let estimated_cost = if remaining == 0 { u64::try_from(cost).ok() } else { None };
+    BuyEligibility {
+        eligible: amount > 0 && property_active && trading_open && recipient_approved && estimated_cost.is_some(),
+        property_active,
+        trading_open,
+        recipient_approved,
+        available_liquidity,
+        estimated_cost,
+    }
+}
+
+/// Caller buys as many whole shares as `max_spend` allows, cheapest listings first, paying
+/// at most `max_price_per_share`. The caller's own listings are skipped. Stops early if a
+/// payment fails, keeping the fills made so far.
+#[update]
+pub async fn buy_with_budget(property_id: PropertyId, max_spend: u64, max_price_per_share: u64) -> Result<BudgetBuyReceipt, String> {
+    let buyer = authenticated_caller()?;
+    if !property_active(property_id) {
+        return Err("Property is not active".to_string());
+    }
+    if !trading_open(property_id) {
+        return Err("Trading is closed for this property".to_string());
+    }
+    if !recipient_allowed(property_id, buyer) {
+        return Err("Buyer is not approved for this property".to_string());
+    }
+    let mut remaining = max_spend;
+    let mut fills: Vec<BuyReceipt> = Vec::new();
+    let mut skipped: HashSet<u64> = HashSet::new();
+    loop {
+        // Reserve as much of the cheapest affordable listing as the budget and seller allow
+        let reserved = MARKETPLACE.with(|mp| {
+            let listing = mp
+                .borrow()
+                .iter()
+                .filter(|l| {
+                    l.property_id == property_id
+                        && l.seller != buyer
+                        && l.price_per_share <= max_price_per_share
+                        && l.price_per_share <= remaining
+                        && !skipped.contains(&l.id)
+                })
+                .min_by_key(|l| (l.price_per_share, l.listed_at, l.id))
+                .cloned()?;
+            let backed = OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, listing.seller));
+            let amount = listing.amount.min(remaining / listing.price_per_share).min(backed);
+            Some((listing, amount))
+        });
+        let Some((listing, amount)) = reserved else { break };
+        if amount == 0 || !OWNERSHIP.with(|own| debit_shares(&mut own.borrow_mut(), property_id, listing.seller, amount)) {
+            skipped.insert(listing.id);
+            continue;
+        }
+        reduce_listing(listing.id, amount);
+        match fill_listing(listing, buyer, amount).await {
+            Ok(receipt) => {
+                remaining -= receipt.total_paid;
+                fills.push(receipt);
+            }
+            Err(e) if fills.is_empty() => return Err(e),
+            Err(_) => break,
+        }
+    }
+    let total_paid: u64 = fills.iter().map(|f| f.total_paid).sum();
+    Ok(BudgetBuyReceipt {
+        shares: fills.iter().map(|f| f.shares).sum(),
+        total_paid,
+        fee: fills.iter().map(|f| f.fee).sum(),
+        leftover: max_spend - total_paid,
+        fills,
+    })
+}
+
+/// Admin limits how many trading and holding calls each principal can make per window.
+/// `None` turns rate limiting off.
+#[update]
+pub fn set_rate_limit(limit: Option<RateLimit>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set the rate limit".to_string());
+    }
+    if limit.is_some_and(|l| l.max_calls == 0 || l.window_nanos == 0) {
+        return Err("Rate limit needs a positive call count and window".to_string());
+    }
+    RATE_LIMIT.with(|l| *l.borrow_mut() = limit);
+    CALL_WINDOWS.with(|w| w.borrow_mut().clear());
+    record_event(None, EventKind::ConfigChanged { setting: "rate_limit".to_string() });
+    Ok("Rate limit updated".to_string())
+}
+
+/// Query the per-principal rate limit, if any.
+#[query]
+pub fn get_rate_limit() -> Option<RateLimit> {
+    RATE_LIMIT.with(|l| *l.borrow())
+}
+
+/// Admin sets the platform fee charged on each trade, in basis points of the trade total.
+#[update]
+pub fn set_platform_fee_bps(fee_bps: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set the platform fee".to_string());
+    }
+    if fee_bps > 10_000 {
+        return Err("Platform fee can't exceed 10000 bps".to_string());
+    }
+    PLATFORM_FEE_BPS.with(|f| *f.borrow_mut() = fee_bps);
+    record_event(None, EventKind::ConfigChanged { setting: "platform_fee_bps".to_string() });
+    Ok("Platform fee updated".to_string())
+}
+
+#[query]
+pub fn get_platform_fee_bps() -> u64 {
+    PLATFORM_FEE_BPS.with(|f| *f.borrow())
+}
+
+/// Admin sets the long-term holder fee rebate tiers, replacing any existing ones. An empty
+/// list removes all rebates. Holding time counts from when a seller's current position in the
+/// property was opened, i.e. when their balance last went from zero to positive.
+#[update]
+pub fn set_fee_rebate_tiers(mut tiers: Vec<FeeRebateTier>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set fee rebates".to_string());
+    }
+    if tiers.iter().any(|t| t.rebate_bps > 10_000) {
+        return Err("Fee rebate can't exceed 10000 bps".to_string());
+    }
+    tiers.sort_by_key(|t| t.min_holding_nanos);
+    FEE_REBATE_TIERS.with(|t| *t.borrow_mut() = tiers);
+    record_event(None, EventKind::ConfigChanged { setting: "fee_rebate_tiers".to_string() });
+    Ok("Fee rebate tiers updated".to_string())
+}
+
+/// Query the long-term holder fee rebate tiers, shortest holding period first.
+#[query]
+pub fn get_fee_rebate_tiers() -> Vec<FeeRebateTier> {
+    FEE_REBATE_TIERS.with(|t| t.borrow().clone())
+}
+
+/// Query when a user's current position in a property was opened, if they hold any.
+#[query]
+pub fn get_holding_since(property_id: PropertyId, user: Principal) -> Option<u64> {
+    if OWNERSHIP.with(|own| holding(&own.borrow(), property_id, user)) == 0 {
+        return None;
+    }
+    HOLDING_SINCE.with(|h| h.borrow().get(&(property_id, user)).cloned())
+}
+
+/// Query platform fees collected into the treasury.
+#[query]
+pub fn get_treasury_balance() -> u64 {
+    TREASURY.with(|t| *t.borrow())
+}
+
+/// Admin pays collected platform fees out of the treasury to `to` on the settlement ledger.
+/// If the transfer fails the amount goes back into the treasury. Once multi-sig is on,
+/// withdrawals go through `propose_operation` instead.
+#[update]
+pub async fn withdraw_treasury(to: Principal, amount: u64) -> Result<WithdrawReceipt, String> {
+    let actor = caller();
+    if get_role(&actor) != Role::Admin {
+        return Err("Only admin can withdraw from the treasury".to_string());
+    }
+    if multi_sig_required() {
+        return Err("Treasury withdrawals require multi-sig approval; use propose_operation".to_string());
+    }
+    execute_treasury_withdrawal(actor, to, amount).await
+}
+
+async fn execute_treasury_withdrawal(actor: Principal, to: Principal, amount: u64) -> Result<WithdrawReceipt, String> {
+    if SETTLEMENT_LEDGER.with(|l| l.borrow().is_none()) {
+        return Err("No settlement ledger configured".to_string());
+    }
+    if amount == 0 {
+        return Err("Withdrawal amount must be positive".to_string());
+    }
+    // Reserve the amount so a concurrent withdrawal can't spend it too
+    TREASURY.with(|t| {
+        let mut t = t.borrow_mut();
+        if *t < amount {
+            return Err(format!("Treasury only holds {}", *t));
+        }
+        *t -= amount;
+        Ok(())
+    })?;
+    match payout(to, None, amount).await {
+        Ok(Some(block_index)) => {
+            record_event_by(actor, None, EventKind::TreasuryWithdrawn { to, amount, block_index });
+            Ok(WithdrawReceipt { amount, block_index })
+        }
+        result => {
+            TREASURY.with(|t| *t.borrow_mut() += amount);
+            let err = result.err().unwrap_or_else(|| "No settlement ledger configured".to_string());
+            log(LogLevel::Error, "withdraw_treasury", format_args!("to={} amount={} error={}", to, amount, err));
+            Err(err)
+        }
+    }
+}
+
+fn multi_sig_required() -> bool {
+    REQUIRED_APPROVALS.with(|r| *r.borrow()) > 1
+}
+
+/// Admin sets how many distinct admin approvals deposits, snapshot distributions, treasury
+/// withdrawals, income adjustments, cash-out consolidations and role changes need. 1 lets a
+/// single admin call them directly. Once multi-sig is on, the threshold itself changes through
+/// `propose_operation`.
+#[update]
+pub fn set_required_approvals(approvals: u64) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set required approvals".to_string());
+    }
+    if multi_sig_required() {
+        return Err("Changing required approvals requires multi-sig approval; use propose_operation".to_string());
+    }
+    apply_required_approvals(approvals)
+}
+
+fn apply_required_approvals(approvals: u64) -> Result<String, String> {
+    if approvals == 0 {
+        return Err("At least one approval is required".to_string());
+    }
+    if approvals > admin_count() {
+        return Err(format!("Only {} admins can approve operations", admin_count()));
+    }
+    REQUIRED_APPROVALS.with(|r| *r.borrow_mut() = approvals);
+    record_event(None, EventKind::ConfigChanged { setting: "required_approvals".to_string() });
+    Ok("Required approvals updated".to_string())
+}
+
+#[query]
+pub fn get_required_approvals() -> u64 {
+    REQUIRED_APPROVALS.with(|r| *r.borrow())
+}
+
+/// Admin proposes a high-value action. It runs once enough admins, the proposer included,
+/// have called `approve_operation`.
+#[update]
+pub fn propose_operation(operation: AdminOperation) -> Result<u64, String> {
+    let proposer = caller();
+    if get_role(&proposer) != Role::Admin {
+        return Err("Only admin can propose operations".to_string());
+    }
+    let id = NEXT_OPERATION_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let id = *next;
+        *next += 1;
+        id
+    });
+    let op = PendingOperation { id, operation, proposer, approvals: Vec::new(), created_at: now() };
+    PENDING_OPERATIONS.with(|ops| ops.borrow_mut().insert(id, op));
+    record_event(None, EventKind::OperationProposed { operation_id: id });
+    Ok(id)
+}
+
+/// Admin approves a pending operation. The approval that reaches the required count runs
+/// it; the operation is consumed whether or not it then succeeds.
+#[update]
+pub async fn approve_operation(operation_id: u64) -> Result<String, String> {
+    let actor = caller();
+    if get_role(&actor) != Role::Admin {
+        return Err("Only admin can approve operations".to_string());
+    }
+    let required = get_required_approvals();
+    // Take the operation out before executing, so a concurrent approval can't run it twice
+    let ready = PENDING_OPERATIONS.with(|ops| {
+        let mut ops = ops.borrow_mut();
+        let op = ops.get_mut(&operation_id).ok_or_else(|| "Operation not found".to_string())?;
+        if op.approvals.contains(&actor) {
+            return Err("Already approved".to_string());
+        }
+        op.approvals.push(actor);
+        let approvals = op.approvals.len() as u64;
+        Ok(if approvals >= required { ops.remove(&operation_id) } else { None })
+    })?;
+    record_event(None, EventKind::OperationApproved { operation_id });
+    let Some(op) = ready else {
+        return Ok("Approval recorded".to_string());
+    };
+    match op.operation {
+        AdminOperation::DepositRentalIncome { property_id, amount } => apply_rental_deposit(property_id, amount),
+        AdminOperation::WithdrawTreasury { to, amount } => execute_treasury_withdrawal(actor, to, amount)
+            .await
+            .map(|receipt| format!("Treasury withdrawal settled at block {}", receipt.block_index)),
+        AdminOperation::DistributeToSnapshot { property_id, snapshot_id, amount } => {
+            apply_snapshot_distribution(property_id, snapshot_id, amount)
+        }
+        AdminOperation::SetRequiredApprovals { approvals } => apply_required_approvals(approvals),
+        AdminOperation::SetRole { user, role } => apply_role(user, role),
+        AdminOperation::AdjustUnclaimedIncome { property_id, user, delta, reason } => {
+            apply_income_adjustment(property_id, user, delta, reason).map(|balance| format!("Unclaimed income adjusted to {}", balance))
+        }
+        AdminOperation::ReverseSplit { property_id, factor, cash_out_price } => {
+            apply_reverse_split(property_id, factor, cash_out_price)
+        }
+    }
+}
+
+/// Admin lists operations still waiting for approvals, oldest first.
+#[query]
+pub fn get_pending_operations() -> Result<Vec<PendingOperation>, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can view pending operations".to_string());
+    }
+    Ok(PENDING_OPERATIONS.with(|ops| ops.borrow().values().cloned().collect()))
+}
+
+/// Admin sets the ICRC-2 ledger trades settle through, or `None` to settle off-chain.
+#[update]
+pub fn set_settlement_ledger(ledger: Option<Principal>) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set the settlement ledger".to_string());
+    }
+    SETTLEMENT_LEDGER.with(|l| *l.borrow_mut() = ledger);
+    record_event(None, EventKind::ConfigChanged { setting: "settlement_ledger".to_string() });
+    Ok("Settlement ledger updated".to_string())
+}
+
+#[query]
+pub fn get_settlement_ledger() -> Option<Principal> {
+    SETTLEMENT_LEDGER.with(|l| *l.borrow())
+}
+
+/// Caller places a bid to buy shares at up to `price_per_share`. Bids rest until
+/// `run_matching` crosses them with listings, and fills are paid from the caller's allowance.
+#[update]
+pub fn place_bid(property_id: PropertyId, amount: u64, price_per_share: u64) -> Result<u64, String> {
+    let buyer = authenticated_caller()?;
+    if amount == 0 || price_per_share == 0 {
+        return Err("Bid amount and price must be positive".to_string());
+    }
+    if amount.checked_mul(price_per_share).is_none() {
+        return Err("Bid total overflows".to_string());
+    }
+    if PROPERTIES.with(|props| !props.borrow().contains_key(&property_id)) {
+        return Err("Property not found".to_string());
+    }
+    if !recipient_allowed(property_id, buyer) {
+        return Err("Buyer is not approved for this property".to_string());
+    }
+    let id = NEXT_BID_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let curr = *next;
+        *next += 1;
+        curr
+    });
+    BIDS.with(|bids| {
+        bids.borrow_mut().push(Bid {
+            id,
+            property_id,
+            buyer,
+            amount,
+            price_per_share,
+            placed_at: now(),
+        });
+    });
+    record_event(Some(property_id), EventKind::BidPlaced { bid_id: id, buyer, amount, price_per_share });
+    Ok(id)
+}
+
+/// Caller cancels one of their resting bids.
+#[update]
+pub fn cancel_bid(bid_id: u64) -> Result<String, String> {
+    let buyer = authenticated_caller()?;
+    let removed = BIDS.with(|bids| {
+        let mut bids = bids.borrow_mut();
+        let pos = bids.iter().position(|b| b.id == bid_id && b.buyer == buyer)?;
+        Some(bids.remove(pos))
+    });
+    match removed {
+        Some(bid) => {
+            record_event(Some(bid.property_id), EventKind::BidCancelled { bid_id, buyer });
+            Ok("Bid cancelled".to_string())
+        }
+        None => Err("Bid not found".to_string()),
+    }
+}
+
+/// Query resting bids for a property.
+#[query]
+pub fn get_bids(property_id: PropertyId) -> Vec<Bid> {
+    BIDS.with(|bids| bids.borrow().iter().filter(|b| b.property_id == property_id).cloned().collect())
+}
+
+/// Query the order book depth for a property, aggregated by price level.
+#[query]
+pub fn get_order_book(property_id: PropertyId) -> OrderBook {
+    let mut asks: BTreeMap<u64, u64> = BTreeMap::new();
+    MARKETPLACE.with(|mp| {
+        for l in mp.borrow().iter().filter(|l| l.property_id == property_id) {
+            *asks.entry(l.price_per_share).or_insert(0) += l.amount;
+        }
+    });
+    let mut bids: BTreeMap<u64, u64> = BTreeMap::new();
+    BIDS.with(|b| {
+        for bid in b.borrow().iter().filter(|b| b.property_id == property_id) {
+            *bids.entry(bid.price_per_share).or_insert(0) += bid.amount;
+        }
+    });
+    OrderBook {
+        asks: asks.into_iter().collect(),
+        bids: bids.into_iter().rev().collect(),
+    }
+}
+
+/// Highest, earliest bid and lowest, earliest listing for a property, if they cross. A bid
+/// never meets its own buyer's listings, so such pairs are passed over.
+fn best_cross(property_id: PropertyId) -> Option<(Bid, Listing)> {
+    let mut bids: Vec<Bid> = BIDS.with(|bids| bids.borrow().iter().filter(|b| b.property_id == property_id).cloned().collect());
+    bids.sort_by_key(|b| (std::cmp::Reverse(b.price_per_share), b.placed_at, b.id));
+    let mut listings: Vec<Listing> =
+        MARKETPLACE.with(|mp| mp.borrow().iter().filter(|l| l.property_id == property_id).cloned().collect());
+    listings.sort_by_key(|l| (l.price_per_share, l.listed_at, l.id));
+    bids.into_iter().find_map(|bid| {
+        let listing = listings.iter().find(|l| l.seller != bid.buyer)?;
+        (bid.price_per_share >= listing.price_per_share).then(|| (bid, listing.clone()))
+    })
+}
+
+/// Take `amount` off a listing, removing it once empty.
+fn reduce_listing(listing_id: u64, amount: u64) {
+    MARKETPLACE.with(|mp| {
+        let mut mp = mp.borrow_mut();
+        if let Some(pos) = mp.iter().position(|l| l.id == listing_id) {
+            if mp[pos].amount <= amount {
+                mp.remove(pos);
+            } else {
+                mp[pos].amount -= amount;
+            }
+        }
+    });
+}
+
+/// Cross resting bids and listings for a property in price-time priority: the highest bid
+/// meets the lowest listing, earlier orders first at each price. Each fill executes at the
+/// price of whichever order was resting first. Listings the seller can no longer back are
+/// dropped from the book, and a bid whose payment fails is cancelled.
+#[update]
+pub async fn run_matching(property_id: PropertyId) -> Result<Vec<MatchFill>, String> {
+    let actor = authenticated_caller()?;
+    let mut fills = Vec::new();
+    if !property_active(property_id) || !trading_open(property_id) {
+        return Ok(fills);
+    }
+    while let Some((bid, listing)) = best_cross(property_id) {
+        let amount = bid.amount.min(listing.amount);
+        let price_per_share = if listing.listed_at <= bid.placed_at {
+            listing.price_per_share
+        } else {
+            bid.price_per_share
+        };
+        // The buyer's approval may have been revoked since the bid was placed
+        if !recipient_allowed(property_id, bid.buyer) {
+            BIDS.with(|bids| bids.borrow_mut().retain(|b| b.id != bid.id));
+            record_event_by(actor, Some(property_id), EventKind::BidCancelled { bid_id: bid.id, buyer: bid.buyer });
+            continue;
+        }
+        // Reserve the seller's shares and both orders before paying
+        if !OWNERSHIP.with(|own| debit_shares(&mut own.borrow_mut(), property_id, listing.seller, amount)) {
+            MARKETPLACE.with(|mp| mp.borrow_mut().retain(|l| l.id != listing.id));
+            continue;
+        }
+        reduce_listing(listing.id, amount);
+        BIDS.with(|bids| {
+            let mut bids = bids.borrow_mut();
+            if let Some(pos) = bids.iter().position(|b| b.id == bid.id) {
+                if bids[pos].amount == amount {
+                    bids.remove(pos);
+                } else {
+                    bids[pos].amount -= amount;
+                }
+            }
+        });
+        let total = amount * price_per_share;
+        let fee_bps = seller_fee_bps(property_id, listing.seller);
+        let fee = take_fee(listing.seller, total, fee_bps);
+        let settlement_block = match settle_property_trade(property_id, bid.buyer, listing.seller, total, fee).await {
+            Ok(block) => block,
+            Err(e) => {
+                log(LogLevel::Error, "run_matching", format_args!("bid_id={} listing_id={} error={}", bid.id, listing.id, e));
+                return_fee(listing.seller, total, fee, fee_bps);
+                // Return the shares and listing; the buyer can't pay, so drop the rest of the bid
+                OWNERSHIP.with(|own| restore_shares(&mut own.borrow_mut(), property_id, listing.seller, amount));
+                MARKETPLACE.with(|mp| {
+                    let mut mp = mp.borrow_mut();
+                    match mp.iter_mut().find(|l| l.id == listing.id) {
+                        Some(l) => l.amount += amount,
+                        None => mp.push(Listing { amount, ..listing }),
+                    }
+                });
+                BIDS.with(|bids| bids.borrow_mut().retain(|b| b.id != bid.id));
+                record_event_by(actor, Some(property_id), EventKind::BidCancelled { bid_id: bid.id, buyer: bid.buyer });
+                continue;
+            }
+        };
+        OWNERSHIP.with(|own| credit_shares(&mut own.borrow_mut(), property_id, bid.buyer, amount));
+        collect_fee(settlement_block, fee);
+        credit_proceeds(property_id, listing.seller, total - fee);
+        TRADES.with(|trades| {
+            trades.borrow_mut().entry(property_id).or_default().push(TradeRecord {
+                timestamp: now(),
+                buyer: bid.buyer,
+                seller: listing.seller,
+                amount,
+                price_per_share,
+                settlement_block,
+            });
+        });
+        record_event_by(
+            actor,
+            Some(property_id),
+            EventKind::SharesBought { listing_id: listing.id, seller: listing.seller, buyer: bid.buyer, amount, price_per_share },
+        );
+        fills.push(MatchFill {
+            bid_id: bid.id,
+            listing_id: listing.id,
+            buyer: bid.buyer,
+            seller: listing.seller,
+            amount,
+            price_per_share,
+            settlement_block,
+        });
+    }
+    Ok(fills)
+}
+
+/// Query the most recent trades for a property, newest first.
+#[query]
+pub fn get_recent_trades(property_id: PropertyId, limit: u64) -> Vec<TradeRecord> {
+    TRADES.with(|trades| {
+        trades
+            .borrow()
+            .get(&property_id)
+            .map(|t| t.iter().rev().take(limit as usize).cloned().collect())
+            .unwrap_or_default()
+    })
+}
+
+/// Query the mark-to-market value of a user's position: shares owned times the latest trade
+/// price, or their pro-rata share of the property valuation when it has never traded.
+#[query]
+pub fn get_position_value(property_id: PropertyId, user: Principal) -> u64 {
+    let shares = OWNERSHIP.with(|own| holding(&own.borrow(), property_id, user)) as u128;
+    let latest_price = TRADES.with(|trades| {
+        trades.borrow().get(&property_id).and_then(|t| t.last()).map(|t| t.price_per_share)
+    });
+    let value = match latest_price {
+        Some(price) => shares * price as u128,
+        None => PROPERTIES.with(|props| match props.borrow().get(&property_id) {
+            Some(prop) if prop.total_shares > 0 => shares * prop.metadata.valuation as u128 / prop.total_shares as u128,
+            _ => 0,
+        }),
+    };
+    value.min(u64::MAX as u128) as u64
+}
+
+/// Query the implied market cap of a property: total shares times the latest trade price,
+/// or its valuation when it has never traded. Saturates at `u64::MAX`; 0 if not found.
+#[query]
+pub fn get_market_cap(property_id: PropertyId) -> u64 {
+    let latest_price = TRADES.with(|trades| {
+        trades.borrow().get(&property_id).and_then(|t| t.last()).map(|t| t.price_per_share)
+    });
+    PROPERTIES.with(|props| match props.borrow().get(&property_id) {
+        Some(prop) => match latest_price {
+            Some(price) => (prop.total_shares as u128 * price as u128).min(u64::MAX as u128) as u64,
+            None => prop.metadata.valuation,
+        },
+        None => 0,
+    })
+}
+
+/// Query the `n` properties whose price moved most over the last `window_secs`, as change in
+/// basis points from the last trade before the window (or the first one in it) to the latest
+/// trade. Largest absolute moves come first, ties by ascending id; properties that haven't
+/// traded in the window are left out.
+#[query]
+pub fn get_top_movers(window_secs: u64, n: u64) -> Vec<(PropertyId, i64)> {
+    let since = now().saturating_sub(window_secs.saturating_mul(1_000_000_000));
+    let mut movers: Vec<(PropertyId, i64)> = TRADES.with(|trades| {
+        trades
+            .borrow()
+            .iter()
+            .filter_map(|(pid, t)| {
+                let latest = t.last().filter(|t| t.timestamp >= since)?.price_per_share;
+                // Trades are oldest first, so with none before the window the first is in it
+                let reference = t[t.iter().rposition(|t| t.timestamp < since).unwrap_or(0)].price_per_share;
+                if reference == 0 {
+                    return None;
+                }
+                let change = (latest as i128 - reference as i128) * 10_000 / reference as i128;
+                Some((*pid, change.clamp(i64::MIN as i128, i64::MAX as i128) as i64))
+            })
+            .collect()
+    });
+    movers.sort_by(|a, b| b.1.unsigned_abs().cmp(&a.1.unsigned_abs()).then(a.0.cmp(&b.0)));
+    movers.truncate(n as usize);
+    movers
+}
+
+/// Transfer shares directly between users. `from` must be the caller.
+#[update]
+pub fn transfer_shares(property_id: PropertyId, from: Principal, to: Principal, amount: u64) -> Result<String, String> {
+    if authenticated_caller()? != from {
+        return Err("Only the holder can transfer their shares".to_string());
+    }
+    if !recipient_allowed(property_id, to) {
+        return Err("Recipient is not approved for this property".to_string());
+    }
+    if OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, from)) < amount {
+        return Err("Not enough shares to transfer".to_string());
+    }
+    OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        debit_shares(&mut own, property_id, from, amount);
+        credit_shares(&mut own, property_id, to, amount);
+    });
+    record_event(Some(property_id), EventKind::SharesTransferred { from, to, amount });
+    auto_claim(caller(), property_id, from);
+    log(LogLevel::Info, "transfer_shares", format_args!("property_id={} from={} to={} amount={}", property_id, from, to, amount));
+    Ok("Shares transferred".to_string())
+}
+
+/// Transfer the caller's entire unlocked balance of a property to `to`.
+#[update]
+pub fn transfer_all_shares(property_id: PropertyId, to: Principal) -> Result<String, String> {
+    let from = caller();
+    let amount = OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, from));
+    if amount == 0 {
+        return Err("No shares to transfer".to_string());
+    }
+    transfer_shares(property_id, from, to, amount)
+}
+
+/// Transfer the caller's shares to `to` only if the property's latest trade price is at
+/// least `min_price`. Nothing moves if it has never traded or trades below it.
+#[update]
+pub fn transfer_shares_if_price_at_least(property_id: PropertyId, to: Principal, amount: u64, min_price: u64) -> Result<String, String> {
+    let from = caller();
+    let latest_price = TRADES.with(|trades| {
+        trades.borrow().get(&property_id).and_then(|t| t.last()).map(|t| t.price_per_share)
+    });
+    match latest_price {
+        None => Err("Property has not traded yet".to_string()),
+        Some(price) if price < min_price => Err(format!("Latest price {} is below {}", price, min_price)),
+        Some(_) => transfer_shares(property_id, from, to, amount),
+    }
+}
+
+/// Move shares between ICRC-1 style subaccounts, of the caller or of another principal.
+/// `None` (or the all-zero subaccount) is the default one, which is what listings, locks and
+/// plain transfers draw from. Each deposit's income is attributed to the subaccounts holding
+/// shares at the time, and stays with them when the shares move on.
+#[update]
+pub fn transfer_subaccount_shares(
+    property_id: PropertyId,
+    from_subaccount: Option<Subaccount>,
+    to: Principal,
+    to_subaccount: Option<Subaccount>,
+    amount: u64,
+) -> Result<String, String> {
+    let from = authenticated_caller()?;
+    let from_subaccount = normalize_subaccount(from_subaccount)?;
+    let to_subaccount = normalize_subaccount(to_subaccount)?;
+    if amount == 0 {
+        return Err("Amount must be greater than 0".to_string());
+    }
+    if from == to && from_subaccount == to_subaccount {
+        return Err("Source and destination must differ".to_string());
+    }
+    if from != to && !recipient_allowed(property_id, to) {
+        return Err("Recipient is not approved for this property".to_string());
+    }
+    if OWNERSHIP.with(|own| available_balance(&own.borrow(), property_id, from, &from_subaccount)) < amount {
+        return Err("Not enough shares to transfer".to_string());
+    }
+    OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        // Crediting first keeps the holding period when a whole position moves between
+        // subaccounts of the same principal
+        credit_subaccount(&mut own, property_id, to, to_subaccount, amount);
+        debit_subaccount(&mut own, property_id, from, &from_subaccount, amount);
+    });
+    if from != to {
+        record_event(Some(property_id), EventKind::SharesTransferred { from, to, amount });
+        auto_claim(from, property_id, from);
+    }
+    Ok("Shares transferred".to_string())
+}
+
+/// Shares a principal holds in one subaccount of a property; `None` is the default one.
+#[query]
+pub fn get_subaccount_balance(property_id: PropertyId, owner: Principal, subaccount: Option<Subaccount>) -> Result<u64, String> {
+    let key = (property_id, owner, normalize_subaccount(subaccount)?);
+    Ok(OWNERSHIP.with(|own| own.borrow().get(&key).cloned().unwrap_or(0)))
+}
+
+/// Unclaimed income one subaccount of a principal earned from a property; `None` is the
+/// default one.
+#[query]
+pub fn get_subaccount_income(property_id: PropertyId, owner: Principal, subaccount: Option<Subaccount>) -> Result<u64, String> {
+    let key = (property_id, owner, normalize_subaccount(subaccount)?);
+    Ok(UNCLAIMED_INCOME.with(|ui| ui.borrow().get(&key).cloned().unwrap_or(0)))
+}
+
+/// Non-default subaccounts of a principal holding shares of a property.
+#[query]
+pub fn get_subaccounts(property_id: PropertyId, owner: Principal) -> Vec<(Subaccount, u64)> {
+    let default = default_subaccount();
+    OWNERSHIP.with(|own| {
+        let mut result: Vec<(Subaccount, u64)> = own
+            .borrow()
+            .iter()
+            .filter(|((pid, user, sub), _)| *pid == property_id && *user == owner && *sub != default)
+            .map(|((_, _, sub), s)| (sub.clone(), *s))
+            .collect();
+        result.sort();
+        result
+    })
+}
+
+/// Admin moves a user's holdings to a new principal, e.g. after key loss, for one property or
+/// all of them. Every subaccount's balance and unclaimed income, locks on their shares and their
+/// listings move; open bids stay with the old principal, since they are paid from its account.
+#[update]
+pub fn migrate_user(property_id: Option<PropertyId>, from: Principal, to: Principal) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can migrate users".to_string());
+    }
+    if from == to {
+        return Err("Source and destination must differ".to_string());
+    }
+    if property_id.is_some_and(|pid| PROPERTIES.with(|props| !props.borrow().contains_key(&pid))) {
+        return Err("Property not found".to_string());
+    }
+    let applies = |pid: PropertyId| property_id.is_none_or(|p| p == pid);
+    OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        let moved: Vec<(PropertyId, Subaccount, u64)> = own
+            .iter()
+            .filter(|((pid, user, _), _)| *user == from && applies(*pid))
+            .map(|((pid, _, sub), s)| (*pid, sub.clone(), *s))
+            .collect();
+        for (pid, sub, shares) in moved {
+            own.remove(&(pid, from, sub.clone()));
+            credit_subaccount(&mut own, pid, to, sub, shares);
+        }
+    });
+    UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        let moved: Vec<(PropertyId, Subaccount, u64)> = ui
+            .iter()
+            .filter(|((pid, user, _), _)| *user == from && applies(*pid))
+            .map(|((pid, _, sub), a)| (*pid, sub.clone(), *a))
+            .collect();
+        for (pid, sub, amount) in moved {
+            ui.remove(&(pid, from, sub.clone()));
+            credit_income(&mut ui, pid, to, sub, amount);
+        }
+    });
+    LOCKED.with(|locked| {
+        let mut locked = locked.borrow_mut();
+        let moved: Vec<(PropertyId, Principal, u64)> = locked
+            .iter()
+            .filter(|((pid, owner, _), _)| *owner == from && applies(*pid))
+            .map(|((pid, _, locker), s)| (*pid, *locker, *s))
+            .collect();
+        for (pid, locker, shares) in moved {
+            locked.remove(&(pid, from, locker));
+            *locked.entry((pid, to, locker)).or_insert(0) += shares;
+        }
+    });
+    MARKETPLACE.with(|mp| {
+        for listing in mp.borrow_mut().iter_mut().filter(|l| l.seller == from && applies(l.property_id)) {
+            listing.seller = to;
+        }
+    });
+    PENDING_TRANSFERS.with(|p| {
+        for transfer in p.borrow_mut().values_mut().filter(|t| applies(t.property_id)) {
+            if transfer.from == from {
+                transfer.from = to;
+            }
+            if transfer.to == from {
+                transfer.to = to;
+            }
+        }
+    });
+    record_event(property_id, EventKind::UserMigrated { from, to });
+    Ok("User migrated".to_string())
+}
+
+/// Admin marks a principal as provably inaccessible (e.g. the management canister or a
+/// blackhole), allowing shares stuck with it to be recovered.
+#[update]
+pub fn set_recoverable_principal(principal: Principal, recoverable: bool) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can mark recoverable principals".to_string());
+    }
+    RECOVERABLE_PRINCIPALS.with(|r| {
+        let mut r = r.borrow_mut();
+        if recoverable {
+            r.insert(principal);
+        } else {
+            r.remove(&principal);
+        }
     });
-    Ok("Role updated".to_string())
+    record_event(None, EventKind::ConfigChanged { setting: "recoverable_principals".to_string() });
+    Ok("Recoverable principals updated".to_string())
+}
+
+/// Query principals whose shares may be recovered.
+#[query]
+pub fn get_recoverable_principals() -> Vec<Principal> {
+    let mut principals: Vec<Principal> = RECOVERABLE_PRINCIPALS.with(|r| r.borrow().iter().cloned().collect());
+    principals.sort();
+    principals
 }
 
+/// Admin forbids or allows a principal as a destination for shares, e.g. canisters that can't
+/// move them on. The management canister is forbidden by default; the anonymous principal
+/// always is.
 #[update]
-pub fn bootstrap_admin(admin: Principal) -> Result<String, String> {
-    let already_bootstrapped = BOOTSTRAPPED.with(|b| *b.borrow());
-    if already_bootstrapped {
-        return Err("Admin already bootstrapped".to_string());
+pub fn set_forbidden_recipient(principal: Principal, forbidden: bool) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can set forbidden recipients".to_string());
     }
-    ROLES.with(|roles| {
-        roles.borrow_mut().insert(admin, Role::Admin);
+    FORBIDDEN_RECIPIENTS.with(|f| {
+        let mut f = f.borrow_mut();
+        if forbidden {
+            f.insert(principal);
+        } else {
+            f.remove(&principal);
+        }
     });
-    BOOTSTRAPPED.with(|b| *b.borrow_mut() = true);
-    Ok("Admin bootstrapped".to_string())
+    record_event(None, EventKind::ConfigChanged { setting: "forbidden_recipients".to_string() });
+    Ok("Forbidden recipients updated".to_string())
 }
 
+/// Query principals that can't receive shares, besides the anonymous principal.
 #[query]
-pub fn get_my_role() -> Role {
-    get_role(&caller())
+pub fn get_forbidden_recipients() -> Vec<Principal> {
+    let mut principals: Vec<Principal> = FORBIDDEN_RECIPIENTS.with(|f| f.borrow().iter().cloned().collect());
+    principals.sort();
+    principals
 }
 
-#[query]
-pub fn is_my_kyc_verified() -> bool {
-    is_kyc_verified(&caller())
+/// Admin moves the unlocked shares of a property held by a recoverable principal to `to`.
+#[update]
+pub fn recover_shares(property_id: PropertyId, from: Principal, to: Principal) -> Result<u64, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can recover shares".to_string());
+    }
+    if !RECOVERABLE_PRINCIPALS.with(|r| r.borrow().contains(&from)) {
+        return Err("Principal is not marked as recoverable".to_string());
+    }
+    if from == to {
+        return Err("Source and destination must differ".to_string());
+    }
+    let amount = OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        let amount = unlocked_balance(&own, property_id, from);
+        if amount > 0 {
+            debit_shares(&mut own, property_id, from, amount);
+            credit_shares(&mut own, property_id, to, amount);
+        }
+        amount
+    });
+    if amount == 0 {
+        return Err("No shares to recover".to_string());
+    }
+    record_event(Some(property_id), EventKind::SharesRecovered { from, to, amount });
+    Ok(amount)
 }
 
+/// Admin restricts a property so its shares can only be transferred or sold to approved recipients.
 #[update]
-pub fn update_property_metadata(property_id: PropertyId, metadata: PropertyMetadata, caller: Principal) -> Result<String, String> {
-    if get_role(&caller) != Role::Admin {
-        return Err("Only admin can update property metadata".to_string());
+pub fn set_transfer_restricted(property_id: PropertyId, restricted: bool) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can restrict transfers".to_string());
     }
-    PROPERTIES.with(|props| {
-        let mut props = props.borrow_mut();
-        if let Some(prop) = props.get_mut(&property_id) {
-            prop.metadata = metadata;
-            Ok("Property metadata updated".to_string())
+    if PROPERTIES.with(|props| !props.borrow().contains_key(&property_id)) {
+        return Err("Property not found".to_string());
+    }
+    TRANSFER_RESTRICTED.with(|r| {
+        let mut r = r.borrow_mut();
+        if restricted {
+            r.insert(property_id);
         } else {
-            Err("Property not found".to_string())
+            r.remove(&property_id);
         }
-    })
+    });
+    record_event(Some(property_id), EventKind::ConfigChanged { setting: "transfer_restricted".to_string() });
+    Ok("Transfer restriction updated".to_string())
+}
+
+/// Query whether a property's transfers are restricted to approved recipients.
+#[query]
+pub fn is_transfer_restricted(property_id: PropertyId) -> bool {
+    TRANSFER_RESTRICTED.with(|r| r.borrow().contains(&property_id))
 }
 
+/// Admin approves or revokes a recipient for a restricted property.
 #[update]
-pub fn update_property_status(property_id: PropertyId, status: PropertyStatus, caller: Principal) -> Result<String, String> {
-    if get_role(&caller) != Role::Admin {
-        return Err("Only admin can update property status".to_string());
+pub fn set_approved_recipient(property_id: PropertyId, user: Principal, approved: bool) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can approve recipients".to_string());
     }
-    PROPERTIES.with(|props| {
-        let mut props = props.borrow_mut();
-        if let Some(prop) = props.get_mut(&property_id) {
-            prop.status = status;
-            Ok("Property status updated".to_string())
+    if PROPERTIES.with(|props| !props.borrow().contains_key(&property_id)) {
+        return Err("Property not found".to_string());
+    }
+    APPROVED_RECIPIENTS.with(|a| {
+        let mut a = a.borrow_mut();
+        if approved {
+            a.insert((property_id, user));
         } else {
-            Err("Property not found".to_string())
+            a.remove(&(property_id, user));
         }
-    })
+    });
+    record_event(Some(property_id), EventKind::RecipientApproval { user, approved });
+    Ok("Recipient approval updated".to_string())
 }
 
-// Update register_property to include metadata and status
-#[update]
-pub fn register_property(name: String, total_shares: u64, metadata: PropertyMetadata) -> Property {
-    let property = PROPERTIES.with(|props| {
-        let mut props = props.borrow_mut();
-        let id = NEXT_PROPERTY_ID.with(|id| {
-            let mut id = id.borrow_mut();
-            let curr = *id;
-            *id += 1;
-            curr
-        });
-        let property = Property {
-            id,
-            name,
-            total_shares,
-            shares_available: total_shares,
-            metadata,
-            status: PropertyStatus::Active,
-        };
-        props.insert(id, property.clone());
-        property
+/// Query the approved recipients for a property.
+#[query]
+pub fn get_approved_recipients(property_id: PropertyId) -> Vec<Principal> {
+    let mut recipients: Vec<Principal> = APPROVED_RECIPIENTS.with(|a| {
+        a.borrow().iter().filter(|(pid, _)| *pid == property_id).map(|(_, user)| *user).collect()
     });
-    property
+    recipients.sort();
+    recipients
 }
 
+/// Lock some of the caller's shares as collateral on behalf of `locker`. Locked shares
+/// still earn income and vote, but can't be transferred or listed until the locker releases them.
 #[update]
-pub fn issue_shares(property_id: PropertyId, to: Principal, amount: u64) -> Result<String, String> {
-    // Check property exists and has enough shares
-    let mut success = false;
-    PROPERTIES.with(|props| {
-        let mut props = props.borrow_mut();
-        if let Some(prop) = props.get_mut(&property_id) {
-            if prop.shares_available >= amount {
-                prop.shares_available -= amount;
-                OWNERSHIP.with(|own| {
-                    let mut own = own.borrow_mut();
-                    *own.entry((property_id, to)).or_insert(0) += amount;
-                });
-                success = true;
-            }
-        }
-    });
-    if success {
-        Ok("Shares issued".to_string())
-    } else {
-        Err("Not enough shares or property not found".to_string())
+pub fn lock_shares(property_id: PropertyId, amount: u64, locker: Principal) -> Result<String, String> {
+    let owner = authenticated_caller()?;
+    if amount == 0 {
+        return Err("Lock amount must be positive".to_string());
+    }
+    if OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, owner)) < amount {
+        return Err("Not enough unlocked shares".to_string());
     }
+    LOCKED.with(|locked| {
+        *locked.borrow_mut().entry((property_id, owner, locker)).or_insert(0) += amount;
+    });
+    record_event(Some(property_id), EventKind::SharesLocked { owner, locker, amount });
+    Ok("Shares locked".to_string())
 }
 
-#[query]
-pub fn get_property(property_id: PropertyId) -> Option<Property> {
-    PROPERTIES.with(|props| props.borrow().get(&property_id).cloned())
+/// Release shares the caller locked for `owner`. Only the locker can unlock.
+#[update]
+pub fn unlock_shares(property_id: PropertyId, owner: Principal, amount: u64) -> Result<String, String> {
+    let locker = authenticated_caller()?;
+    LOCKED.with(|locked| {
+        let mut locked = locked.borrow_mut();
+        let key = (property_id, owner, locker);
+        let current = locked.get(&key).cloned().unwrap_or(0);
+        if current < amount {
+            return Err("Not enough shares locked by caller".to_string());
+        }
+        if current == amount {
+            locked.remove(&key);
+        } else {
+            locked.insert(key, current - amount);
+        }
+        Ok(())
+    })?;
+    record_event(Some(property_id), EventKind::SharesUnlocked { owner, locker, amount });
+    Ok("Shares unlocked".to_string())
 }
 
+/// Query shares of a user's balance that are locked, across all lockers.
 #[query]
-pub fn get_ownership(property_id: PropertyId, user: Principal) -> u64 {
-    OWNERSHIP.with(|own| own.borrow().get(&(property_id, user)).cloned().unwrap_or(0))
+pub fn get_locked_shares(property_id: PropertyId, user: Principal) -> u64 {
+    locked_shares(property_id, user)
 }
 
-/// Admin deposits rental income for a property. Distributes to all current owners proportionally.
+/// Set aside some of the caller's shares for `to`, who can claim them once they are allowed to
+/// receive the property's shares. Until then the shares stay locked in the caller's balance;
+/// the caller can take them back if the recipient hasn't claimed them in time.
 #[update]
-pub fn deposit_rental_income(property_id: PropertyId, amount: u64) -> Result<String, String> {
-    // Track total income
-    RENTAL_INCOME.with(|ri| {
-        let mut ri = ri.borrow_mut();
-        *ri.entry(property_id).or_insert(0) += amount;
+pub fn create_pending_transfer(property_id: PropertyId, to: Principal, amount: u64) -> Result<u64, String> {
+    let from = authenticated_caller()?;
+    if amount == 0 {
+        return Err("Transfer amount must be positive".to_string());
+    }
+    if from == to {
+        return Err("Can't transfer to yourself".to_string());
+    }
+    if to == Principal::anonymous() {
+        return Err("Anonymous principal can't hold shares".to_string());
+    }
+    if OWNERSHIP.with(|own| unlocked_balance(&own.borrow(), property_id, from)) < amount {
+        return Err("Not enough unlocked shares".to_string());
+    }
+    LOCKED.with(|locked| {
+        *locked.borrow_mut().entry((property_id, from, canister_id())).or_insert(0) += amount;
     });
-    // Distribute to owners
-    let mut total_shares = 0;
-    PROPERTIES.with(|props| {
-        if let Some(prop) = props.borrow().get(&property_id) {
-            total_shares = prop.total_shares;
-        }
+    let id = NEXT_PENDING_TRANSFER_ID.with(|next| {
+        let mut next = next.borrow_mut();
+        let curr = *next;
+        *next += 1;
+        curr
     });
-    if total_shares == 0 {
-        return Err("Property not found or has no shares".to_string());
+    PENDING_TRANSFERS.with(|p| {
+        p.borrow_mut().insert(id, PendingTransfer { id, property_id, from, to, amount, created_at: now() });
+    });
+    record_event(Some(property_id), EventKind::PendingTransferCreated { transfer_id: id, from, to, amount });
+    Ok(id)
+}
+
+/// Recipient claims a pending transfer, once they are allowed to receive the property's shares.
+#[update]
+pub fn claim_pending_transfer(transfer_id: u64) -> Result<String, String> {
+    authenticated_caller()?;
+    let transfer = PENDING_TRANSFERS.with(|p| p.borrow().get(&transfer_id).cloned());
+    let transfer = transfer.ok_or_else(|| "Pending transfer not found".to_string())?;
+    if transfer.to != caller() {
+        return Err("Only the recipient can claim this transfer".to_string());
     }
-    // Find all owners
-    OWNERSHIP.with(|own| {
-        let own = own.borrow();
-        for ((pid, user), shares) in own.iter() {
-            if *pid == property_id && *shares > 0 {
-                let user_income = amount * shares / total_shares;
-                UNCLAIMED_INCOME.with(|ui| {
-                    let mut ui = ui.borrow_mut();
-                    *ui.entry((property_id, user.clone())).or_insert(0) += user_income;
-                });
-            }
+    if !recipient_allowed(transfer.property_id, transfer.to) {
+        return Err("Recipient is not approved for this property yet".to_string());
+    }
+    release_pending_lock(&transfer);
+    let PendingTransfer { property_id, from, to, amount, .. } = transfer;
+    let moved = OWNERSHIP.with(|own| {
+        let mut own = own.borrow_mut();
+        let moved = debit_shares(&mut own, property_id, from, amount);
+        if moved {
+            credit_shares(&mut own, property_id, to, amount);
         }
+        moved
     });
-    Ok("Rental income distributed".to_string())
+    if !moved {
+        // Keep the transfer and its lock, so the sender can still reclaim what is left
+        LOCKED.with(|locked| *locked.borrow_mut().entry((property_id, from, canister_id())).or_insert(0) += amount);
+        return Err("Sender no longer holds the pending shares".to_string());
+    }
+    PENDING_TRANSFERS.with(|p| p.borrow_mut().remove(&transfer_id));
+    record_event(Some(property_id), EventKind::SharesTransferred { from, to, amount });
+    Ok("Pending transfer claimed".to_string())
 }
 
-/// User claims their unclaimed rental income for a property.
+/// Sender takes back a pending transfer the recipient hasn't claimed within the timeout.
 #[update]
-pub fn claim_income(property_id: PropertyId, user: Principal) -> u64 {
-    let mut claimed = 0;
-    UNCLAIMED_INCOME.with(|ui| {
-        let mut ui = ui.borrow_mut();
-        claimed = ui.remove(&(property_id, user)).unwrap_or(0);
-    });
-    claimed
+pub fn reclaim_pending_transfer(transfer_id: u64) -> Result<String, String> {
+    authenticated_caller()?;
+    let transfer = PENDING_TRANSFERS.with(|p| p.borrow().get(&transfer_id).cloned());
+    let transfer = transfer.ok_or_else(|| "Pending transfer not found".to_string())?;
+    if transfer.from != caller() {
+        return Err("Only the sender can reclaim this transfer".to_string());
+    }
+    if now() < transfer.created_at.saturating_add(PENDING_TRANSFER_TIMEOUT_NANOS) {
+        return Err("Pending transfer can't be reclaimed yet".to_string());
+    }
+    PENDING_TRANSFERS.with(|p| p.borrow_mut().remove(&transfer_id));
+    release_pending_lock(&transfer);
+    record_event(Some(transfer.property_id), EventKind::PendingTransferReclaimed { transfer_id, from: transfer.from });
+    Ok("Pending transfer reclaimed".to_string())
 }
 
-/// Query unclaimed rental income for a user and property.
+/// Query pending transfers sent or received by a user, oldest first.
 #[query]
-pub fn get_unclaimed_income(property_id: PropertyId, user: Principal) -> u64 {
-    UNCLAIMED_INCOME.with(|ui| ui.borrow().get(&(property_id, user)).cloned().unwrap_or(0))
+pub fn get_pending_transfers(user: Principal) -> Vec<PendingTransfer> {
+    PENDING_TRANSFERS.with(|p| p.borrow().values().filter(|t| t.from == user || t.to == user).cloned().collect())
 }
 
-/// List shares for sale on the marketplace
-#[update]
-pub fn list_shares_for_sale(property_id: PropertyId, seller: Principal, amount: u64, price_per_share: u64) -> Result<String, String> {
-    // Check seller owns enough shares
-    let owned = OWNERSHIP.with(|own| own.borrow().get(&(property_id, seller)).cloned().unwrap_or(0));
-    if owned < amount {
-        return Err("Not enough shares to list".to_string());
-    }
-    // Add listing
-    MARKETPLACE.with(|mp| {
-        mp.borrow_mut().push(Listing {
-            property_id,
-            seller,
-            amount,
-            price_per_share,
-        });
-    });
-    Ok("Shares listed for sale".to_string())
+/// Get all marketplace listings in ascending id order. The flag is set when the result was cut
+/// short to fit the response limit.
+#[query]
+pub fn get_marketplace_listings() -> (Vec<Listing>, bool) {
+    // Listings restored after a failed payment go to the back, so the Vec isn't always in id order
+    let mut listings = MARKETPLACE.with(|mp| mp.borrow().clone());
+    listings.sort_by_key(|l| l.id);
+    cap_response(listings.into_iter())
 }
 
-/// Buy shares from the marketplace
-#[update]
-pub fn buy_shares(property_id: PropertyId, seller: Principal, buyer: Principal, amount: u64) -> Result<String, String> {
-    let mut found = false;
+/// Query active listings grouped by property, ordered by property id and paginated over
+/// properties that have at least one listing. Listings within a group are in id order.
+#[query]
+pub fn get_marketplace_grouped(offset: u64, limit: u64) -> Vec<(PropertyId, Vec<Listing>)> {
+    let mut grouped: BTreeMap<PropertyId, Vec<Listing>> = BTreeMap::new();
     MARKETPLACE.with(|mp| {
-        let mut mp = mp.borrow_mut();
-        if let Some(pos) = mp.iter().position(|l| l.property_id == property_id && l.seller == seller && l.amount >= amount) {
-            let price_per_share = mp[pos].price_per_share;
-            // Transfer shares
-            OWNERSHIP.with(|own| {
-                let mut own = own.borrow_mut();
-                // Remove from seller
-                let seller_shares = own.entry((property_id, seller)).or_insert(0);
-                if *seller_shares < amount {
-                    return;
-                }
-                *seller_shares -= amount;
-                // Add to buyer
-                *own.entry((property_id, buyer)).or_insert(0) += amount;
-            });
-            // Reduce or remove listing
-            if mp[pos].amount == amount {
-                mp.remove(pos);
-            } else {
-                mp[pos].amount -= amount;
-            }
-            found = true;
+        for listing in mp.borrow().iter() {
+            grouped.entry(listing.property_id).or_default().push(listing.clone());
         }
     });
-    if found {
-        Ok("Shares bought successfully".to_string())
-    } else {
-        Err("Listing not found or insufficient shares".to_string())
-    }
+    grouped
+        .into_iter()
+        .skip(offset as usize)
+        .take(limit as usize)
+        .map(|(pid, mut listings)| {
+            listings.sort_by_key(|l| l.id);
+            (pid, listings)
+        })
+        .collect()
 }
 
-/// Transfer shares directly between users
-#[update]
-pub fn transfer_shares(property_id: PropertyId, from: Principal, to: Principal, amount: u64) -> Result<String, String> {
+/// Query how many active listings a property has.
+#[query]
+pub fn get_listing_count(property_id: PropertyId) -> u64 {
+    MARKETPLACE.with(|mp| mp.borrow().iter().filter(|l| l.property_id == property_id).count() as u64)
+}
+
+/// Query the total shares on offer across a property's active listings.
+#[query]
+pub fn get_total_shares_listed(property_id: PropertyId) -> u64 {
+    MARKETPLACE.with(|mp| mp.borrow().iter().filter(|l| l.property_id == property_id).map(|l| l.amount).sum())
+}
+
+/// Query how legacy textual user ids map to principals, for migrating records kept outside the
+/// canister. Results line up with `ids`; ids that don't parse carry the reason instead.
+#[query]
+pub fn parse_user_ids(ids: Vec<UserId>) -> Vec<Result<Principal, String>> {
+    ids.iter().map(user_id_to_principal).collect()
+}
+
+/// Query ids of properties whose share accounting doesn't add up, i.e. where the holders'
+/// balances plus the unissued shares differ from the total. Empty when everything balances.
+#[query]
+pub fn find_share_accounting_errors() -> Vec<PropertyId> {
+    let mut held: HashMap<PropertyId, u128> = HashMap::new();
     OWNERSHIP.with(|own| {
-        let mut own = own.borrow_mut();
-        let from_shares = own.entry((property_id, from)).or_insert(0);
-        if *from_shares < amount {
-            return Err("Not enough shares to transfer".to_string());
+        for ((pid, _, _), shares) in own.borrow().iter() {
+            *held.entry(*pid).or_insert(0) += *shares as u128;
         }
-        *from_shares -= amount;
-        *own.entry((property_id, to)).or_insert(0) += amount;
-        Ok("Shares transferred".to_string())
-    })
+    });
+    let mut broken: Vec<PropertyId> = PROPERTIES.with(|props| {
+        props
+            .borrow()
+            .values()
+            .filter(|p| {
+                held.get(&p.id).cloned().unwrap_or(0) + p.shares_available as u128 + reserved_shares(p.id) as u128
+                    != p.total_shares as u128
+            })
+            .map(|p| p.id)
+            .collect()
+    });
+    broken.sort();
+    broken
 }
 
-/// Get all marketplace listings
+/// Query ids of listings the seller's unlocked balance no longer backs. A seller's listings
+/// for a property are counted against that balance in id order, so once it is used up every
+/// later listing is reported.
 #[query]
-pub fn get_marketplace_listings() -> Vec<Listing> {
-    MARKETPLACE.with(|mp| mp.borrow().clone())
+pub fn find_invalid_listings() -> Vec<u64> {
+    let mut listings = MARKETPLACE.with(|mp| mp.borrow().clone());
+    listings.sort_by_key(|l| l.id);
+    let mut committed: HashMap<(PropertyId, Principal), u64> = HashMap::new();
+    OWNERSHIP.with(|own| {
+        let own = own.borrow();
+        listings
+            .iter()
+            .filter(|l| {
+                let balance = unlocked_balance(&own, l.property_id, l.seller);
+                let used = committed.entry((l.property_id, l.seller)).or_insert(0);
+                *used = used.saturating_add(l.amount);
+                *used > balance
+            })
+            .map(|l| l.id)
+            .collect()
+    })
 }
 
 #[update]
-pub fn submit_proposal(property_id: PropertyId, description: String) -> Proposal {
-    let proposer = caller();
+pub fn submit_proposal(property_id: PropertyId, description: String) -> Result<Proposal, String> {
+    let proposer = authenticated_caller()?;
     let id = NEXT_PROPOSAL_ID.with(|next| {
         let mut next = next.borrow_mut();
         let curr = *next;
@@ -379,13 +4631,14 @@ pub fn submit_proposal(property_id: PropertyId, description: String) -> Proposal
     PROPOSALS.with(|props| {
         props.borrow_mut().insert(id, proposal.clone());
     });
-    proposal
+    record_event(Some(property_id), EventKind::ProposalSubmitted { proposal_id: id });
+    Ok(proposal)
 }
 
 #[update]
 pub fn vote_on_proposal(proposal_id: u64, vote: bool) -> Result<String, String> {
-    let voter = caller();
-    let mut found = false;
+    let voter = authenticated_caller()?;
+    let mut found = None;
     PROPOSALS.with(|props| {
         let mut props = props.borrow_mut();
         if let Some(prop) = props.get_mut(&proposal_id) {
@@ -396,7 +4649,7 @@ pub fn vote_on_proposal(proposal_id: u64, vote: bool) -> Result<String, String>
                 return;
             }
             // Get voter's shares for the property
-            let shares = OWNERSHIP.with(|own| own.borrow().get(&(prop.property_id, voter)).cloned().unwrap_or(0));
+            let shares = OWNERSHIP.with(|own| holding(&own.borrow(), prop.property_id, voter));
             if shares == 0 {
                 return;
             }
@@ -406,10 +4659,11 @@ pub fn vote_on_proposal(proposal_id: u64, vote: bool) -> Result<String, String>
             } else {
                 prop.no_votes += shares;
             }
-            found = true;
+            found = Some(prop.property_id);
         }
     });
-    if found {
+    if let Some(property_id) = found {
+        record_event(Some(property_id), EventKind::ProposalVoted { proposal_id, vote });
         Ok("Vote recorded".to_string())
     } else {
         Err("Proposal not found, not open, already voted, or no shares".to_string())
@@ -418,7 +4672,9 @@ pub fn vote_on_proposal(proposal_id: u64, vote: bool) -> Result<String, String>
 
 #[update]
 pub fn execute_proposal(proposal_id: u64) -> Result<String, String> {
+    authenticated_caller()?;
     let mut result = Err("Proposal not found or not open".to_string());
+    let mut executed = None;
     PROPOSALS.with(|props| {
         let mut props = props.borrow_mut();
         if let Some(prop) = props.get_mut(&proposal_id) {
@@ -435,8 +4691,12 @@ pub fn execute_proposal(proposal_id: u64) -> Result<String, String> {
                 prop.status = ProposalStatus::Rejected;
                 result = Ok("Proposal rejected".to_string());
             }
+            executed = Some((prop.property_id, prop.status == ProposalStatus::Executed));
         }
     });
+    if let Some((property_id, approved)) = executed {
+        record_event(Some(property_id), EventKind::ProposalExecuted { proposal_id, approved });
+    }
     result
 }
 
@@ -446,3 +4706,329 @@ pub fn get_proposals(property_id: PropertyId) -> Vec<Proposal> {
         props.borrow().values().filter(|p| p.property_id == property_id).cloned().collect()
     })
 }
+
+/// Admin exports the full state of the core collections.
+#[query]
+pub fn export_state() -> Result<StateDump, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can export state".to_string());
+    }
+    let mut properties: Vec<Property> = PROPERTIES.with(|props| props.borrow().values().cloned().collect());
+    properties.sort_by_key(|p| p.id);
+    let mut ownership: Vec<(PropertyId, Principal, Subaccount, u64)> =
+        OWNERSHIP.with(|own| own.borrow().iter().map(|((pid, user, sub), shares)| (*pid, *user, sub.clone(), *shares)).collect());
+    ownership.sort();
+    let mut rental_income: Vec<(PropertyId, u64)> =
+        RENTAL_INCOME.with(|ri| ri.borrow().iter().map(|(pid, amount)| (*pid, *amount)).collect());
+    rental_income.sort_by_key(|(pid, _)| *pid);
+    let mut unclaimed_income: Vec<(PropertyId, Principal, Subaccount, u64)> =
+        UNCLAIMED_INCOME.with(|ui| ui.borrow().iter().map(|((pid, user, sub), amount)| (*pid, *user, sub.clone(), *amount)).collect());
+    unclaimed_income.sort();
+    let mut claimed_income: Vec<(PropertyId, u64)> =
+        CLAIMED_INCOME.with(|ci| ci.borrow().iter().map(|(pid, amount)| (*pid, *amount)).collect());
+    claimed_income.sort_by_key(|(pid, _)| *pid);
+    let mut proposals: Vec<Proposal> = PROPOSALS.with(|props| props.borrow().values().cloned().collect());
+    proposals.sort_by_key(|p| p.id);
+    let mut locks: Vec<(PropertyId, Principal, Principal, u64)> = LOCKED.with(|locked| {
+        locked
+            .borrow()
+            .iter()
+            .filter(|((_, _, locker), _)| *locker != canister_id())
+            .map(|((pid, owner, locker), shares)| (*pid, *owner, *locker, *shares))
+            .collect()
+    });
+    locks.sort();
+    let mut transfer_restricted: Vec<PropertyId> = TRANSFER_RESTRICTED.with(|tr| tr.borrow().iter().cloned().collect());
+    transfer_restricted.sort();
+    let mut approved_recipients: Vec<(PropertyId, Principal)> = APPROVED_RECIPIENTS.with(|ar| ar.borrow().iter().cloned().collect());
+    approved_recipients.sort();
+    Ok(StateDump {
+        properties,
+        ownership,
+        rental_income,
+        unclaimed_income,
+        claimed_income,
+        marketplace: MARKETPLACE.with(|mp| mp.borrow().clone()),
+        proposals,
+        reservations: RESERVATIONS.with(|r| r.borrow().values().cloned().collect()),
+        locks,
+        transfer_restricted,
+        approved_recipients,
+        next_property_id: NEXT_PROPERTY_ID.with(|id| *id.borrow()),
+        next_listing_id: NEXT_LISTING_ID.with(|id| *id.borrow()),
+        next_proposal_id: NEXT_PROPOSAL_ID.with(|id| *id.borrow()),
+        next_reservation_id: NEXT_RESERVATION_ID.with(|id| *id.borrow()),
+    })
+}
+
+/// Check that a state dump is internally consistent before it replaces live state.
+fn validate_state_dump(dump: &StateDump) -> Result<(), String> {
+    let mut issued: HashMap<PropertyId, u128> = HashMap::new();
+    for prop in &dump.properties {
+        if prop.id == 0 || prop.id >= dump.next_property_id {
+            return Err(format!("Property {} is outside the id range", prop.id));
+        }
+        if issued.insert(prop.id, 0).is_some() {
+            return Err(format!("Duplicate property {}", prop.id));
+        }
+        if prop.shares_available > prop.total_shares {
+            return Err(format!("Property {} has more shares available than total", prop.id));
+        }
+    }
+    let mut owners = HashSet::new();
+    for (pid, user, sub, shares) in &dump.ownership {
+        if sub.len() != 32 || !owners.insert((*pid, *user, sub.clone())) {
+            return Err(format!("Invalid or duplicate ownership entry for property {}", pid));
+        }
+        match issued.get_mut(pid) {
+            Some(total) => *total += *shares as u128,
+            None => return Err(format!("Ownership references unknown property {}", pid)),
+        }
+    }
+    let mut reservation_ids = HashSet::new();
+    let mut reserved: HashMap<PropertyId, u128> = HashMap::new();
+    for reservation in &dump.reservations {
+        if reservation.id == 0 || reservation.id >= dump.next_reservation_id || !reservation_ids.insert(reservation.id) {
+            return Err(format!("Reservation {} has an invalid or duplicate id", reservation.id));
+        }
+        if !issued.contains_key(&reservation.property_id) {
+            return Err(format!("Reservation {} references unknown property {}", reservation.id, reservation.property_id));
+        }
+        *reserved.entry(reservation.property_id).or_insert(0) += reservation.amount as u128;
+    }
+    for prop in &dump.properties {
+        let held = issued.get(&prop.id).cloned().unwrap_or(0);
+        let reserved = reserved.get(&prop.id).cloned().unwrap_or(0);
+        if held + reserved + prop.shares_available as u128 != prop.total_shares as u128 {
+            return Err(format!("Ownership of property {} does not match issued shares", prop.id));
+        }
+    }
+    for (pid, _) in dump.rental_income.iter().chain(dump.claimed_income.iter()) {
+        if !issued.contains_key(pid) {
+            return Err(format!("Rental income references unknown property {}", pid));
+        }
+    }
+    let mut income_keys = HashSet::new();
+    for (pid, user, sub, _) in &dump.unclaimed_income {
+        if !issued.contains_key(pid) {
+            return Err(format!("Unclaimed income references unknown property {}", pid));
+        }
+        if sub.len() != 32 || !income_keys.insert((*pid, *user, sub.clone())) {
+            return Err(format!("Invalid or duplicate unclaimed income entry for property {}", pid));
+        }
+    }
+    // Locks come out of the default subaccount, so together they can't exceed its balance
+    let default = default_subaccount();
+    let balances: HashMap<(PropertyId, Principal), u64> = dump
+        .ownership
+        .iter()
+        .filter(|(_, _, sub, _)| *sub == default)
+        .map(|(pid, user, _, s)| ((*pid, *user), *s))
+        .collect();
+    let mut lock_keys = HashSet::new();
+    let mut committed: HashMap<(PropertyId, Principal), u128> = HashMap::new();
+    for (pid, owner, locker, shares) in &dump.locks {
+        if !issued.contains_key(pid) {
+            return Err(format!("Lock references unknown property {}", pid));
+        }
+        if *shares == 0 || !lock_keys.insert((*pid, *owner, *locker)) {
+            return Err(format!("Lock on property {} is empty or duplicated", pid));
+        }
+        *committed.entry((*pid, *owner)).or_insert(0) += *shares as u128;
+    }
+    for (key, total) in committed {
+        if total > balances.get(&key).cloned().unwrap_or(0) as u128 {
+            return Err(format!("Locked shares of property {} exceed the holder's balance", key.0));
+        }
+    }
+    let mut listing_ids = HashSet::new();
+    for listing in &dump.marketplace {
+        if listing.id == 0 || listing.id >= dump.next_listing_id || !listing_ids.insert(listing.id) {
+            return Err(format!("Listing {} has an invalid or duplicate id", listing.id));
+        }
+        if !issued.contains_key(&listing.property_id) {
+            return Err(format!("Listing {} references unknown property {}", listing.id, listing.property_id));
+        }
+        if listing.amount == 0 || listing.price_per_share == 0 {
+            return Err(format!("Listing {} has a zero amount or price", listing.id));
+        }
+    }
+    for pid in dump.transfer_restricted.iter().chain(dump.approved_recipients.iter().map(|(pid, _)| pid)) {
+        if !issued.contains_key(pid) {
+            return Err(format!("Transfer restriction references unknown property {}", pid));
+        }
+    }
+    let mut proposal_ids = HashSet::new();
+    for proposal in &dump.proposals {
+        if proposal.id == 0 || proposal.id >= dump.next_proposal_id || !proposal_ids.insert(proposal.id) {
+            return Err(format!("Proposal {} has an invalid or duplicate id", proposal.id));
+        }
+    }
+    Ok(())
+}
+
+/// Admin replaces the core collections with a previously exported state dump. Pending transfers
+/// and bids aren't part of a dump and wouldn't match the imported balances, so they are dropped,
+/// as is every other record keyed by property id (deposit history, trades, snapshots, trading
+/// settings) and any pending multi-sig operation. Holding periods restart at the import.
+#[update]
+pub fn import_state(dump: StateDump) -> Result<String, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can import state".to_string());
+    }
+    validate_state_dump(&dump)?;
+    NAME_INDEX.with(|index| {
+        let mut index = index.borrow_mut();
+        index.clear();
+        for prop in &dump.properties {
+            let id = index.entry(prop.name.clone()).or_insert(prop.id);
+            *id = (*id).min(prop.id);
+        }
+    });
+    PROPERTIES.with(|props| {
+        *props.borrow_mut() = dump.properties.into_iter().map(|p| (p.id, p)).collect();
+    });
+    for pid in PROPERTIES.with(|props| props.borrow().keys().cloned().collect::<Vec<_>>()) {
+        assert_supply_invariant(pid);
+    }
+    OWNERSHIP.with(|own| {
+        *own.borrow_mut() = dump.ownership.into_iter().map(|(pid, user, sub, shares)| ((pid, user, sub), shares)).collect();
+    });
+    let at = now();
+    HOLDING_SINCE.with(|hs| {
+        *hs.borrow_mut() = OWNERSHIP.with(|own| own.borrow().keys().map(|(pid, user, _)| ((*pid, *user), at)).collect());
+    });
+    LOCKED.with(|locked| {
+        *locked.borrow_mut() = dump.locks.into_iter().map(|(pid, owner, locker, shares)| ((pid, owner, locker), shares)).collect();
+    });
+    PENDING_TRANSFERS.with(|p| p.borrow_mut().clear());
+    BIDS.with(|bids| bids.borrow_mut().clear());
+    // Anything else keyed by the old property ids would attach to whatever the import reuses them for
+    DEPOSITS.with(|d| d.borrow_mut().clear());
+    INCOME_DUST.with(|d| d.borrow_mut().clear());
+    VALUATIONS.with(|v| v.borrow_mut().clear());
+    MIN_DEPOSITS.with(|m| m.borrow_mut().clear());
+    TRADES.with(|t| t.borrow_mut().clear());
+    SNAPSHOTS.with(|s| s.borrow_mut().clear());
+    SELLER_PROCEEDS.with(|sp| sp.borrow_mut().clear());
+    TRADING_WINDOWS.with(|w| w.borrow_mut().clear());
+    TRANSFER_RESTRICTED.with(|tr| *tr.borrow_mut() = dump.transfer_restricted.into_iter().collect());
+    APPROVED_RECIPIENTS.with(|ar| *ar.borrow_mut() = dump.approved_recipients.into_iter().collect());
+    PENDING_OPERATIONS.with(|ops| ops.borrow_mut().clear());
+    RENTAL_INCOME.with(|ri| {
+        *ri.borrow_mut() = dump.rental_income.into_iter().collect();
+    });
+    UNCLAIMED_INCOME.with(|ui| {
+        *ui.borrow_mut() = dump.unclaimed_income.into_iter().map(|(pid, user, sub, amount)| ((pid, user, sub), amount)).collect();
+    });
+    CLAIMED_INCOME.with(|ci| {
+        *ci.borrow_mut() = dump.claimed_income.into_iter().collect();
+    });
+    MARKETPLACE.with(|mp| *mp.borrow_mut() = dump.marketplace);
+    PROPOSALS.with(|props| {
+        *props.borrow_mut() = dump.proposals.into_iter().map(|p| (p.id, p)).collect();
+    });
+    RESERVATIONS.with(|r| {
+        *r.borrow_mut() = dump.reservations.into_iter().map(|res| (res.id, res)).collect();
+    });
+    NEXT_PROPERTY_ID.with(|id| *id.borrow_mut() = dump.next_property_id);
+    NEXT_LISTING_ID.with(|id| *id.borrow_mut() = dump.next_listing_id);
+    NEXT_PROPOSAL_ID.with(|id| *id.borrow_mut() = dump.next_proposal_id);
+    NEXT_RESERVATION_ID.with(|id| *id.borrow_mut() = dump.next_reservation_id);
+    record_event(None, EventKind::StateImported);
+    Ok("State imported".to_string())
+}
+
+/// Admin pages through the audit log, oldest first.
+#[query]
+pub fn get_events(offset: u64, limit: u64) -> Result<Vec<Event>, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can read the audit log".to_string());
+    }
+    Ok(EVENTS.with(|events| events.borrow().iter().skip(offset as usize).take(limit as usize).cloned().collect()))
+}
+
+/// Admin pages through the audit log entries made by a given caller, oldest first.
+#[query]
+pub fn get_events_by_caller(principal: Principal, offset: u64, limit: u64) -> Result<Vec<Event>, String> {
+    if get_role(&caller()) != Role::Admin {
+        return Err("Only admin can read the audit log".to_string());
+    }
+    Ok(EVENTS.with(|events| {
+        events
+            .borrow()
+            .iter()
+            .filter(|e| e.caller == principal)
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    }))
+}
+
+/// Query events that affected the caller, newest first.
+#[query]
+pub fn get_my_events(offset: u64, limit: u64) -> Vec<Event> {
+    let ids = USER_EVENTS.with(|index| index.borrow().get(&caller()).cloned().unwrap_or_default());
+    EVENTS.with(|events| {
+        let events = events.borrow();
+        ids.iter()
+            .rev()
+            .skip(offset as usize)
+            .take(limit as usize)
+            .filter_map(|id| events.get((*id - 1) as usize).cloned())
+            .collect()
+    })
+}
+
+/// Query a page of events that changed a user's balance of a property, from issuance,
+/// transfers, trades, recovery or migration, with timestamps in `[from_ts, to_ts)`, oldest
+/// first. Users can read their own history; admins anyone's.
+#[query]
+pub fn get_ownership_history_range(
+    property_id: PropertyId,
+    user: Principal,
+    from_ts: u64,
+    to_ts: u64,
+    offset: u64,
+    limit: u64,
+) -> Result<Vec<Event>, String> {
+    let requester = caller();
+    if requester != user && get_role(&requester) != Role::Admin {
+        return Err("Only the user or an admin can read this history".to_string());
+    }
+    let ids = USER_EVENTS.with(|index| index.borrow().get(&user).cloned().unwrap_or_default());
+    Ok(EVENTS.with(|events| {
+        let events = events.borrow();
+        ids.iter()
+            .filter_map(|id| events.get((*id - 1) as usize))
+            .filter(|e| e.property_id == Some(property_id) && e.timestamp >= from_ts && e.timestamp < to_ts)
+            .filter(|e| {
+                matches!(
+                    e.kind,
+                    EventKind::SharesIssued { .. }
+                        | EventKind::SharesTransferred { .. }
+                        | EventKind::SharesBought { .. }
+                        | EventKind::SharesRecovered { .. }
+                        | EventKind::UserMigrated { .. }
+                )
+            })
+            .skip(offset as usize)
+            .take(limit as usize)
+            .cloned()
+            .collect()
+    }))
+}
+
+/// Query the deployed canister version and build information.
+#[query]
+pub fn version() -> VersionInfo {
+    VersionInfo {
+        semver: env!("CARGO_PKG_VERSION").to_string(),
+        build_time: BUILD_TIME.and_then(|t| t.parse().ok()).unwrap_or(0),
+        commit: GIT_COMMIT.unwrap_or("unknown").to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests;
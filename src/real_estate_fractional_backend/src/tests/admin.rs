@@ -0,0 +1,343 @@
+use super::*;
+
+/// Make `BOB` a second admin and require both admins to approve high-value actions.
+fn enable_multi_sig() {
+    set_role(BOB, Role::Admin).unwrap();
+    set_required_approvals(2).unwrap();
+}
+
+#[test]
+fn threshold_cannot_exceed_the_admin_count() {
+    setup();
+    assert!(set_required_approvals(2).is_err());
+    set_role(BOB, Role::Admin).unwrap();
+    assert!(set_required_approvals(2).is_ok());
+}
+
+#[test]
+fn one_admin_cannot_lower_the_threshold_or_mint_approvers() {
+    setup();
+    enable_multi_sig();
+    assert!(set_required_approvals(1).is_err());
+    assert!(set_role(CAROL, Role::Admin).is_err());
+    assert_eq!(get_required_approvals(), 2);
+    assert!(get_role(&CAROL) == Role::User);
+}
+
+#[test]
+fn threshold_and_roles_change_once_approved() {
+    setup();
+    enable_multi_sig();
+    let grant = propose_operation(AdminOperation::SetRole { user: CAROL, role: Role::Admin }).unwrap();
+    assert_eq!(block_on(approve_operation(grant)), Ok("Approval recorded".to_string()));
+    assert!(get_role(&CAROL) == Role::User);
+    as_caller(BOB, || block_on(approve_operation(grant))).unwrap();
+    assert!(get_role(&CAROL) == Role::Admin);
+    let lower = propose_operation(AdminOperation::SetRequiredApprovals { approvals: 1 }).unwrap();
+    block_on(approve_operation(lower)).unwrap();
+    as_caller(CAROL, || block_on(approve_operation(lower))).unwrap();
+    assert_eq!(get_required_approvals(), 1);
+}
+
+#[test]
+fn demoting_an_admin_cannot_strand_the_threshold() {
+    setup();
+    enable_multi_sig();
+    let demote = propose_operation(AdminOperation::SetRole { user: BOB, role: Role::User }).unwrap();
+    block_on(approve_operation(demote)).unwrap();
+    assert!(as_caller(BOB, || block_on(approve_operation(demote))).is_err());
+    assert!(get_role(&BOB) == Role::Admin);
+}
+
+#[test]
+fn snapshot_distribution_needs_approval_under_multi_sig() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 50);
+    let snapshot_id = take_snapshot(pid).unwrap();
+    enable_multi_sig();
+    assert!(distribute_to_snapshot(pid, snapshot_id, 1_000).is_err());
+    assert_eq!(get_unclaimed_income(pid, ALICE), 0);
+    let op = propose_operation(AdminOperation::DistributeToSnapshot { property_id: pid, snapshot_id, amount: 1_000 }).unwrap();
+    block_on(approve_operation(op)).unwrap();
+    as_caller(BOB, || block_on(approve_operation(op))).unwrap();
+    assert_eq!(get_unclaimed_income(pid, ALICE), 500);
+}
+
+#[test]
+fn state_round_trips_through_a_dump() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 30);
+    deposit_rental_income(pid, 1_000).unwrap();
+    list(pid, ALICE, 10, 5);
+    let dump = export_state().unwrap();
+    let empty = StateDump {
+        properties: vec![],
+        ownership: vec![],
+        rental_income: vec![],
+        unclaimed_income: vec![],
+        claimed_income: vec![],
+        marketplace: vec![],
+        proposals: vec![],
+        reservations: vec![],
+        locks: vec![],
+        transfer_restricted: vec![],
+        approved_recipients: vec![],
+        next_property_id: 1,
+        next_listing_id: 1,
+        next_proposal_id: 1,
+        next_reservation_id: 1,
+    };
+    import_state(empty).unwrap();
+    assert!(get_property(pid).is_none());
+    assert_eq!(get_ownership(pid, ALICE), 0);
+    import_state(dump).unwrap();
+    assert_eq!(get_ownership(pid, ALICE), 30);
+    assert_eq!(get_unclaimed_income(pid, ALICE), 300);
+    assert_eq!(get_listing_count(pid), 1);
+    assert_eq!(peek_next_property_id(), pid + 1);
+}
+
+#[test]
+fn state_dump_carries_reservations() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 30);
+    let id = reserve_shares(pid, BOB, 20, 1_000).unwrap();
+    let dump = export_state().unwrap();
+    assert_eq!(dump.reservations.len(), 1);
+    import_state(dump).unwrap();
+    confirm_reservation(id).unwrap();
+    assert_eq!(get_ownership(pid, BOB), 20);
+    assert!(find_share_accounting_errors().is_empty());
+}
+
+#[test]
+fn import_rejects_zero_price_listings_and_over_locked_holders() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let listing = list(pid, ALICE, 5, 10);
+    as_caller(ALICE, || lock_shares(pid, 4, CAROL)).unwrap();
+    let mut free_listing = export_state().unwrap();
+    free_listing.marketplace[0].price_per_share = 0;
+    assert_eq!(import_state(free_listing), Err(format!("Listing {} has a zero amount or price", listing)));
+    let mut over_locked = export_state().unwrap();
+    over_locked.locks[0].3 = 11;
+    assert!(import_state(over_locked).is_err());
+    assert_eq!(get_locked_shares(pid, ALICE), 4);
+}
+
+#[test]
+fn import_forgets_records_keyed_by_replaced_property_ids() {
+    setup();
+    let pid = new_property(3);
+    for holder in [ALICE, BOB, CAROL] {
+        issue(pid, holder, 1);
+    }
+    deposit_rental_income(pid, 10).unwrap();
+    set_min_deposit(pid, Some(5)).unwrap();
+    list(pid, ALICE, 1, 10);
+    buy(pid, BOB, ALICE, 1);
+    let mut dump = export_state().unwrap();
+    dump.properties.clear();
+    dump.ownership.clear();
+    dump.rental_income.clear();
+    dump.unclaimed_income.clear();
+    dump.next_property_id = 1;
+    import_state(dump).unwrap();
+    let reused = new_property(3);
+    assert_eq!(reused, pid);
+    assert_eq!((get_undistributed_dust(reused), get_min_deposit(reused)), (0, None));
+    assert!(get_recent_trades(reused, 10).is_empty());
+    assert_eq!(get_deposit_allocation(reused, 0, ALICE), 0);
+}
+
+#[test]
+fn import_rejects_a_dump_missing_its_reservations() {
+    setup();
+    let pid = new_property(100);
+    reserve_shares(pid, BOB, 20, 1_000).unwrap();
+    let mut dump = export_state().unwrap();
+    dump.reservations.clear();
+    assert_eq!(import_state(dump), Err(format!("Ownership of property {} does not match issued shares", pid)));
+}
+
+#[test]
+fn state_dump_carries_subaccounts_and_their_income() {
+    setup();
+    let pid = new_property(100);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 50, Some(sub(7)))).unwrap();
+    deposit_rental_income(pid, 1_000).unwrap();
+    let dump = export_state().unwrap();
+    assert_eq!(dump.ownership, vec![(pid, ALICE, sub(7), 50)]);
+    assert_eq!(dump.unclaimed_income, vec![(pid, ALICE, sub(7), 500)]);
+    import_state(dump).unwrap();
+    assert_eq!(get_subaccount_balance(pid, ALICE, Some(sub(7))), Ok(50));
+    assert_eq!(get_subaccount_income(pid, ALICE, Some(sub(7))), Ok(500));
+}
+
+#[test]
+fn import_rejects_malformed_subaccount_entries() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let dump = export_state().unwrap();
+    let mut short = dump.clone();
+    short.ownership = vec![(pid, ALICE, vec![7; 31], 10)];
+    assert_eq!(import_state(short), Err(format!("Invalid or duplicate ownership entry for property {}", pid)));
+    let mut duplicate = dump.clone();
+    duplicate.ownership = vec![(pid, ALICE, sub(7), 5), (pid, ALICE, sub(7), 5)];
+    assert!(import_state(duplicate).is_err());
+    let mut income = dump;
+    income.unclaimed_income = vec![(pid, ALICE, vec![], 5)];
+    assert_eq!(import_state(income), Err(format!("Invalid or duplicate unclaimed income entry for property {}", pid)));
+}
+
+#[test]
+fn anonymous_callers_are_rejected() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 5);
+    let unauthorized = Err(RwaError::Unauthorized.to_string());
+    set_mock_caller(Principal::anonymous());
+    assert_eq!(transfer_shares(pid, ALICE, BOB, 1), unauthorized);
+    assert!(block_on(buy_shares(pid, ALICE, 1)).is_err());
+    assert_eq!(claim_income(pid, ALICE), Err(RwaError::Unauthorized.to_string()));
+    assert!(block_on(run_matching(pid)).is_err());
+    assert!(submit_proposal(pid, "Repaint".to_string()).is_err());
+    assert_eq!(execute_proposal(1), unauthorized);
+    assert_eq!(get_ownership(pid, ALICE), 10);
+}
+
+#[test]
+fn log_level_gates_messages() {
+    setup();
+    assert!(get_log_level() == LogLevel::Off);
+    assert!(!log_enabled(LogLevel::Error));
+    set_log_level(LogLevel::Info).unwrap();
+    assert!(log_enabled(LogLevel::Error) && log_enabled(LogLevel::Info));
+    assert!(!log_enabled(LogLevel::Debug) && !log_enabled(LogLevel::Off));
+    assert!(as_caller(BOB, || set_log_level(LogLevel::Debug)).is_err());
+    assert!(!log_enabled(LogLevel::Debug));
+}
+
+#[test]
+fn treasury_withdrawal_pays_out_what_it_holds() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    TREASURY.with(|t| *t.borrow_mut() = 100);
+    assert_eq!(block_on(withdraw_treasury(CAROL, 101)).err(), Some("Treasury only holds 100".to_string()));
+    let receipt = block_on(withdraw_treasury(CAROL, 60)).unwrap();
+    assert_eq!((receipt.amount, receipt.block_index), (60, 1));
+    assert_eq!(mock_transfers(), vec![MockTransfer { from: None, to: CAROL, to_subaccount: None, amount: 60 }]);
+    assert_eq!(get_treasury_balance(), 40);
+    set_mock_ledger_failing(true);
+    assert!(block_on(withdraw_treasury(CAROL, 40)).is_err());
+    assert_eq!(get_treasury_balance(), 40);
+    assert!(as_caller(BOB, || block_on(withdraw_treasury(BOB, 1))).is_err());
+}
+
+#[test]
+fn version_reports_the_crate_semver() {
+    let info = version();
+    assert_eq!(info.semver, env!("CARGO_PKG_VERSION"));
+    let parts: Vec<&str> = info.semver.split('.').collect();
+    assert_eq!(parts.len(), 3);
+    assert!(parts.iter().all(|p| p.parse::<u64>().is_ok()));
+}
+
+#[test]
+fn legacy_user_ids_parse_or_report_why_not() {
+    let parsed = parse_user_ids(vec![ALICE.to_text(), format!(" {} ", BOB.to_text()), "not-a-principal".to_string()]);
+    assert_eq!(parsed[..2], [Ok(ALICE), Ok(BOB)]);
+    assert!(parsed[2].as_ref().unwrap_err().starts_with("Invalid principal 'not-a-principal'"));
+}
+
+#[test]
+fn rate_limit_throttles_until_the_window_passes() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    set_mock_time(1_000);
+    set_rate_limit(Some(RateLimit { max_calls: 2, window_nanos: 100 })).unwrap();
+    let send = || as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 1));
+    assert!(send().is_ok());
+    assert!(send().is_ok());
+    assert_eq!(send(), Err(RwaError::RateLimited.to_string()));
+    set_mock_time(1_099);
+    assert_eq!(send(), Err(RwaError::RateLimited.to_string()));
+    set_mock_time(1_100);
+    assert!(send().is_ok());
+    assert_eq!(get_ownership(pid, BOB), 3);
+    assert!(as_caller(ALICE, || set_rate_limit(None)).is_err());
+    assert!(set_rate_limit(Some(RateLimit { max_calls: 0, window_nanos: 100 })).is_err());
+}
+
+#[test]
+fn treasury_withdrawal_needs_two_approvals_under_multi_sig() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    TREASURY.with(|t| *t.borrow_mut() = 100);
+    enable_multi_sig();
+    assert!(block_on(withdraw_treasury(CAROL, 60)).is_err());
+    let op = propose_operation(AdminOperation::WithdrawTreasury { to: CAROL, amount: 60 }).unwrap();
+    assert_eq!(block_on(approve_operation(op)), Ok("Approval recorded".to_string()));
+    assert_eq!(block_on(approve_operation(op)), Err("Already approved".to_string()));
+    assert!(mock_transfers().is_empty());
+    assert_eq!(get_treasury_balance(), 100);
+    assert!(as_caller(BOB, || block_on(approve_operation(op))).is_ok());
+    assert_eq!(mock_transfers(), vec![MockTransfer { from: None, to: CAROL, to_subaccount: None, amount: 60 }]);
+    assert_eq!(get_treasury_balance(), 40);
+    assert_eq!(block_on(approve_operation(op)), Err("Operation not found".to_string()));
+}
+
+#[test]
+fn global_config_reflects_every_setting() {
+    setup();
+    let defaults = get_global_config();
+    assert_eq!((defaults.platform_fee_bps, defaults.max_listings_per_seller, defaults.required_approvals), (0, 10, 1));
+    assert!(defaults.registration_policy == RegistrationPolicy::Open && defaults.log_level == LogLevel::Off);
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    set_platform_fee_bps(250).unwrap();
+    set_registration_policy(RegistrationPolicy::Quota(3)).unwrap();
+    set_require_unique_names(true).unwrap();
+    set_max_listings_per_seller(4).unwrap();
+    set_max_price_per_share(Some(1_000)).unwrap();
+    set_max_total_shares(Some(5_000)).unwrap();
+    set_log_level(LogLevel::Info).unwrap();
+    let config = get_global_config();
+    assert_eq!((config.settlement_ledger, config.platform_fee_bps, config.max_listings_per_seller), (Some(LEDGER), 250, 4));
+    assert_eq!((config.max_price_per_share, config.max_total_shares), (Some(1_000), Some(5_000)));
+    assert!(config.registration_policy == RegistrationPolicy::Quota(3) && config.require_unique_names);
+    assert!(config.log_level == LogLevel::Info && config.rate_limit.is_none());
+}
+
+#[test]
+fn income_adjustments_and_cash_outs_need_approval_under_multi_sig() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 15);
+    enable_multi_sig();
+    assert!(adjust_unclaimed_income(pid, ADMIN, 1_000, "Mine".to_string()).is_err());
+    assert!(reverse_split_property(pid, 10, 7).is_err());
+    let adjust = propose_operation(AdminOperation::AdjustUnclaimedIncome {
+        property_id: pid,
+        user: ALICE,
+        delta: 40,
+        reason: "Correction".to_string(),
+    })
+    .unwrap();
+    let consolidate = propose_operation(AdminOperation::ReverseSplit { property_id: pid, factor: 10, cash_out_price: 7 }).unwrap();
+    for op in [adjust, consolidate] {
+        block_on(approve_operation(op)).unwrap();
+    }
+    assert_eq!((get_unclaimed_income(pid, ALICE), get_ownership(pid, ALICE)), (0, 15));
+    for op in [adjust, consolidate] {
+        as_caller(BOB, || block_on(approve_operation(op))).unwrap();
+    }
+    assert_eq!((get_unclaimed_income(pid, ALICE), get_ownership(pid, ALICE)), (75, 1));
+    assert!(reverse_split_property(pid, 2, 0).is_ok());
+}
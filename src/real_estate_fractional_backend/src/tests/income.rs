@@ -0,0 +1,351 @@
+use super::*;
+
+#[test]
+fn auto_claim_on_transfer_credits_proceeds() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 50);
+    deposit_rental_income(pid, 1_000).unwrap();
+    as_caller(ALICE, || set_auto_claim(true)).unwrap();
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 10)).unwrap();
+    assert_eq!(get_unclaimed_income(pid, ALICE), 0);
+    assert_eq!(get_seller_proceeds_for_property(ALICE, pid), 500);
+    assert_eq!(get_income_summary(pid).total_claimed, 500);
+}
+
+#[test]
+fn auto_claim_is_off_by_default() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 50);
+    deposit_rental_income(pid, 1_000).unwrap();
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 10)).unwrap();
+    assert_eq!(get_unclaimed_income(pid, ALICE), 500);
+    assert_eq!(get_seller_proceeds(ALICE), 0);
+}
+
+#[test]
+fn only_the_holder_can_claim_their_income() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 50);
+    deposit_rental_income(pid, 1_000).unwrap();
+    assert!(as_caller(BOB, || claim_income(pid, ALICE)).is_err());
+    assert!(as_caller(Principal::anonymous(), || claim_income(pid, ALICE)).is_err());
+    assert_eq!(get_unclaimed_income(pid, ALICE), 500);
+    assert_eq!(as_caller(ALICE, || claim_income(pid, ALICE)), Ok(500));
+    assert_eq!(get_unclaimed_income(pid, ALICE), 0);
+}
+
+#[test]
+fn deposits_are_attributed_to_subaccounts_and_withdrawn_to_them() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 40, Some(sub(7)))).unwrap();
+    deposit_rental_income(pid, 1_000).unwrap();
+    assert_eq!(get_unclaimed_income(pid, ALICE), 500);
+    assert_eq!(get_subaccount_income(pid, ALICE, Some(sub(7))), Ok(400));
+    assert_eq!(get_subaccount_income(pid, ALICE, None), Ok(100));
+    let receipt = as_caller(ALICE, || block_on(claim_and_withdraw(pid, Some(sub(7))))).unwrap();
+    assert_eq!(receipt.amount, 400);
+    assert_eq!(mock_transfers(), vec![MockTransfer { from: None, to: ALICE, to_subaccount: Some(sub(7)), amount: 400 }]);
+    assert_eq!(get_unclaimed_income(pid, ALICE), 100);
+    assert_eq!(get_subaccount_income(pid, ALICE, Some(sub(7))), Ok(0));
+}
+
+#[test]
+fn failed_subaccount_withdrawal_restores_its_income() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(100);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 50, Some(sub(7)))).unwrap();
+    deposit_rental_income(pid, 1_000).unwrap();
+    set_mock_ledger_failing(true);
+    assert!(as_caller(ALICE, || block_on(claim_and_withdraw(pid, Some(sub(7))))).is_err());
+    assert_eq!(get_unclaimed_income(pid, ALICE), 500);
+    assert_eq!(get_subaccount_income(pid, ALICE, Some(sub(7))), Ok(500));
+}
+
+#[test]
+fn claims_use_up_the_default_portion_first() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 40, Some(sub(7)))).unwrap();
+    deposit_rental_income(pid, 1_000).unwrap();
+    assert_eq!(as_caller(ALICE, || claim_income_partial(pid, 150)), Ok(350));
+    assert_eq!(get_subaccount_income(pid, ALICE, Some(sub(7))), Ok(350));
+    assert_eq!(get_subaccount_income(pid, ALICE, None), Ok(0));
+    as_caller(ALICE, || claim_income(pid, ALICE)).unwrap();
+    assert_eq!(get_subaccount_income(pid, ALICE, Some(sub(7))), Ok(0));
+}
+
+#[test]
+fn income_summary_splits_deposits_into_claimed_and_unclaimed() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 60);
+    issue(pid, BOB, 40);
+    deposit_rental_income(pid, 1_000).unwrap();
+    as_caller(ALICE, || claim_income_partial(pid, 200)).unwrap();
+    let summary = get_income_summary(pid);
+    assert_eq!((summary.total_deposited, summary.total_unclaimed, summary.total_claimed), (1_000, 800, 200));
+}
+
+#[test]
+fn deposits_to_a_sold_property_are_rejected() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 50);
+    update_property_status(pid, PropertyStatus::Sold).unwrap();
+    assert_eq!(deposit_rental_income(pid, 1_000), Err("Property is not active".to_string()));
+    assert_eq!(get_unclaimed_income(pid, ALICE), 0);
+    assert_eq!(get_income_summary(pid).total_deposited, 0);
+}
+
+#[test]
+fn yield_extrapolates_only_recent_deposits() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 100);
+    deposit_rental_income(pid, 5_000).unwrap();
+    set_mock_time(200 * NANOS_PER_DAY);
+    deposit_rental_income(pid, 90).unwrap();
+    // 90 over the 90-day lookback is 365 a year, on a valuation of 10_000
+    assert_eq!(get_annualized_yield_bps(pid), 365);
+    set_mock_time(291 * NANOS_PER_DAY);
+    assert_eq!(get_annualized_yield_bps(pid), 0);
+}
+
+#[test]
+fn batch_deposit_reports_each_property_separately() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 100);
+    issue(b, ALICE, 100);
+    let results = deposit_rental_income_batch(vec![(a, 100), (999, 100), (b, 200)]);
+    let ids: Vec<PropertyId> = results.iter().map(|(pid, _)| *pid).collect();
+    assert_eq!(ids, vec![a, 999, b]);
+    assert!(results[0].1.is_ok() && results[1].1.is_err() && results[2].1.is_ok());
+    assert_eq!((get_unclaimed_income(a, ALICE), get_unclaimed_income(b, ALICE)), (100, 200));
+    let refused = as_caller(ALICE, || deposit_rental_income_batch(vec![(a, 100), (b, 100)]));
+    assert!(refused.iter().all(|(_, r)| r.is_err()));
+    assert_eq!((get_unclaimed_income(a, ALICE), get_unclaimed_income(b, ALICE)), (100, 200));
+}
+
+#[test]
+fn snapshot_distribution_pays_the_holders_at_the_snapshot() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 60);
+    issue(pid, BOB, 40);
+    let snap = take_snapshot(pid).unwrap();
+    as_caller(ALICE, || transfer_shares(pid, ALICE, CAROL, 60)).unwrap();
+    distribute_to_snapshot(pid, snap, 1_000).unwrap();
+    assert_eq!(get_unclaimed_income(pid, ALICE), 600);
+    assert_eq!(get_unclaimed_income(pid, BOB), 400);
+    assert_eq!(get_unclaimed_income(pid, CAROL), 0);
+}
+
+#[test]
+fn withdrawal_pays_out_and_rolls_back_on_failure() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 50);
+    deposit_rental_income(pid, 1_000).unwrap();
+    set_mock_ledger_failing(true);
+    assert!(as_caller(ALICE, || block_on(claim_and_withdraw(pid, None))).is_err());
+    assert_eq!(get_unclaimed_income(pid, ALICE), 500);
+    assert_eq!(get_income_summary(pid).total_claimed, 0);
+    set_mock_ledger_failing(false);
+    let receipt = as_caller(ALICE, || block_on(claim_and_withdraw(pid, None))).unwrap();
+    assert_eq!((receipt.amount, receipt.block_index), (500, 1));
+    assert_eq!(mock_transfers(), vec![MockTransfer { from: None, to: ALICE, to_subaccount: None, amount: 500 }]);
+    assert_eq!(get_unclaimed_income(pid, ALICE), 0);
+    assert_eq!(get_income_summary(pid).total_claimed, 500);
+    assert!(as_caller(ALICE, || block_on(claim_and_withdraw(pid, None))).is_err());
+}
+
+#[test]
+fn simulated_share_matches_the_actual_deposit() {
+    setup();
+    let pid = new_property(300);
+    issue(pid, ALICE, 70);
+    issue(pid, BOB, 130);
+    let simulated = simulate_income_share(pid, ALICE, 1_001);
+    assert!(simulated > 0);
+    deposit_rental_income(pid, 1_001).unwrap();
+    assert_eq!(get_unclaimed_income(pid, ALICE), simulated);
+    assert_eq!(simulate_income_share(pid, CAROL, 1_001), 0);
+}
+
+#[test]
+fn remainder_stays_claimable_after_a_partial_claim() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 100);
+    deposit_rental_income(pid, 1_000).unwrap();
+    assert_eq!(as_caller(ALICE, || claim_income_partial(pid, 300)), Ok(700));
+    assert_eq!(as_caller(ALICE, || claim_income_partial(pid, 701)), Err("Only 700 income available to claim".to_string()));
+    assert!(as_caller(ALICE, || claim_income_partial(pid, 0)).is_err());
+    assert_eq!(as_caller(ALICE, || claim_income(pid, ALICE)), Ok(700));
+    assert_eq!(get_income_summary(pid).total_claimed, 1_000);
+}
+
+#[test]
+fn compaction_purges_zero_income_entries() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 100);
+    UNCLAIMED_INCOME.with(|ui| {
+        let mut ui = ui.borrow_mut();
+        ui.insert((pid, BOB, sub(0)), 0);
+        ui.insert((pid, CAROL, sub(0)), 0);
+        ui.insert((pid, ALICE, sub(0)), 5);
+    });
+    assert_eq!(compact_unclaimed_income(), Ok(2));
+    assert_eq!(UNCLAIMED_INCOME.with(|ui| ui.borrow().len()), 1);
+    assert_eq!(compact_unclaimed_income(), Ok(0));
+    assert!(as_caller(BOB, compact_unclaimed_income).is_err());
+}
+
+#[test]
+fn unclaimed_income_adjusts_within_bounds() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 100);
+    deposit_rental_income(pid, 100).unwrap();
+    let adjust = |delta| adjust_unclaimed_income(pid, ALICE, delta, "Correction".to_string());
+    assert_eq!(adjust(50), Ok(150));
+    assert_eq!(adjust(-120), Ok(30));
+    assert_eq!(adjust(-31), Err("Adjustment exceeds unclaimed income of 30".to_string()));
+    assert!(adjust(0).is_err());
+    assert!(adjust_unclaimed_income(pid, ALICE, 5, " ".to_string()).is_err());
+    assert_eq!(adjust(i64::MAX), Ok(30 + i64::MAX as u64));
+    assert_eq!(adjust(i64::MAX), Err("Adjustment overflows unclaimed income".to_string()));
+    assert!(as_caller(BOB, || adjust_unclaimed_income(pid, ALICE, 1, "Mine".to_string())).is_err());
+}
+
+#[test]
+fn distribution_cost_grows_with_holders() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 10);
+    issue(a, BOB, 10);
+    issue(b, CAROL, 10);
+    let estimate = estimate_distribution_cost(a);
+    assert_eq!(estimate.holder_count, 2);
+    // Three ownership entries are scanned, two holders credited
+    let expected = DISTRIBUTION_BASE_INSTRUCTIONS + 3 * DISTRIBUTION_SCAN_INSTRUCTIONS + 2 * DISTRIBUTION_CREDIT_INSTRUCTIONS;
+    assert_eq!(estimate.estimated_instructions, expected);
+    assert!(estimate.within_limit);
+    assert_eq!(estimate_distribution_cost(b).holder_count, 1);
+}
+
+#[test]
+fn rounding_dust_carries_into_the_next_deposit() {
+    setup();
+    let pid = new_property(3);
+    for holder in [ALICE, BOB, CAROL] {
+        issue(pid, holder, 1);
+    }
+    deposit_rental_income(pid, 10).unwrap();
+    assert_eq!(get_undistributed_dust(pid), 1);
+    assert_eq!(get_unclaimed_income(pid, ALICE), 3);
+    deposit_rental_income(pid, 2).unwrap();
+    assert_eq!(get_undistributed_dust(pid), 0);
+    assert_eq!([ALICE, BOB, CAROL].map(|h| get_unclaimed_income(pid, h)), [4, 4, 4]);
+}
+
+#[test]
+fn deposits_below_the_floor_are_rejected() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 100);
+    set_min_deposit(pid, Some(100)).unwrap();
+    assert_eq!(deposit_rental_income(pid, 99), Err("Deposit must be at least 100".to_string()));
+    assert_eq!(get_unclaimed_income(pid, ALICE), 0);
+    assert!(deposit_rental_income(pid, 100).is_ok());
+    set_min_deposit(pid, None).unwrap();
+    assert!(deposit_rental_income(pid, 1).is_ok());
+    assert_eq!(get_unclaimed_income(pid, ALICE), 101);
+    assert!(as_caller(ALICE, || set_min_deposit(pid, Some(1))).is_err());
+}
+
+#[test]
+fn preview_matches_what_a_deposit_credits() {
+    setup();
+    let pid = new_property(7);
+    issue(pid, ALICE, 3);
+    issue(pid, BOB, 2);
+    deposit_rental_income(pid, 10).unwrap();
+    let before = [ALICE, BOB].map(|h| get_unclaimed_income(pid, h));
+    let preview = preview_distribution(pid, 23).unwrap();
+    deposit_rental_income(pid, 23).unwrap();
+    let mut credited: Vec<(Principal, u64)> =
+        [ALICE, BOB].iter().zip(before).map(|(h, b)| (*h, get_unclaimed_income(pid, *h) - b)).collect();
+    credited.sort();
+    assert_eq!(preview, credited);
+    assert_eq!(preview_distribution(pid + 1, 1), Err("Property not found".to_string()));
+}
+
+#[test]
+fn claimable_properties_list_only_positive_balances() {
+    setup();
+    let (a, b, c) = (new_property(10), new_property(10), new_property(10));
+    for pid in [a, b, c] {
+        issue(pid, ALICE, 10);
+        deposit_rental_income(pid, 50).unwrap();
+    }
+    as_caller(ALICE, || claim_income(b, ALICE)).unwrap();
+    UNCLAIMED_INCOME.with(|ui| ui.borrow_mut().insert((b, ALICE, sub(0)), 0));
+    as_caller(ALICE, || claim_income_partial(c, 20)).unwrap();
+    assert_eq!(as_caller(ALICE, get_my_claimable_properties), vec![(a, 50), (c, 30)]);
+    assert!(as_caller(BOB, get_my_claimable_properties).is_empty());
+}
+
+#[test]
+fn deposit_allocations_are_kept_per_deposit() {
+    setup();
+    let pid = new_property(10);
+    issue(pid, ALICE, 6);
+    issue(pid, BOB, 4);
+    deposit_rental_income(pid, 100).unwrap();
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 6)).unwrap();
+    deposit_rental_income(pid, 50).unwrap();
+    assert_eq!((get_deposit_allocation(pid, 0, ALICE), get_deposit_allocation(pid, 0, BOB)), (60, 40));
+    assert_eq!((get_deposit_allocation(pid, 1, ALICE), get_deposit_allocation(pid, 1, BOB)), (0, 50));
+    assert_eq!((get_deposit_allocation(pid, 2, BOB), get_deposit_allocation(pid, 0, CAROL)), (0, 0));
+}
+
+#[test]
+fn unclaimed_income_is_read_in_batches() {
+    setup();
+    let (a, b) = (new_property(10), new_property(10));
+    issue(a, ALICE, 5);
+    issue(a, BOB, 5);
+    issue(b, BOB, 10);
+    deposit_rental_income(a, 100).unwrap();
+    deposit_rental_income(b, 30).unwrap();
+    let queries = vec![(a, ALICE), (b, ALICE), (b, BOB), (a, BOB), (b + 1, BOB)];
+    assert_eq!(get_unclaimed_income_many(queries), vec![50, 0, 30, 50, 0]);
+    assert!(get_unclaimed_income_many(vec![]).is_empty());
+}
+
+#[test]
+fn only_admin_can_deposit_rental_income() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(10);
+    issue(pid, ALICE, 10);
+    assert_eq!(
+        as_caller(ALICE, || deposit_rental_income(pid, 500_000)),
+        Err("Only admin can deposit rental income".to_string())
+    );
+    assert_eq!(get_unclaimed_income(pid, ALICE), 0);
+    assert!(as_caller(ALICE, || block_on(claim_and_withdraw(pid, None))).is_err());
+    assert!(mock_transfers().is_empty());
+}
@@ -0,0 +1,87 @@
+use super::*;
+
+#[test]
+fn expired_reservation_is_released_instead_of_confirmed() {
+    setup();
+    let pid = new_property(100);
+    set_mock_time(1_000);
+    let id = reserve_shares(pid, ALICE, 40, 2_000).unwrap();
+    assert_eq!(get_property(pid).unwrap().shares_available, 60);
+    set_mock_time(2_000);
+    assert_eq!(confirm_reservation(id), Err("Reservation expired".to_string()));
+    assert_eq!(get_property(pid).unwrap().shares_available, 100);
+    assert_eq!(get_ownership(pid, ALICE), 0);
+}
+
+#[test]
+fn reservations_confirm_only_for_active_properties_and_allowed_recipients() {
+    setup();
+    let pid = new_property(100);
+    let id = reserve_shares(pid, BOB, 30, u64::MAX).unwrap();
+    update_property_status(pid, PropertyStatus::Maintenance).unwrap();
+    assert_eq!(confirm_reservation(id), Err("Property is not active".to_string()));
+    update_property_status(pid, PropertyStatus::Active).unwrap();
+    set_transfer_restricted(pid, true).unwrap();
+    assert_eq!(confirm_reservation(id), Err("Recipient is not approved for this property".to_string()));
+    assert_eq!(get_ownership(pid, BOB), 0);
+    set_approved_recipient(pid, BOB, true).unwrap();
+    confirm_reservation(id).unwrap();
+    assert_eq!(get_ownership(pid, BOB), 30);
+    assert_eq!(get_property(pid).unwrap().shares_available, 70);
+}
+
+#[test]
+#[should_panic(expected = "Supply invariant violated")]
+fn releasing_a_reservation_past_the_total_traps() {
+    setup();
+    let pid = new_property(100);
+    let id = reserve_shares(pid, BOB, 20, 1_000).unwrap();
+    // Simulate the reserved shares having been handed back already
+    PROPERTIES.with(|props| props.borrow_mut().get_mut(&pid).unwrap().shares_available = 100);
+    let _ = cancel_reservation(id);
+}
+
+#[test]
+fn issuing_on_a_frozen_property_fails() {
+    setup();
+    let pid = new_property(100);
+    update_property_status(pid, PropertyStatus::Maintenance).unwrap();
+    assert_eq!(as_caller(ADMIN, || issue_shares(pid, ALICE, 10, None)), Err("Property is not active".to_string()));
+    assert_eq!(get_ownership(pid, ALICE), 0);
+    assert_eq!(get_property(pid).unwrap().shares_available, 100);
+}
+
+#[test]
+fn only_admin_can_issue_shares() {
+    setup();
+    let pid = new_property(100);
+    assert_eq!(as_caller(ALICE, || issue_shares(pid, ALICE, 10, None)), Err("Only admin can issue shares".to_string()));
+    assert_eq!((get_ownership(pid, ALICE), get_property(pid).unwrap().shares_available), (0, 100));
+}
+
+#[test]
+fn issuance_progress_tracks_issued_shares() {
+    setup();
+    let pid = new_property(300);
+    assert_eq!(get_issuance_progress(pid), Ok((0, 300, 0)));
+    issue(pid, ALICE, 100);
+    assert_eq!(get_issuance_progress(pid), Ok((100, 300, 3_333)));
+    issue(pid, BOB, 200);
+    assert_eq!(get_issuance_progress(pid), Ok((300, 300, 10_000)));
+    assert!(get_issuance_progress(999).is_err());
+}
+
+#[test]
+fn issuance_stops_exactly_at_the_total() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 60);
+    issue(pid, BOB, 40);
+    assert_eq!(get_property(pid).unwrap().shares_available, 0);
+    assert_eq!(
+        issue_shares(pid, CAROL, 1, None),
+        Err(RwaError::InsufficientShares { available: 0 }.to_string())
+    );
+    assert_eq!(get_ownership(pid, CAROL), 0);
+    assert_eq!(issue_shares(pid + 1, CAROL, 1, None), Err(RwaError::PropertyNotFound.to_string()));
+}
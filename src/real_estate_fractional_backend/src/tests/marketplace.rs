@@ -0,0 +1,693 @@
+use super::*;
+
+#[test]
+fn buy_pays_from_the_caller_and_records_the_block() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 5);
+    let receipt = as_caller(BOB, || block_on(buy_shares(pid, ALICE, 4))).unwrap();
+    assert_eq!(receipt.settlement_block, Some(1));
+    assert_eq!(mock_transfers(), vec![MockTransfer { from: Some(BOB), to: ALICE, to_subaccount: None, amount: 20 }]);
+    assert_eq!(get_ownership(pid, BOB), 4);
+    assert_eq!(get_recent_trades(pid, 1)[0].settlement_block, Some(1));
+}
+
+#[test]
+fn listings_and_locks_draw_on_the_default_subaccount() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 20, Some(sub(7)))).unwrap();
+    assert_eq!(
+        as_caller(ALICE, || list_shares_for_sale(pid, ALICE, 11, 5, None)),
+        Err("Not enough shares: 10 available".to_string())
+    );
+    as_caller(ALICE, || lock_shares(pid, 4, CAROL)).unwrap();
+    list(pid, ALICE, 6, 5);
+    buy(pid, BOB, ALICE, 6);
+    assert_eq!(get_subaccount_balance(pid, ALICE, None), Ok(4));
+    assert_eq!(get_subaccount_balance(pid, ALICE, Some(sub(7))), Ok(20));
+    assert!(as_caller(ALICE, || lock_shares(pid, 1, CAROL)).is_err());
+}
+
+#[test]
+fn failed_payment_undoes_the_buy() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 5);
+    set_mock_ledger_failing(true);
+    assert!(as_caller(BOB, || block_on(buy_shares(pid, ALICE, 4))).is_err());
+    assert_eq!(get_ownership(pid, ALICE), 10);
+    assert_eq!(get_ownership(pid, BOB), 0);
+    assert_eq!(get_total_shares_listed(pid), 10);
+}
+
+#[test]
+fn bids_belong_to_the_caller() {
+    setup();
+    let pid = new_property(100);
+    let bid_id = as_caller(BOB, || place_bid(pid, 5, 10)).unwrap();
+    assert_eq!(get_bids(pid)[0].buyer, BOB);
+    assert_eq!(as_caller(ALICE, || cancel_bid(bid_id)), Err("Bid not found".to_string()));
+    assert_eq!(as_caller(BOB, || cancel_bid(bid_id)), Ok("Bid cancelled".to_string()));
+    assert!(get_bids(pid).is_empty());
+}
+
+#[test]
+fn matching_pays_from_the_bidder() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 5);
+    as_caller(BOB, || place_bid(pid, 4, 6)).unwrap();
+    let fills = as_caller(ADMIN, || block_on(run_matching(pid))).unwrap();
+    assert_eq!(fills.len(), 1);
+    assert_eq!((fills[0].buyer, fills[0].amount, fills[0].price_per_share), (BOB, 4, 5));
+    assert_eq!(mock_transfers(), vec![MockTransfer { from: Some(BOB), to: ALICE, to_subaccount: None, amount: 20 }]);
+    assert_eq!(get_ownership(pid, BOB), 4);
+}
+
+#[test]
+fn locked_shares_no_longer_back_a_listing() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let first = list(pid, ALICE, 6, 5);
+    let second = list(pid, ALICE, 4, 5);
+    assert!(find_invalid_listings().is_empty());
+    as_caller(ALICE, || lock_shares(pid, 3, CAROL)).unwrap();
+    assert_eq!(find_invalid_listings(), vec![second]);
+    as_caller(ALICE, || lock_shares(pid, 4, CAROL)).unwrap();
+    assert_eq!(find_invalid_listings(), vec![first, second]);
+}
+
+#[test]
+fn listings_stop_at_the_per_seller_limit() {
+    setup();
+    set_max_listings_per_seller(2).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 3, 5);
+    list(pid, ALICE, 3, 5);
+    let third = as_caller(ALICE, || list_shares_for_sale(pid, ALICE, 3, 5, None));
+    assert_eq!(third, Err(RwaError::ListingLimitReached { max: 2 }.to_string()));
+    assert_eq!(get_listing_count(pid), 2);
+}
+
+#[test]
+fn partial_fill_receipt_reports_the_shares_and_fee() {
+    setup();
+    set_platform_fee_bps(250).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let listing = list(pid, ALICE, 10, 50);
+    let receipt = as_caller(BOB, || block_on(buy_shares(pid, ALICE, 4))).unwrap();
+    assert_eq!((receipt.shares, receipt.price_per_share, receipt.total_paid, receipt.fee), (4, 50, 200, 5));
+    assert_eq!((receipt.listing_id, receipt.settlement_block), (listing, None));
+    assert_eq!(get_marketplace_listings().0[0].amount, 6);
+    assert_eq!(get_seller_proceeds(ALICE), 195);
+}
+
+#[test]
+fn listings_are_stamped_with_the_clock() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    set_mock_time(1_000);
+    list(pid, ALICE, 4, 5);
+    set_mock_time(2_000);
+    list(pid, ALICE, 6, 5);
+    let stamps: Vec<u64> = get_marketplace_listings().0.iter().map(|l| l.listed_at).collect();
+    assert_eq!(stamps, vec![1_000, 2_000]);
+}
+
+#[test]
+fn recent_trades_come_newest_first() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 3, 5);
+    list(pid, ALICE, 3, 6);
+    list(pid, ALICE, 3, 7);
+    // Each buy fills the oldest listing that covers it
+    for at in 1..=3 {
+        set_mock_time(at * 1_000);
+        buy(pid, BOB, ALICE, 3);
+    }
+    let prices: Vec<u64> = get_recent_trades(pid, 2).iter().map(|t| t.price_per_share).collect();
+    assert_eq!(prices, vec![7, 6]);
+    assert_eq!(get_recent_trades(pid, 10).len(), 3);
+}
+
+#[test]
+fn zero_amount_and_self_trades_are_rejected() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 5, 10);
+    assert_eq!(check_listing_valid(pid, ALICE, 0, 1), Err(RwaError::InvalidAmount));
+    assert_eq!(as_caller(BOB, || block_on(buy_shares(pid, ALICE, 0))).err(), Some(RwaError::InvalidAmount.to_string()));
+    assert!(as_caller(ALICE, || block_on(buy_shares(pid, ALICE, 1))).is_err());
+    assert!(get_recent_trades(pid, 10).is_empty());
+}
+
+#[test]
+fn matching_never_crosses_a_bidder_with_their_own_listing() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    issue(pid, BOB, 10);
+    list(pid, ALICE, 5, 5);
+    list(pid, BOB, 5, 8);
+    as_caller(ALICE, || place_bid(pid, 3, 10)).unwrap();
+    let fills = block_on(run_matching(pid)).unwrap();
+    assert_eq!(fills.len(), 1);
+    assert_eq!((fills[0].buyer, fills[0].seller, fills[0].amount, fills[0].price_per_share), (ALICE, BOB, 3, 8));
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (13, 7));
+    assert_eq!(get_total_shares_listed(pid), 7);
+}
+
+#[test]
+fn split_and_list_keeps_the_requested_shares() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    as_caller(ALICE, || split_and_list(pid, ALICE, 4, 5)).unwrap();
+    assert_eq!(get_total_shares_listed(pid), 6);
+    assert_eq!(get_ownership(pid, ALICE), 10);
+    assert!(as_caller(ALICE, || split_and_list(pid, ALICE, 10, 5)).is_err());
+}
+
+#[test]
+fn only_the_holder_can_list_their_shares() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let refused = Err("Only the holder can list their shares".to_string());
+    assert_eq!(as_caller(BOB, || list_shares_for_sale(pid, ALICE, 10, 1, None)), refused);
+    assert_eq!(as_caller(BOB, || split_and_list(pid, ALICE, 0, 1)), refused);
+    assert_eq!(get_listing_count(pid), 0);
+}
+
+#[test]
+fn position_value_marks_to_the_latest_trade() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 20);
+    // Untraded: pro rata of the 10_000 valuation
+    assert_eq!(get_position_value(pid, ALICE), 2_000);
+    list(pid, ALICE, 5, 300);
+    buy(pid, BOB, ALICE, 5);
+    assert_eq!(get_position_value(pid, ALICE), 15 * 300);
+    assert_eq!(get_position_value(pid, BOB), 5 * 300);
+}
+
+#[test]
+fn listing_orphaned_by_a_transfer_is_reported() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let listing = list(pid, ALICE, 8, 5);
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 5)).unwrap();
+    assert_eq!(find_invalid_listings(), vec![listing]);
+}
+
+#[test]
+fn oversized_listing_results_are_truncated_and_flagged() {
+    setup();
+    let pid = new_property(100);
+    let note = "x".repeat(MAX_LISTING_NOTE_LEN);
+    MARKETPLACE.with(|mp| {
+        let mut mp = mp.borrow_mut();
+        for id in 1..=8_000 {
+            mp.push(Listing { id, property_id: pid, seller: ALICE, amount: 1, price_per_share: 1, listed_at: 0, note: Some(note.clone()) });
+        }
+    });
+    let (listings, truncated) = get_marketplace_listings();
+    assert!(truncated);
+    assert!(!listings.is_empty() && listings.len() < 8_000);
+    assert_eq!(listings.last().unwrap().id, listings.len() as u64);
+    MARKETPLACE.with(|mp| mp.borrow_mut().truncate(10));
+    let (listings, truncated) = get_marketplace_listings();
+    assert_eq!((listings.len(), truncated), (10, false));
+}
+
+#[test]
+fn listing_an_unknown_property_is_rejected() {
+    setup();
+    let listed = as_caller(ALICE, || list_shares_for_sale(999, ALICE, 1, 5, None));
+    assert_eq!(listed, Err(RwaError::PropertyNotFound.to_string()));
+    assert_eq!(get_listing_count(999), 0);
+    let empty = new_property(0);
+    assert!(as_caller(ALICE, || list_shares_for_sale(empty, ALICE, 1, 5, None)).is_err());
+}
+
+#[test]
+fn small_trades_accumulate_the_full_fee() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    set_platform_fee_bps(250).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 20);
+    list(pid, ALICE, 20, 4);
+    // Each trade of 4 owes a tenth of a unit; the fractions add up instead of vanishing
+    let fees: u64 = (0..20).map(|_| buy(pid, BOB, ALICE, 1).fee).sum();
+    assert_eq!(fees, 2);
+    assert_eq!(get_treasury_balance(), 2);
+    assert_eq!(get_seller_proceeds(ALICE), 78);
+}
+
+#[test]
+fn fee_fractions_carry_per_seller() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    set_platform_fee_bps(5_000).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    issue(pid, CAROL, 10);
+    list(pid, ALICE, 10, 1);
+    list(pid, CAROL, 10, 1);
+    // Half a unit each: ALICE's fraction must not complete a fee on CAROL's trade
+    assert_eq!(buy(pid, BOB, ALICE, 1).fee, 0);
+    assert_eq!(buy(pid, BOB, CAROL, 1).fee, 0);
+    assert_eq!(buy(pid, BOB, ALICE, 1).fee, 1);
+    assert_eq!(buy(pid, BOB, CAROL, 1).fee, 1);
+    assert_eq!(get_treasury_balance(), 2);
+}
+
+#[test]
+fn off_chain_trades_add_nothing_to_the_treasury() {
+    setup();
+    set_platform_fee_bps(1_000).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 10);
+    assert_eq!(buy(pid, BOB, ALICE, 5).fee, 5);
+    assert_eq!(get_treasury_balance(), 0);
+    assert_eq!(get_seller_proceeds(ALICE), 45);
+}
+
+#[test]
+fn order_book_aggregates_price_levels() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    issue(pid, CAROL, 10);
+    list(pid, ALICE, 3, 9);
+    list(pid, CAROL, 4, 7);
+    list(pid, ALICE, 2, 7);
+    as_caller(BOB, || place_bid(pid, 5, 4)).unwrap();
+    as_caller(BOB, || place_bid(pid, 1, 6)).unwrap();
+    as_caller(CAROL, || place_bid(pid, 2, 4)).unwrap();
+    let book = get_order_book(pid);
+    assert_eq!(book.asks, vec![(7, 6), (9, 3)]);
+    assert_eq!(book.bids, vec![(6, 1), (4, 7)]);
+}
+
+#[test]
+fn seller_proceeds_accumulate_per_property() {
+    setup();
+    set_platform_fee_bps(100).unwrap();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 10);
+    issue(b, ALICE, 10);
+    list(a, ALICE, 10, 100);
+    list(b, ALICE, 10, 50);
+    buy(a, BOB, ALICE, 2);
+    buy(a, CAROL, ALICE, 3);
+    buy(b, BOB, ALICE, 4);
+    assert_eq!(get_seller_proceeds_for_property(ALICE, a), 495);
+    assert_eq!(get_seller_proceeds_for_property(ALICE, b), 198);
+    assert_eq!(get_seller_proceeds(ALICE), 693);
+    assert_eq!(get_seller_proceeds(BOB), 0);
+}
+
+#[test]
+fn listing_prices_must_be_positive_and_under_the_cap() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let listing = |price| as_caller(ALICE, || list_shares_for_sale(pid, ALICE, 1, price, None));
+    assert_eq!(listing(0), Err(RwaError::InvalidPrice { max: None }.to_string()));
+    set_max_price_per_share(Some(1_000)).unwrap();
+    assert_eq!(listing(1_001), Err(RwaError::InvalidPrice { max: Some(1_000) }.to_string()));
+    assert!(listing(1_000).is_ok());
+}
+
+#[test]
+fn trading_is_limited_to_the_window() {
+    setup();
+    const HOUR: u64 = NANOS_PER_DAY / 24;
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    set_trading_window(pid, Some(TradingWindow::Daily { open: 9 * HOUR, close: 17 * HOUR })).unwrap();
+    set_mock_time(8 * HOUR);
+    assert_eq!(as_caller(ALICE, || list_shares_for_sale(pid, ALICE, 5, 10, None)), Err(RwaError::TradingClosed.to_string()));
+    set_mock_time(9 * HOUR);
+    list(pid, ALICE, 5, 10);
+    set_mock_time(NANOS_PER_DAY + 17 * HOUR);
+    assert!(as_caller(BOB, || block_on(buy_shares(pid, ALICE, 1))).is_err());
+    set_mock_time(NANOS_PER_DAY + 12 * HOUR);
+    buy(pid, BOB, ALICE, 1);
+    // An overnight window wraps past midnight
+    set_trading_window(pid, Some(TradingWindow::Daily { open: 22 * HOUR, close: 2 * HOUR })).unwrap();
+    set_mock_time(2 * NANOS_PER_DAY + HOUR);
+    buy(pid, BOB, ALICE, 1);
+    assert!(set_trading_window(pid, Some(TradingWindow::Fixed { opens_at: 5, closes_at: 5 })).is_err());
+}
+
+#[test]
+fn listing_counts_and_listed_shares_are_per_property() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 10);
+    issue(a, BOB, 10);
+    issue(b, ALICE, 10);
+    list(a, ALICE, 3, 5);
+    list(a, BOB, 4, 6);
+    list(b, ALICE, 9, 5);
+    assert_eq!((get_listing_count(a), get_total_shares_listed(a)), (2, 7));
+    assert_eq!((get_listing_count(b), get_total_shares_listed(b)), (1, 9));
+    buy(a, CAROL, BOB, 4);
+    assert_eq!((get_listing_count(a), get_total_shares_listed(a)), (1, 3));
+}
+
+#[test]
+fn grouped_listings_page_over_properties() {
+    setup();
+    let (a, b, c) = (new_property(100), new_property(100), new_property(100));
+    for pid in [a, b, c] {
+        issue(pid, ALICE, 10);
+    }
+    let first = list(c, ALICE, 1, 5);
+    list(a, ALICE, 2, 5);
+    let last = list(c, ALICE, 3, 5);
+    let grouped = get_marketplace_grouped(0, 10);
+    assert_eq!(grouped.iter().map(|(pid, _)| *pid).collect::<Vec<_>>(), vec![a, c]);
+    assert_eq!(grouped[1].1.iter().map(|l| l.id).collect::<Vec<_>>(), vec![first, last]);
+    let page = get_marketplace_grouped(1, 1);
+    assert_eq!((page.len(), page[0].0), (1, c));
+    assert!(get_marketplace_grouped(2, 10).is_empty());
+    assert!(!grouped.iter().any(|(pid, _)| *pid == b));
+}
+
+#[test]
+fn budget_buy_takes_the_cheapest_shares_first() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 20);
+    issue(pid, BOB, 5);
+    list(pid, ALICE, 4, 7);
+    list(pid, ALICE, 3, 5);
+    list(pid, ALICE, 10, 9);
+    list(pid, ALICE, 10, 12);
+    // BOB's own listing is skipped
+    list(pid, BOB, 5, 1);
+    let receipt = as_caller(BOB, || block_on(buy_with_budget(pid, 50, 10))).unwrap();
+    assert_eq!((receipt.shares, receipt.total_paid, receipt.leftover), (7, 43, 7));
+    assert_eq!(receipt.fills.iter().map(|f| (f.shares, f.price_per_share)).collect::<Vec<_>>(), vec![(3, 5), (4, 7)]);
+    assert_eq!(get_ownership(pid, BOB), 12);
+}
+
+#[test]
+fn proceeds_accumulate_past_u64() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 3);
+    list(pid, ALICE, 3, u64::MAX);
+    for _ in 0..3 {
+        buy(pid, BOB, ALICE, 1);
+    }
+    assert_eq!(get_seller_proceeds(ALICE), 3 * u64::MAX as u128);
+    assert_eq!(get_seller_proceeds_for_property(ALICE, pid), 3 * u64::MAX as u128);
+    assert!(as_caller(BOB, || list_shares_for_sale(pid, BOB, 2, u64::MAX, None)).is_ok());
+    assert_eq!(as_caller(CAROL, || block_on(buy_shares(pid, BOB, 2))).err(), Some("Trade total overflows".to_string()));
+}
+
+#[test]
+fn failed_buys_and_transfers_change_nothing() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 5);
+    as_caller(ALICE, || lock_shares(pid, 8, CAROL)).unwrap();
+    let events = get_events(0, 100).unwrap().len();
+    assert_eq!(
+        as_caller(BOB, || block_on(buy_shares(pid, ALICE, 5))).err(),
+        Some("Seller no longer holds enough unlocked shares".to_string())
+    );
+    assert!(as_caller(BOB, || block_on(buy_shares(pid, ALICE, 11))).is_err());
+    assert!(as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 3)).is_err());
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (10, 0));
+    assert_eq!(get_total_shares_listed(pid), 10);
+    assert_eq!(get_events(0, 100).unwrap().len(), events);
+}
+
+#[test]
+fn multi_level_fill_charges_each_level_exactly() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    set_platform_fee_bps(300).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    issue(pid, CAROL, 10);
+    list(pid, ALICE, 2, 11);
+    list(pid, CAROL, 3, 13);
+    list(pid, ALICE, 5, 17);
+    let receipt = as_caller(BOB, || block_on(buy_with_budget(pid, 100, 20))).unwrap();
+    // 2 @ 11 + 3 @ 13 + 2 @ 17, with 5 left over that can't buy another share
+    assert_eq!(receipt.fills.iter().map(|f| f.total_paid).collect::<Vec<_>>(), vec![22, 39, 34]);
+    assert_eq!((receipt.shares, receipt.total_paid, receipt.leftover), (7, 95, 5));
+    assert_eq!(receipt.fee, receipt.fills.iter().map(|f| f.fee).sum::<u64>());
+    assert_eq!(get_seller_proceeds(ALICE) + get_seller_proceeds(CAROL) + get_treasury_balance() as u128, 95);
+}
+
+#[test]
+fn seller_cancels_all_their_listings() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 10);
+    issue(b, ALICE, 10);
+    issue(a, BOB, 10);
+    list(a, ALICE, 2, 5);
+    list(a, ALICE, 3, 5);
+    list(b, ALICE, 4, 5);
+    let bobs = list(a, BOB, 5, 5);
+    assert_eq!(as_caller(ALICE, || cancel_all_listings(Some(a))), Ok(2));
+    assert_eq!((get_listing_count(a), get_listing_count(b)), (1, 1));
+    assert_eq!(as_caller(ALICE, || cancel_all_listings(None)), Ok(1));
+    assert_eq!(get_marketplace_listings().0.iter().map(|l| l.id).collect::<Vec<_>>(), vec![bobs]);
+    assert_eq!(get_ownership(a, ALICE), 10);
+}
+
+#[test]
+fn listing_notes_round_trip_up_to_the_limit() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let note = "é".repeat(MAX_LISTING_NOTE_LEN);
+    assert!(as_caller(ALICE, || list_shares_for_sale(pid, ALICE, 5, 10, Some(note.clone()))).is_ok());
+    assert_eq!(get_marketplace_listings().0[0].note, Some(note));
+    let long = "x".repeat(MAX_LISTING_NOTE_LEN + 1);
+    assert_eq!(
+        as_caller(ALICE, || list_shares_for_sale(pid, ALICE, 5, 10, Some(long))),
+        Err(format!("Listing note can't exceed {} characters", MAX_LISTING_NOTE_LEN))
+    );
+    assert_eq!(get_listing_count(pid), 1);
+}
+
+#[test]
+fn buy_eligibility_reports_what_blocks_a_buy() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 4, 10);
+    list(pid, ALICE, 4, 12);
+    let ok = check_buy_eligibility(pid, BOB, 6);
+    assert!(ok.eligible && ok.property_active && ok.trading_open && ok.recipient_approved);
+    assert_eq!((ok.available_liquidity, ok.estimated_cost), (8, Some(64)));
+    let short = check_buy_eligibility(pid, BOB, 9);
+    assert!(!short.eligible);
+    assert_eq!(short.estimated_cost, None);
+    assert_eq!(check_buy_eligibility(pid, ALICE, 1).available_liquidity, 0);
+    set_transfer_restricted(pid, true).unwrap();
+    let restricted = check_buy_eligibility(pid, BOB, 6);
+    assert!(!restricted.eligible && !restricted.recipient_approved);
+    assert!(restricted.property_active && restricted.trading_open);
+}
+
+#[test]
+fn admin_force_cancels_a_listing_and_the_seller_keeps_the_shares() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let id = list(pid, ALICE, 5, 10);
+    assert!(as_caller(BOB, || force_cancel_listing(id)).is_err());
+    assert!(force_cancel_listing(id).is_ok());
+    assert_eq!(get_listing_count(pid), 0);
+    assert_eq!(get_ownership(pid, ALICE), 10);
+    assert_eq!(force_cancel_listing(id), Err("Listing not found".to_string()));
+}
+
+#[test]
+fn sold_properties_cannot_be_bought() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 5, 10);
+    update_property_status(pid, PropertyStatus::Sold).unwrap();
+    assert_eq!(
+        as_caller(BOB, || block_on(buy_shares(pid, ALICE, 5))).err(),
+        Some("Property is not active".to_string())
+    );
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (10, 0));
+    assert!(mock_transfers().is_empty());
+}
+
+#[test]
+fn listings_keep_id_order_through_buys_and_cancels() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 20);
+    issue(pid, BOB, 20);
+    let a = list(pid, ALICE, 5, 30);
+    let b = list(pid, BOB, 5, 10);
+    let c = list(pid, ALICE, 5, 10);
+    let d = list(pid, BOB, 5, 20);
+    let ids = || get_marketplace_listings().0.iter().map(|l| l.id).collect::<Vec<_>>();
+    assert_eq!(ids(), vec![a, b, c, d]);
+    buy(pid, CAROL, BOB, 2);
+    force_cancel_listing(a).unwrap();
+    let e = list(pid, ALICE, 5, 5);
+    assert_eq!(ids(), vec![b, c, d, e]);
+}
+
+#[test]
+fn long_term_holders_pay_a_rebated_fee() {
+    setup();
+    set_platform_fee_bps(1_000).unwrap();
+    set_fee_rebate_tiers(vec![
+        FeeRebateTier { min_holding_nanos: 5_000, rebate_bps: 10_000 },
+        FeeRebateTier { min_holding_nanos: 1_000, rebate_bps: 5_000 },
+    ])
+    .unwrap();
+    let pid = new_property(100);
+    set_mock_time(0);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 100);
+    set_mock_time(999);
+    assert_eq!(buy(pid, BOB, ALICE, 1).fee, 10);
+    set_mock_time(1_000);
+    assert_eq!(buy(pid, BOB, ALICE, 1).fee, 5);
+    set_mock_time(5_000);
+    assert_eq!(buy(pid, BOB, ALICE, 1).fee, 0);
+    assert_eq!(get_fee_rebate_tiers()[0].min_holding_nanos, 1_000);
+    assert!(set_fee_rebate_tiers(vec![FeeRebateTier { min_holding_nanos: 0, rebate_bps: 10_001 }]).is_err());
+}
+
+#[test]
+fn market_cap_follows_the_latest_trade() {
+    setup();
+    let pid = new_property(100);
+    assert_eq!(get_market_cap(pid), 10_000);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 5, 150);
+    list(pid, ALICE, 5, 80);
+    buy(pid, BOB, ALICE, 5);
+    assert_eq!(get_market_cap(pid), 15_000);
+    buy(pid, BOB, ALICE, 5);
+    assert_eq!(get_market_cap(pid), 8_000);
+    assert_eq!(get_market_cap(pid + 1), 0);
+}
+
+#[test]
+fn top_movers_rank_price_changes_within_the_window() {
+    const SEC: u64 = 1_000_000_000;
+    setup();
+    let (a, b, c) = (new_property(100), new_property(100), new_property(100));
+    let trade_at = |pid, secs, price| {
+        set_mock_time(secs * SEC);
+        list(pid, ALICE, 1, price);
+        buy(pid, BOB, ALICE, 1);
+    };
+    for pid in [a, b, c] {
+        issue(pid, ALICE, 10);
+    }
+    trade_at(a, 0, 100);
+    trade_at(c, 10, 100);
+    trade_at(b, 160, 100);
+    trade_at(a, 200, 150);
+    trade_at(b, 200, 70);
+    set_mock_time(250 * SEC);
+    assert_eq!(get_top_movers(100, 5), vec![(a, 5_000), (b, -3_000)]);
+    assert_eq!(get_top_movers(100, 1), vec![(a, 5_000)]);
+    assert_eq!(get_top_movers(10, 5), vec![]);
+}
+
+#[test]
+fn listing_check_passes_a_valid_listing() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    assert_eq!(check_listing_valid(pid, ALICE, 10, 5), Ok(()));
+}
+
+#[test]
+fn listing_check_rejects_an_unknown_property() {
+    setup();
+    assert_eq!(check_listing_valid(7, ALICE, 1, 5), Err(RwaError::PropertyNotFound));
+}
+
+#[test]
+fn listing_check_rejects_an_inactive_property() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    update_property_status(pid, PropertyStatus::Delisted).unwrap();
+    assert_eq!(check_listing_valid(pid, ALICE, 1, 5), Err(RwaError::PropertyNotActive));
+}
+
+#[test]
+fn listing_check_rejects_a_closed_trading_window() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    set_trading_window(pid, Some(TradingWindow::Fixed { opens_at: 1_000, closes_at: 2_000 })).unwrap();
+    set_mock_time(2_000);
+    assert_eq!(check_listing_valid(pid, ALICE, 1, 5), Err(RwaError::TradingClosed));
+}
+
+#[test]
+fn listing_check_rejects_prices_out_of_bounds() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    assert_eq!(check_listing_valid(pid, ALICE, 1, 0), Err(RwaError::InvalidPrice { max: None }));
+    set_max_price_per_share(Some(50)).unwrap();
+    assert_eq!(check_listing_valid(pid, ALICE, 1, 51), Err(RwaError::InvalidPrice { max: Some(50) }));
+}
+
+#[test]
+fn listing_check_rejects_more_than_the_unlocked_balance() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    as_caller(ALICE, || lock_shares(pid, 4, CAROL)).unwrap();
+    assert_eq!(check_listing_valid(pid, ALICE, 7, 5), Err(RwaError::InsufficientShares { available: 6 }));
+    assert_eq!(check_listing_valid(pid, ALICE, 6, 5), Ok(()));
+}
+
+#[test]
+fn listing_check_rejects_a_seller_at_the_listing_limit() {
+    setup();
+    set_max_listings_per_seller(1).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 1, 5);
+    assert_eq!(check_listing_valid(pid, ALICE, 1, 5), Err(RwaError::ListingLimitReached { max: 1 }));
+}
@@ -0,0 +1,79 @@
+//! Unit tests. libtest runs every test on a fresh thread, so each one starts from empty
+//! canister state; the clock, caller, cycles and settlement ledger are the mocks in `lib.rs`.
+
+use super::*;
+use std::future::Future;
+use std::pin::pin;
+use std::task::{Context, Poll, Waker};
+
+mod admin;
+mod income;
+mod issuance;
+mod marketplace;
+mod properties;
+mod proptests;
+mod transfers;
+
+const ADMIN: Principal = Principal::from_slice(&[1; 29]);
+const ALICE: Principal = Principal::from_slice(&[2; 29]);
+const BOB: Principal = Principal::from_slice(&[3; 29]);
+const CAROL: Principal = Principal::from_slice(&[5; 29]);
+const LEDGER: Principal = Principal::from_slice(&[0x1E; 10]);
+
+/// Drive an async endpoint to completion. The mock ledger never suspends, so one poll does it.
+fn block_on<F: Future>(fut: F) -> F::Output {
+    match pin!(fut).poll(&mut Context::from_waker(Waker::noop())) {
+        Poll::Ready(out) => out,
+        Poll::Pending => panic!("future suspended without a real ledger to wake it"),
+    }
+}
+
+/// Bootstrap `ADMIN` and leave it as the caller.
+fn setup() {
+    set_mock_caller(ADMIN);
+    bootstrap_admin(ADMIN).unwrap();
+}
+
+/// Run `f` as `principal`, restoring the previous caller afterwards.
+fn as_caller<T>(principal: Principal, f: impl FnOnce() -> T) -> T {
+    let previous = caller();
+    set_mock_caller(principal);
+    let out = f();
+    set_mock_caller(previous);
+    out
+}
+
+fn metadata(valuation: u64) -> PropertyMetadata {
+    PropertyMetadata {
+        location: "Lisbon".to_string(),
+        description: "Test property".to_string(),
+        valuation,
+        decimals: 0,
+    }
+}
+
+/// Register a property as `ADMIN`, valued at 100 per share.
+fn new_property(total_shares: u64) -> PropertyId {
+    let name = format!("Property {}", peek_next_property_id());
+    as_caller(ADMIN, || register_property(name, total_shares, metadata(total_shares * 100))).unwrap().id
+}
+
+fn issue(property_id: PropertyId, to: Principal, amount: u64) {
+    as_caller(ADMIN, || issue_shares(property_id, to, amount, None)).unwrap();
+}
+
+/// A subaccount filled with `byte`; `sub(0)` is the default one.
+fn sub(byte: u8) -> Subaccount {
+    vec![byte; 32]
+}
+
+/// List shares as `seller`, returning the new listing's id.
+fn list(property_id: PropertyId, seller: Principal, amount: u64, price_per_share: u64) -> u64 {
+    as_caller(seller, || list_shares_for_sale(property_id, seller, amount, price_per_share, None)).unwrap();
+    NEXT_LISTING_ID.with(|id| *id.borrow()) - 1
+}
+
+/// Buy `amount` of `seller`'s listed shares as `buyer`.
+fn buy(property_id: PropertyId, buyer: Principal, seller: Principal, amount: u64) -> BuyReceipt {
+    as_caller(buyer, || block_on(buy_shares(property_id, seller, amount))).unwrap()
+}
@@ -0,0 +1,398 @@
+use super::*;
+
+#[test]
+fn split_scales_resting_bids() {
+    setup();
+    let pid = new_property(100);
+    as_caller(BOB, || place_bid(pid, 3, 20)).unwrap();
+    split_property(pid, 10).unwrap();
+    let bid = &get_bids(pid)[0];
+    assert_eq!((bid.amount, bid.price_per_share), (30, 2));
+}
+
+#[test]
+fn split_rejects_a_bid_price_it_cannot_divide() {
+    setup();
+    let pid = new_property(100);
+    as_caller(BOB, || place_bid(pid, 3, 25)).unwrap();
+    assert!(split_property(pid, 10).is_err());
+    assert_eq!(get_property(pid).unwrap().total_shares, 100);
+    assert_eq!(get_bids(pid)[0].amount, 3);
+}
+
+#[test]
+fn consolidation_scales_bids_and_cancels_those_rounding_to_zero() {
+    setup();
+    let pid = new_property(100);
+    let small = as_caller(BOB, || place_bid(pid, 1, 20)).unwrap();
+    as_caller(ALICE, || place_bid(pid, 25, 20)).unwrap();
+    reverse_split_property(pid, 10, 0).unwrap();
+    let bids = get_bids(pid);
+    assert_eq!(bids.len(), 1);
+    assert_eq!((bids[0].buyer, bids[0].amount, bids[0].price_per_share), (ALICE, 2, 200));
+    assert!(get_events(0, 100).unwrap().iter().any(|e| matches!(e.kind, EventKind::BidCancelled { bid_id, .. } if bid_id == small)));
+}
+
+#[test]
+fn share_count_changes_apply_to_each_subaccount() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 15);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 25, Some(sub(7)))).unwrap();
+    split_property(pid, 2).unwrap();
+    assert_eq!(get_subaccount_balance(pid, ALICE, None), Ok(30));
+    assert_eq!(get_subaccount_balance(pid, ALICE, Some(sub(7))), Ok(50));
+    // Each subaccount rounds down on its own and is cashed out for its own remainder
+    reverse_split_property(pid, 4, 3).unwrap();
+    assert_eq!(get_subaccount_balance(pid, ALICE, None), Ok(7));
+    assert_eq!(get_subaccount_balance(pid, ALICE, Some(sub(7))), Ok(12));
+    assert_eq!(get_subaccount_income(pid, ALICE, None), Ok(6));
+    assert_eq!(get_subaccount_income(pid, ALICE, Some(sub(7))), Ok(6));
+    migrate_user(Some(pid), ALICE, CAROL).unwrap();
+    assert_eq!(get_subaccount_balance(pid, CAROL, Some(sub(7))), Ok(12));
+    assert_eq!(get_subaccount_income(pid, CAROL, Some(sub(7))), Ok(6));
+    assert_eq!((get_ownership(pid, ALICE), get_unclaimed_income(pid, ALICE)), (0, 0));
+    assert!(find_share_accounting_errors().is_empty());
+}
+
+#[test]
+fn status_changes_check_the_real_caller() {
+    setup();
+    let pid = new_property(100);
+    assert!(as_caller(BOB, || update_property_status(pid, PropertyStatus::Sold)).is_err());
+    assert!(get_property(pid).unwrap().status == PropertyStatus::Active);
+    update_property_status(pid, PropertyStatus::Maintenance).unwrap();
+    assert!(get_property(pid).unwrap().status == PropertyStatus::Maintenance);
+}
+
+#[test]
+fn merge_converts_holdings_at_their_value() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 30);
+    issue(b, ALICE, 20);
+    issue(b, BOB, 10);
+    let id = merge_properties(a, b).unwrap();
+    assert_eq!((get_ownership(id, ALICE), get_ownership(id, BOB)), (50, 10));
+    assert_eq!((get_ownership(a, ALICE), get_ownership(b, BOB)), (0, 0));
+    assert_eq!(get_property(id).unwrap().shares_available, 140);
+    assert!(find_share_accounting_errors().is_empty());
+}
+
+#[test]
+fn merge_closes_the_originals_market() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 30);
+    let bid = as_caller(BOB, || place_bid(b, 5, 10)).unwrap();
+    let proposal = as_caller(ALICE, || submit_proposal(a, "Repaint".to_string())).unwrap();
+    let id = merge_properties(a, b).unwrap();
+    assert!(get_bids(b).is_empty());
+    assert!(get_proposals(a)[0].status == ProposalStatus::Rejected);
+    let events = get_events(0, 100).unwrap();
+    assert!(events.iter().any(|e| matches!(e.kind, EventKind::BidCancelled { bid_id, .. } if bid_id == bid)));
+    assert!(events.iter().any(|e| matches!(e.kind, EventKind::ProposalExecuted { proposal_id, approved: false } if proposal_id == proposal.id)));
+    let primary: Vec<PropertyId> = get_primary_market(0, 10).iter().map(|p| p.id).collect();
+    assert_eq!(primary, vec![id]);
+}
+
+#[test]
+fn restricted_properties_cannot_be_merged() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 30);
+    set_transfer_restricted(b, true).unwrap();
+    assert_eq!(merge_properties(a, b), Err("Property is transfer-restricted".to_string()));
+    assert!(get_property(a).unwrap().status == PropertyStatus::Active);
+    assert_eq!(get_ownership(a, ALICE), 30);
+}
+
+#[test]
+fn next_property_id_advances_with_each_registration() {
+    setup();
+    let next = peek_next_property_id();
+    assert_eq!(new_property(10), next);
+    assert_eq!(peek_next_property_id(), next + 1);
+}
+
+#[test]
+fn registration_quota_is_enforced_per_caller() {
+    setup();
+    set_registration_policy(RegistrationPolicy::Quota(1)).unwrap();
+    assert!(as_caller(ALICE, || register_property("Alice 1".to_string(), 10, metadata(1_000))).is_ok());
+    let second = as_caller(ALICE, || register_property("Alice 2".to_string(), 10, metadata(1_000)));
+    assert_eq!(second.err(), Some("Registration quota exceeded".to_string()));
+    assert!(as_caller(BOB, || register_property("Bob 1".to_string(), 10, metadata(1_000))).is_ok());
+}
+
+#[test]
+fn paid_registration_takes_the_fee_in_cycles() {
+    setup();
+    set_registration_policy(RegistrationPolicy::Paid(500)).unwrap();
+    set_mock_cycles(499);
+    let short = as_caller(ALICE, || register_property("Alice 1".to_string(), 10, metadata(1_000)));
+    assert_eq!(short.err(), Some("Registration requires 500 cycles".to_string()));
+    set_mock_cycles(800);
+    assert!(as_caller(ALICE, || register_property("Alice 1".to_string(), 10, metadata(1_000))).is_ok());
+    assert_eq!(cycles_available(), 300);
+}
+
+#[test]
+fn primary_market_lists_active_properties_with_shares_left() {
+    setup();
+    let (open, sold_out, paused, other) = (new_property(10), new_property(10), new_property(10), new_property(10));
+    issue(open, ALICE, 5);
+    issue(sold_out, ALICE, 10);
+    update_property_status(paused, PropertyStatus::Maintenance).unwrap();
+    let ids = |offset, limit| get_primary_market(offset, limit).iter().map(|p| p.id).collect::<Vec<_>>();
+    assert_eq!(ids(0, 10), vec![open, other]);
+    assert_eq!(ids(1, 10), vec![other]);
+    assert_eq!(ids(0, 1), vec![open]);
+}
+
+#[test]
+fn split_keeps_proportions_and_income() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 30);
+    issue(pid, BOB, 20);
+    deposit_rental_income(pid, 1_000).unwrap();
+    split_property(pid, 10).unwrap();
+    let prop = get_property(pid).unwrap();
+    assert_eq!((prop.total_shares, prop.shares_available), (1_000, 500));
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (300, 200));
+    assert_eq!(get_unclaimed_income(pid, ALICE), 300);
+    deposit_rental_income(pid, 1_000).unwrap();
+    assert_eq!((get_unclaimed_income(pid, ALICE), get_unclaimed_income(pid, BOB)), (600, 400));
+}
+
+#[test]
+fn share_counts_cannot_change_while_a_trade_settles() {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    list(pid, ALICE, 10, 6);
+    set_mock_ledger_suspending(true);
+    set_mock_caller(BOB);
+    let mut buying = pin!(buy_shares(pid, ALICE, 4));
+    let mut cx = Context::from_waker(Waker::noop());
+    assert!(buying.as_mut().poll(&mut cx).is_pending());
+    set_mock_caller(ADMIN);
+    let settling = Err("Property has trades settling".to_string());
+    assert_eq!(split_property(pid, 2), settling);
+    assert_eq!(reverse_split_property(pid, 2, 0), settling);
+    assert!(matches!(buying.as_mut().poll(&mut cx), Poll::Ready(Ok(_))));
+    split_property(pid, 2).unwrap();
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (12, 8));
+}
+
+#[test]
+fn consolidation_cashes_out_remainders() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 25);
+    issue(pid, BOB, 30);
+    reverse_split_property(pid, 10, 7).unwrap();
+    let prop = get_property(pid).unwrap();
+    assert_eq!((prop.total_shares, prop.shares_available), (10, 5));
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (2, 3));
+    assert_eq!((get_unclaimed_income(pid, ALICE), get_unclaimed_income(pid, BOB)), (35, 0));
+    assert_eq!(get_income_summary(pid).total_deposited, 35);
+}
+
+#[test]
+fn top_holders_are_ordered_and_truncated() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    issue(pid, BOB, 30);
+    issue(pid, CAROL, 10);
+    assert_eq!(get_top_holders(pid, 2), vec![(BOB, 30), (ALICE, 10)]);
+    assert_eq!(get_top_holders(pid, 10), vec![(BOB, 30), (ALICE, 10), (CAROL, 10)]);
+    assert!(get_top_holders(pid, 0).is_empty());
+}
+
+#[test]
+fn properties_can_be_looked_up_by_exact_name() {
+    setup();
+    let pid = new_property(10);
+    let name = get_property(pid).unwrap().name;
+    assert_eq!(get_property_by_name(name.clone()).map(|p| p.id), Some(pid));
+    assert!(get_property_by_name(name.to_lowercase()).is_none());
+    assert!(get_property_by_name("Nowhere".to_string()).is_none());
+}
+
+#[test]
+fn duplicate_names_depend_on_the_uniqueness_flag() {
+    setup();
+    let register = || register_property("Harbour View".to_string(), 10, metadata(1_000));
+    let first = register().unwrap().id;
+    assert!(register().is_ok());
+    // The lookup keeps pointing at the oldest property with the name
+    assert_eq!(get_property_by_name("Harbour View".to_string()).map(|p| p.id), Some(first));
+    set_require_unique_names(true).unwrap();
+    assert_eq!(register().err(), Some("A property with this name already exists".to_string()));
+    set_require_unique_names(false).unwrap();
+    assert!(register().is_ok());
+}
+
+#[test]
+fn snapshot_percentage_ignores_later_transfers() {
+    setup();
+    let pid = new_property(200);
+    issue(pid, ALICE, 50);
+    let snap = take_snapshot(pid).unwrap();
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 50)).unwrap();
+    assert_eq!(get_snapshot_percentage(pid, snap, ALICE), Ok(2_500));
+    assert_eq!(get_snapshot_percentage(pid, snap, BOB), Ok(0));
+    assert!(get_snapshot_percentage(pid + 1, snap, ALICE).is_err());
+}
+
+#[test]
+fn prices_are_formatted_with_the_property_decimals() {
+    setup();
+    let cents = register_property("Cents".to_string(), 10, PropertyMetadata { decimals: 2, ..metadata(1_000) }).unwrap().id;
+    assert_eq!(format_price(cents, 12_345), Ok("123.45".to_string()));
+    assert_eq!(format_price(cents, 5), Ok("0.05".to_string()));
+    assert_eq!(format_price(cents, 0), Ok("0.00".to_string()));
+    assert_eq!(format_price(new_property(10), 42), Ok("42".to_string()));
+    assert!(format_price(999, 1).is_err());
+}
+
+#[test]
+fn documents_show_up_in_the_property_detail() {
+    setup();
+    let pid = new_property(10);
+    let doc = |byte| DocumentRef { title: "Deed".to_string(), sha256: vec![byte; 32], url: "https://example.com/deed".to_string() };
+    add_document(pid, doc(1)).unwrap();
+    add_document(pid, doc(2)).unwrap();
+    assert!(add_document(pid, doc(1)).is_err());
+    assert!(add_document(pid, DocumentRef { sha256: vec![3; 31], ..doc(3) }).is_err());
+    remove_document(pid, vec![1; 32]).unwrap();
+    let hashes: Vec<Vec<u8>> = get_property_detail(pid).unwrap().property.documents.into_iter().map(|d| d.sha256).collect();
+    assert_eq!(hashes, vec![vec![2; 32]]);
+}
+
+#[test]
+fn share_cap_bounds_registrations_and_splits() {
+    setup();
+    set_max_total_shares(Some(1_000)).unwrap();
+    let capped = || "Total shares exceed the maximum allowed per property".to_string();
+    assert_eq!(register_property("Big".to_string(), 1_001, metadata(1_000)).err(), Some(capped()));
+    let pid = register_property("Edge".to_string(), 1_000, metadata(1_000)).unwrap().id;
+    let small = new_property(100);
+    assert!(split_property(pid, 2).is_err());
+    split_property(small, 10).unwrap();
+    set_max_total_shares(None).unwrap();
+    assert!(split_property(pid, 2).is_ok());
+}
+
+#[test]
+fn batch_lookup_keeps_positions_for_missing_ids() {
+    setup();
+    let (a, b) = (new_property(10), new_property(10));
+    let found: Vec<Option<PropertyId>> = get_properties(vec![b, 999, a, 0]).iter().map(|p| p.as_ref().map(|p| p.id)).collect();
+    assert_eq!(found, vec![Some(b), None, Some(a), None]);
+}
+
+#[test]
+fn valuation_history_records_each_change() {
+    setup();
+    let pid = new_property(100);
+    let revalue = |valuation| UpdatePropertyArgs { name: None, location: None, description: None, valuation: Some(valuation), decimals: None };
+    set_mock_time(1_000);
+    update_property_metadata(pid, revalue(12_000)).unwrap();
+    set_mock_time(2_000);
+    update_property_metadata(pid, revalue(12_000)).unwrap();
+    update_property_metadata(pid, revalue(9_000)).unwrap();
+    assert_eq!(get_valuation_history(pid), vec![(1_000, 12_000), (2_000, 9_000)]);
+    assert_eq!(get_property(pid).unwrap().metadata.valuation, 9_000);
+}
+
+#[test]
+fn property_config_reports_every_setting() {
+    setup();
+    let pid = new_property(100);
+    let window = TradingWindow::Fixed { opens_at: 0, closes_at: 1_000 };
+    set_trading_window(pid, Some(window.clone())).unwrap();
+    set_transfer_restricted(pid, true).unwrap();
+    set_min_deposit(pid, Some(50)).unwrap();
+    set_platform_fee_bps(120).unwrap();
+    set_max_listings_per_seller(3).unwrap();
+    set_max_price_per_share(Some(10_000)).unwrap();
+    set_max_total_shares(Some(1_000_000)).unwrap();
+    let config = get_property_config(pid).unwrap();
+    assert!(config.status == PropertyStatus::Active && config.trading_window == Some(window));
+    assert!(config.transfer_restricted);
+    assert_eq!((config.min_deposit, config.platform_fee_bps, config.max_listings_per_seller), (Some(50), 120, 3));
+    assert_eq!((config.max_price_per_share, config.max_total_shares), (Some(10_000), Some(1_000_000)));
+    assert!(get_property_config(999).is_none());
+}
+
+#[test]
+fn properties_sort_by_each_key() {
+    setup();
+    let add = |name: &str, shares, valuation| register_property(name.to_string(), shares, metadata(valuation)).unwrap().id;
+    let (a, b, c) = (add("Cedar", 100, 5_000), add("Aspen", 50, 20_000), add("Birch", 200, 5_000));
+    for pid in [a, b, c] {
+        issue(pid, ALICE, 10);
+    }
+    deposit_rental_income(a, 500).unwrap();
+    deposit_rental_income(b, 100).unwrap();
+    let ids = |key, descending| get_properties_sorted(key, descending, 0, 10).iter().map(|p| p.id).collect::<Vec<_>>();
+    assert_eq!(ids(SortKey::Yield, true), vec![a, b, c]);
+    // Equal valuations fall back to ascending id either way
+    assert_eq!(ids(SortKey::Valuation, false), vec![a, c, b]);
+    assert_eq!(ids(SortKey::Valuation, true), vec![b, a, c]);
+    assert_eq!(ids(SortKey::AvailableShares, false), vec![b, a, c]);
+    assert_eq!(ids(SortKey::Name, false), vec![b, c, a]);
+    assert_eq!(get_properties_sorted(SortKey::Name, false, 1, 1)[0].id, c);
+}
+
+#[test]
+fn average_holding_rounds_down_over_holders() {
+    setup();
+    let pid = new_property(100);
+    assert_eq!(get_average_holding(pid), 0);
+    issue(pid, ALICE, 10);
+    issue(pid, BOB, 5);
+    assert_eq!(get_average_holding(pid), 7);
+    assert_eq!(get_average_holding(pid + 1), 0);
+}
+
+#[test]
+fn property_summaries_page_in_id_order() {
+    setup();
+    let ids: Vec<PropertyId> = (0..3).map(|_| new_property(100)).collect();
+    issue(ids[1], ALICE, 30);
+    let page = get_property_summaries(1, 5);
+    assert_eq!(page.iter().map(|s| s.id).collect::<Vec<_>>(), ids[1..]);
+    let s = &page[0];
+    assert_eq!(s.name, get_property(ids[1]).unwrap().name);
+    assert_eq!((s.shares_available, s.total_shares, s.valuation), (70, 100, 10_000));
+    assert!(get_property_summaries(3, 5).is_empty());
+    assert_eq!(get_property_summaries(0, 1).len(), 1);
+}
+
+#[test]
+fn deleting_a_property_leaves_nothing_behind_and_its_id_unused() {
+    setup();
+    let pid = new_property(100);
+    let name = get_property(pid).unwrap().name;
+    set_min_deposit(pid, Some(10)).unwrap();
+    set_trading_window(pid, Some(TradingWindow::Fixed { opens_at: 0, closes_at: 10 })).unwrap();
+    set_transfer_restricted(pid, true).unwrap();
+    set_approved_recipient(pid, BOB, true).unwrap();
+    update_property_metadata(pid, UpdatePropertyArgs { name: None, location: None, description: None, valuation: Some(500), decimals: None }).unwrap();
+    assert!(as_caller(ALICE, || delete_property(pid)).is_err());
+    delete_property(pid).unwrap();
+    assert!(get_property(pid).is_none() && get_property_config(pid).is_none() && get_property_by_name(name).is_none());
+    assert_eq!((get_min_deposit(pid), get_valuation_history(pid)), (None, vec![]));
+    assert!(get_trading_window(pid).is_none() && !is_transfer_restricted(pid));
+    assert_eq!(delete_property(pid), Err("Property not found".to_string()));
+    let next = new_property(100);
+    assert!(next > pid);
+    issue(next, ALICE, 1);
+    assert_eq!(delete_property(next), Err("Cannot delete a property with issued shares".to_string()));
+}
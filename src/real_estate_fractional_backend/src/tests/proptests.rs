@@ -0,0 +1,127 @@
+//! Property-based checks of the share accounting invariant over random operation sequences.
+
+use super::*;
+use proptest::prelude::*;
+use proptest::sample::Index;
+
+const USERS: [Principal; 3] = [ALICE, BOB, CAROL];
+
+#[derive(Clone, Debug)]
+enum Op {
+    Register { total_shares: u64 },
+    Issue { property: Index, to: usize, amount: u64 },
+    /// `caller` is `None` when the holder acts for themselves, else a possibly different user.
+    Transfer { property: Index, caller: Option<usize>, from: usize, to: usize, amount: u64 },
+    List { property: Index, caller: Option<usize>, seller: usize, amount: u64, price_per_share: u64 },
+    Buy { property: Index, buyer: usize, seller: usize, amount: u64 },
+    Deposit { property: Index, amount: u64 },
+    LedgerFailing(bool),
+}
+
+/// Share amounts: mostly small enough to succeed, sometimes absurd enough to overflow.
+fn amount() -> impl Strategy<Value = u64> {
+    prop_oneof![9 => 0..=150u64, 1 => Just(u64::MAX)]
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    let user = 0..USERS.len();
+    let caller = prop::option::weighted(0.25, user.clone());
+    prop_oneof![
+        (1..=1_000u64).prop_map(|total_shares| Op::Register { total_shares }),
+        (any::<Index>(), user.clone(), amount()).prop_map(|(property, to, amount)| Op::Issue { property, to, amount }),
+        (any::<Index>(), caller.clone(), user.clone(), user.clone(), amount())
+            .prop_map(|(property, caller, from, to, amount)| Op::Transfer { property, caller, from, to, amount }),
+        (any::<Index>(), caller, user.clone(), amount(), 0..=1_000_000u64).prop_map(|(property, caller, seller, amount, price_per_share)| {
+            Op::List { property, caller, seller, amount, price_per_share }
+        }),
+        (any::<Index>(), user.clone(), user, amount())
+            .prop_map(|(property, buyer, seller, amount)| Op::Buy { property, buyer, seller, amount }),
+        (any::<Index>(), 0..=1_000_000_000_000u64).prop_map(|(property, amount)| Op::Deposit { property, amount }),
+        any::<bool>().prop_map(Op::LedgerFailing),
+    ]
+}
+
+/// Holders' balances plus unissued and reserved shares add up to the total for every
+/// property, and no more income is owed or paid than was deposited.
+fn check_invariants(properties: &[PropertyId]) -> Result<(), TestCaseError> {
+    for &pid in properties {
+        let prop = get_property(pid).unwrap();
+        let held: u128 = OWNERSHIP.with(|own| {
+            own.borrow().iter().filter(|((p, _, _), _)| *p == pid).map(|(_, shares)| *shares as u128).sum()
+        });
+        prop_assert_eq!(held + prop.shares_available as u128 + reserved_shares(pid) as u128, prop.total_shares as u128);
+        let income = get_income_summary(pid);
+        prop_assert!(income.total_unclaimed as u128 + income.total_claimed as u128 <= income.total_deposited as u128);
+    }
+    prop_assert!(find_share_accounting_errors().is_empty());
+    Ok(())
+}
+
+fn run(ops: Vec<Op>) -> Result<(), TestCaseError> {
+    setup();
+    set_settlement_ledger(Some(LEDGER)).unwrap();
+    set_platform_fee_bps(250).unwrap();
+    let mut properties: Vec<PropertyId> = Vec::new();
+    for op in ops {
+        let pick = |i: &Index| (!properties.is_empty()).then(|| properties[i.index(properties.len())]);
+        // Individual operations may be rejected; the invariant must hold either way
+        match op {
+            Op::Register { total_shares } => properties.push(new_property(total_shares)),
+            Op::Issue { property, to, amount } => {
+                if let Some(pid) = pick(&property) {
+                    let _ = as_caller(ADMIN, || issue_shares(pid, USERS[to], amount, None));
+                }
+            }
+            Op::Transfer { property, caller, from, to, amount } => {
+                if let Some(pid) = pick(&property) {
+                    let (caller, from) = (USERS[caller.unwrap_or(from)], USERS[from]);
+                    let before = get_ownership(pid, from);
+                    let result = as_caller(caller, || transfer_shares(pid, from, USERS[to], amount));
+                    if caller != from {
+                        prop_assert!(result.is_err());
+                        prop_assert_eq!(get_ownership(pid, from), before);
+                    }
+                }
+            }
+            Op::List { property, caller, seller, amount, price_per_share } => {
+                if let Some(pid) = pick(&property) {
+                    let (caller, seller) = (USERS[caller.unwrap_or(seller)], USERS[seller]);
+                    let listed = get_total_shares_listed(pid);
+                    let result = as_caller(caller, || list_shares_for_sale(pid, seller, amount, price_per_share, None));
+                    if caller != seller {
+                        prop_assert!(result.is_err());
+                        prop_assert_eq!(get_total_shares_listed(pid), listed);
+                    }
+                }
+            }
+            Op::Buy { property, buyer, seller, amount } => {
+                if let Some(pid) = pick(&property) {
+                    let result = as_caller(USERS[buyer], || block_on(buy_shares(pid, USERS[seller], amount)));
+                    if buyer == seller || amount == 0 {
+                        prop_assert!(result.is_err());
+                    }
+                }
+            }
+            Op::Deposit { property, amount } => {
+                if let Some(pid) = pick(&property) {
+                    let _ = as_caller(ADMIN, || deposit_rental_income(pid, amount));
+                }
+            }
+            Op::LedgerFailing(failing) => set_mock_ledger_failing(failing),
+        }
+        check_invariants(&properties)?;
+    }
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig { cases: 64, ..ProptestConfig::default() })]
+
+    #[test]
+    fn share_accounting_holds_across_random_operations(ops in prop::collection::vec(op(), 1..40)) {
+        // Canister state is thread-local, so each case gets a fresh thread and a fresh canister
+        std::thread::spawn(move || run(ops))
+            .join()
+            .unwrap_or_else(|_| Err(TestCaseError::fail("operation panicked")))?;
+    }
+}
@@ -0,0 +1,303 @@
+use super::*;
+
+/// A transfer-restricted property with `ALICE` holding 50 shares and a pending transfer of 20
+/// of them to the not-yet-approved `BOB`.
+fn pending_to_bob() -> (PropertyId, u64) {
+    let pid = new_property(100);
+    issue(pid, ALICE, 50);
+    set_transfer_restricted(pid, true).unwrap();
+    let id = as_caller(ALICE, || create_pending_transfer(pid, BOB, 20)).unwrap();
+    (pid, id)
+}
+
+#[test]
+fn pending_transfer_fails_cleanly_when_the_sender_is_short() {
+    setup();
+    let (pid, id) = pending_to_bob();
+    set_approved_recipient(pid, BOB, true).unwrap();
+    // Simulate the sender's balance drifting below the pending amount
+    OWNERSHIP.with(|own| own.borrow_mut().insert((pid, ALICE, sub(0)), 10));
+    assert!(as_caller(BOB, || claim_pending_transfer(id)).is_err());
+    assert_eq!(get_ownership(pid, BOB), 0);
+    assert_eq!(get_pending_transfers(BOB).len(), 1);
+    assert_eq!(get_locked_shares(pid, ALICE), 20);
+}
+
+#[test]
+fn pending_transfer_is_claimable_once_approved() {
+    setup();
+    let (pid, id) = pending_to_bob();
+    assert!(as_caller(BOB, || claim_pending_transfer(id)).is_err());
+    assert!(as_caller(CAROL, || claim_pending_transfer(id)).is_err());
+    set_approved_recipient(pid, BOB, true).unwrap();
+    as_caller(BOB, || claim_pending_transfer(id)).unwrap();
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (30, 20));
+    assert_eq!(get_locked_shares(pid, ALICE), 0);
+    assert!(get_pending_transfers(BOB).is_empty());
+}
+
+#[test]
+fn sender_reclaims_an_unclaimed_transfer_after_the_timeout() {
+    setup();
+    let (pid, id) = pending_to_bob();
+    // The pending shares stay locked until then
+    set_approved_recipient(pid, CAROL, true).unwrap();
+    assert_eq!(as_caller(ALICE, || transfer_shares(pid, ALICE, CAROL, 31)), Err("Not enough shares to transfer".to_string()));
+    assert!(as_caller(ALICE, || reclaim_pending_transfer(id)).is_err());
+    set_mock_time(PENDING_TRANSFER_TIMEOUT_NANOS);
+    assert!(as_caller(BOB, || reclaim_pending_transfer(id)).is_err());
+    as_caller(ALICE, || reclaim_pending_transfer(id)).unwrap();
+    assert_eq!((get_ownership(pid, ALICE), get_locked_shares(pid, ALICE)), (50, 0));
+    set_approved_recipient(pid, BOB, true).unwrap();
+    assert!(as_caller(BOB, || claim_pending_transfer(id)).is_err());
+}
+
+#[test]
+fn import_keeps_collateral_locks_but_drops_pending_transfers_and_bids() {
+    setup();
+    let (pid, _) = pending_to_bob();
+    as_caller(ALICE, || lock_shares(pid, 5, CAROL)).unwrap();
+    set_approved_recipient(pid, ALICE, true).unwrap();
+    as_caller(ALICE, || place_bid(pid, 1, 10)).unwrap();
+    set_mock_time(7_000);
+    import_state(export_state().unwrap()).unwrap();
+    assert_eq!(get_locked_shares(pid, ALICE), 5);
+    assert!(is_transfer_restricted(pid));
+    assert!(get_pending_transfers(ALICE).is_empty());
+    assert!(get_bids(pid).is_empty());
+    assert_eq!(get_holding_since(pid, ALICE), Some(7_000));
+}
+
+#[test]
+fn issuing_into_a_subaccount_keeps_it_out_of_the_default_one() {
+    setup();
+    let pid = new_property(100);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 30, Some(sub(7)))).unwrap();
+    assert_eq!(get_ownership(pid, ALICE), 30);
+    assert_eq!(get_subaccount_balance(pid, ALICE, Some(sub(7))), Ok(30));
+    assert_eq!(get_subaccount_balance(pid, ALICE, None), Ok(0));
+    assert!(as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 1)).is_err());
+    assert!(as_caller(ADMIN, || issue_shares(pid, ALICE, 1, Some(vec![7; 31]))).is_err());
+}
+
+#[test]
+fn transfer_between_two_subaccounts() {
+    setup();
+    let pid = new_property(100);
+    as_caller(ADMIN, || issue_shares(pid, ALICE, 50, Some(sub(7)))).unwrap();
+    as_caller(ALICE, || transfer_subaccount_shares(pid, Some(sub(7)), BOB, Some(sub(9)), 20)).unwrap();
+    assert_eq!(get_subaccount_balance(pid, ALICE, Some(sub(7))), Ok(30));
+    assert_eq!(get_subaccount_balance(pid, BOB, Some(sub(9))), Ok(20));
+    assert_eq!(get_subaccount_balance(pid, BOB, None), Ok(0));
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (30, 20));
+    assert!(find_share_accounting_errors().is_empty());
+}
+
+#[test]
+fn transfer_between_two_subaccounts_of_the_same_principal() {
+    setup();
+    let pid = new_property(100);
+    set_mock_time(1_000);
+    issue(pid, ALICE, 50);
+    set_mock_time(2_000);
+    as_caller(ALICE, || transfer_subaccount_shares(pid, None, ALICE, Some(sub(7)), 50)).unwrap();
+    assert_eq!(get_subaccount_balance(pid, ALICE, None), Ok(0));
+    as_caller(ALICE, || transfer_subaccount_shares(pid, Some(sub(7)), ALICE, Some(sub(9)), 20)).unwrap();
+    assert_eq!(get_subaccounts(pid, ALICE), vec![(sub(7), 30), (sub(9), 20)]);
+    assert_eq!((get_ownership(pid, ALICE), get_holder_count(pid)), (50, 1));
+    assert_eq!(get_holding_since(pid, ALICE), Some(1_000));
+    assert!(as_caller(ALICE, || transfer_subaccount_shares(pid, Some(sub(9)), ALICE, Some(sub(9)), 1)).is_err());
+    assert!(as_caller(ALICE, || transfer_subaccount_shares(pid, Some(sub(9)), ALICE, None, 21)).is_err());
+    assert!(get_events(0, 100).unwrap().iter().all(|e| !matches!(e.kind, EventKind::SharesTransferred { .. })));
+}
+
+#[test]
+fn transfer_events_record_the_caller() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 4)).unwrap();
+    let events = get_events_by_caller(ALICE, 0, 10).unwrap();
+    assert_eq!(events.len(), 1);
+    assert_eq!(events[0].property_id, Some(pid));
+    assert!(matches!(events[0].kind, EventKind::SharesTransferred { from: ALICE, to: BOB, amount: 4 }));
+}
+
+#[test]
+fn emptied_balances_leave_no_entry() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    issue(pid, BOB, 5);
+    assert_eq!(get_holder_count(pid), 2);
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 10)).unwrap();
+    assert!(OWNERSHIP.with(|own| !own.borrow().contains_key(&(pid, ALICE, sub(0)))));
+    assert_eq!(get_holder_count(pid), 1);
+}
+
+#[test]
+fn recipients_see_transfers_in_their_inbox() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 4)).unwrap();
+    let inbox = as_caller(BOB, || get_my_events(0, 10));
+    assert_eq!(inbox.len(), 1);
+    assert!(matches!(inbox[0].kind, EventKind::SharesTransferred { from: ALICE, to: BOB, amount: 4 }));
+    // ALICE also sees her issuance, newest first
+    let alice = as_caller(ALICE, || get_my_events(0, 10));
+    assert!(matches!(alice[0].kind, EventKind::SharesTransferred { .. }));
+    assert!(matches!(alice[1].kind, EventKind::SharesIssued { to: ALICE, amount: 10 }));
+    assert!(as_caller(CAROL, || get_my_events(0, 10)).is_empty());
+}
+
+#[test]
+fn transferring_everything_removes_the_holding() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 12);
+    as_caller(ALICE, || transfer_all_shares(pid, BOB)).unwrap();
+    assert_eq!(get_ownership(pid, BOB), 12);
+    assert!(OWNERSHIP.with(|own| !own.borrow().contains_key(&(pid, ALICE, sub(0)))));
+    assert!(as_caller(ALICE, || transfer_all_shares(pid, BOB)).is_err());
+}
+
+#[test]
+fn restricted_properties_only_transfer_to_approved_recipients() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    set_transfer_restricted(pid, true).unwrap();
+    assert_eq!(
+        as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 2)),
+        Err("Recipient is not approved for this property".to_string())
+    );
+    set_approved_recipient(pid, BOB, true).unwrap();
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 2)).unwrap();
+    assert_eq!(get_approved_recipients(pid), vec![BOB]);
+    set_approved_recipient(pid, BOB, false).unwrap();
+    assert!(as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 2)).is_err());
+    assert_eq!(get_ownership(pid, BOB), 2);
+}
+
+#[test]
+fn migration_moves_every_holding() {
+    setup();
+    let (a, b) = (new_property(100), new_property(100));
+    issue(a, ALICE, 10);
+    issue(b, ALICE, 20);
+    deposit_rental_income(a, 1_000).unwrap();
+    let listing = list(b, ALICE, 5, 7);
+    as_caller(ALICE, || lock_shares(a, 4, CAROL)).unwrap();
+    migrate_user(None, ALICE, BOB).unwrap();
+    assert_eq!((get_ownership(a, BOB), get_ownership(b, BOB)), (10, 20));
+    assert_eq!((get_ownership(a, ALICE), get_ownership(b, ALICE)), (0, 0));
+    assert_eq!(get_unclaimed_income(a, BOB), 100);
+    assert_eq!(get_locked_shares(a, BOB), 4);
+    assert_eq!(get_marketplace_listings().0.iter().find(|l| l.id == listing).map(|l| l.seller), Some(BOB));
+    assert!(find_share_accounting_errors().is_empty());
+}
+
+#[test]
+fn account_properties_cover_holdings_listings_and_income() {
+    setup();
+    let (held, listed, earning, untouched) = (new_property(100), new_property(100), new_property(100), new_property(100));
+    issue(held, ALICE, 5);
+    issue(listed, ALICE, 5);
+    list(listed, ALICE, 5, 9);
+    as_caller(ALICE, || transfer_shares(listed, ALICE, BOB, 5)).unwrap();
+    issue(earning, ALICE, 5);
+    deposit_rental_income(earning, 100).unwrap();
+    as_caller(ALICE, || transfer_shares(earning, ALICE, BOB, 5)).unwrap();
+    issue(untouched, BOB, 5);
+    assert_eq!(get_account_properties(ALICE), vec![held, listed, earning]);
+    assert_eq!(get_account_properties(BOB), vec![listed, earning, untouched]);
+}
+
+#[test]
+fn shares_are_recovered_only_from_recoverable_principals() {
+    setup();
+    let pid = new_property(100);
+    let blackhole = Principal::from_slice(&[0xBB; 10]);
+    issue(pid, blackhole, 10);
+    issue(pid, ALICE, 10);
+    assert_eq!(recover_shares(pid, ALICE, BOB), Err("Principal is not marked as recoverable".to_string()));
+    set_recoverable_principal(blackhole, true).unwrap();
+    assert!(as_caller(BOB, || recover_shares(pid, blackhole, BOB)).is_err());
+    assert_eq!(recover_shares(pid, blackhole, BOB), Ok(10));
+    assert_eq!((get_ownership(pid, blackhole), get_ownership(pid, BOB)), (0, 10));
+    assert_eq!(recover_shares(pid, blackhole, BOB), Err("No shares to recover".to_string()));
+    assert_eq!(get_ownership(pid, ALICE), 10);
+}
+
+#[test]
+fn forbidden_recipients_cannot_receive_shares() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let rejected = Err("Recipient is not approved for this property".to_string());
+    let send = |to| as_caller(ALICE, || transfer_shares(pid, ALICE, to, 1));
+    assert_eq!(send(Principal::anonymous()), rejected);
+    assert_eq!(send(Principal::management_canister()), rejected);
+    set_forbidden_recipient(BOB, true).unwrap();
+    assert_eq!(send(BOB), rejected);
+    assert_eq!(get_forbidden_recipients().len(), 2);
+    set_forbidden_recipient(BOB, false).unwrap();
+    assert!(send(BOB).is_ok());
+    assert_eq!(get_ownership(pid, ALICE), 9);
+    assert!(as_caller(ALICE, || set_forbidden_recipient(CAROL, true)).is_err());
+}
+
+#[test]
+fn conditional_transfer_moves_shares_only_at_or_above_the_price() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    let send = |min_price| as_caller(ALICE, || transfer_shares_if_price_at_least(pid, CAROL, 1, min_price));
+    assert_eq!(send(1), Err("Property has not traded yet".to_string()));
+    list(pid, ALICE, 2, 50);
+    buy(pid, BOB, ALICE, 2);
+    assert_eq!(send(51), Err("Latest price 50 is below 51".to_string()));
+    assert_eq!(get_ownership(pid, CAROL), 0);
+    assert!(send(50).is_ok());
+    assert!(send(49).is_ok());
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, CAROL)), (6, 2));
+}
+
+#[test]
+fn ownership_history_range_filters_by_time() {
+    setup();
+    let pid = new_property(100);
+    set_mock_time(100);
+    issue(pid, ALICE, 10);
+    set_mock_time(200);
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 1)).unwrap();
+    set_mock_time(300);
+    list(pid, ALICE, 2, 10);
+    as_caller(ALICE, || transfer_shares(pid, ALICE, BOB, 1)).unwrap();
+    let times = |from_ts, to_ts, offset, limit| {
+        as_caller(ALICE, || get_ownership_history_range(pid, ALICE, from_ts, to_ts, offset, limit))
+            .unwrap()
+            .iter()
+            .map(|e| e.timestamp)
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(times(0, u64::MAX, 0, 10), vec![100, 200, 300]);
+    assert_eq!(times(100, 300, 0, 10), vec![100, 200]);
+    assert_eq!(times(200, 301, 1, 10), vec![300]);
+    assert!(as_caller(CAROL, || get_ownership_history_range(pid, ALICE, 0, u64::MAX, 0, 10)).is_err());
+    assert_eq!(get_ownership_history_range(pid, ALICE, 0, u64::MAX, 0, 10).map(|h| h.len()), Ok(3));
+}
+
+#[test]
+fn only_the_holder_can_transfer_their_shares() {
+    setup();
+    let pid = new_property(100);
+    issue(pid, ALICE, 10);
+    assert_eq!(
+        as_caller(BOB, || transfer_shares(pid, ALICE, BOB, 5)),
+        Err("Only the holder can transfer their shares".to_string())
+    );
+    assert!(transfer_shares(pid, ALICE, ADMIN, 5).is_err());
+    assert_eq!((get_ownership(pid, ALICE), get_ownership(pid, BOB)), (10, 0));
+}